@@ -0,0 +1,72 @@
+//! Lookup benchmark for large hostlists, run with `cargo bench --bench hostlist`.
+
+use std::{hint::black_box, time::Instant};
+
+use packetmock::hostlist::Hostlist;
+
+const ENTRIES: usize = 200_000;
+const LOOKUPS: usize = 1_000_000;
+
+const TLDS: &[&str] = &["com", "net", "org", "ru", "io", "co.uk", "com.tr"];
+
+/// Deterministic xorshift generator, so runs are comparable.
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    fn domain(&mut self) -> String {
+        let name = self.next() % (ENTRIES as u64 * 4);
+        let tld = TLDS[self.next() as usize % TLDS.len()];
+        format!("site{name:x}.{tld}")
+    }
+}
+
+fn main() {
+    let mut rng = Rng(0x5eefaaa);
+
+    let mut text = String::new();
+    for i in 0..ENTRIES {
+        let domain = rng.domain();
+        match i % 10 {
+            0 => text.push_str(&format!("={domain}\n")),
+            1 => text.push_str(&format!("*.{domain}\n")),
+            2 => text.push_str(&format!("cdn.*.{domain}\n")),
+            _ => text.push_str(&format!("{domain}\n")),
+        }
+    }
+
+    let start = Instant::now();
+    let (hostlist, invalid) = Hostlist::parse(&text);
+    let elapsed = start.elapsed();
+
+    assert!(invalid.is_empty());
+    println!("built {} entries in {elapsed:?}", hostlist.len());
+
+    let queries = (0..LOOKUPS / 100)
+        .map(|i| match i % 3 {
+            0 => rng.domain(),
+            1 => format!("www.{}", rng.domain()),
+            _ => format!("cdn.edge{i}.{}", rng.domain()),
+        })
+        .collect::<Vec<_>>();
+
+    let start = Instant::now();
+    let mut hits = 0;
+    for _ in 0..100 {
+        for query in &queries {
+            hits += black_box(hostlist.matches(black_box(query))) as usize;
+        }
+    }
+    let elapsed = start.elapsed();
+
+    println!(
+        "{LOOKUPS} lookups in {elapsed:?} ({:.0} ns/lookup, {hits} hits)",
+        elapsed.as_nanos() as f64 / LOOKUPS as f64
+    );
+}
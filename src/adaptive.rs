@@ -0,0 +1,234 @@
+//! Per-domain choice of the strategy chain from the outcome of desynced flows.
+//!
+//! A domain that stays blocked with the chain of its profile moves to the first candidate
+//! chain, then to the next one each time the current one keeps failing. The first candidate
+//! getting a response is kept. Decisions are kept in the state store so they survive restarts,
+//! and can be listed and reset from the command line.
+
+use std::{collections::BTreeMap, fmt, path::PathBuf, time::Duration};
+
+use color_eyre::Result;
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::{autohostlist::Outcome, state::StateStore, strategy::Step};
+
+/// Namespace of the decisions in the state store.
+const NAMESPACE: &str = "strategy";
+
+/// What was decided for a domain.
+#[derive(Clone, PartialEq, Debug, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Decision {
+    /// Candidate chain in use, the chain of the profile if `None`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub candidate: Option<Vec<Step>>,
+    /// The chain in use got a response since it was chosen.
+    pub confirmed: bool,
+    /// Blocked flows since the last response or change of chain.
+    pub failures: u32,
+}
+
+/// The decisions by domain, kept in sync with the state store.
+pub struct StrategyCache {
+    store: StateStore,
+    /// Chains tried in order once the chain of the profile fails.
+    candidates: Vec<Vec<Step>>,
+    /// Blocked flows after which the next candidate is tried.
+    threshold: u32,
+    /// How long a decision is kept after it last changed, forever if `None`.
+    ttl: Option<Duration>,
+    decisions: BTreeMap<String, Decision>,
+}
+
+impl StrategyCache {
+    /// Load the decisions from the state store at the path. Decisions for chains that are no
+    /// longer candidates are ignored.
+    pub fn load(
+        path: PathBuf,
+        candidates: Vec<Vec<Step>>,
+        threshold: u32,
+        ttl: Option<Duration>,
+    ) -> Result<Self> {
+        let store = StateStore::open(path)?;
+
+        let decisions = store
+            .entries::<Decision>(NAMESPACE)
+            .filter(|(_, decision)| {
+                decision
+                    .candidate
+                    .as_ref()
+                    .is_none_or(|chain| candidates.contains(chain))
+            })
+            .map(|(domain, decision)| (domain.to_owned(), decision))
+            .collect();
+
+        Ok(Self {
+            store,
+            candidates,
+            threshold,
+            ttl,
+            decisions,
+        })
+    }
+
+    /// Whether there are candidates to move through.
+    #[inline]
+    pub fn is_enabled(&self) -> bool {
+        !self.candidates.is_empty()
+    }
+
+    /// The candidate chain chosen for the domain, `None` if it uses the chain of its profile.
+    pub fn chain(&self, host: &str) -> Option<&[Step]> {
+        self.decisions
+            .get(&host.to_ascii_lowercase())?
+            .candidate
+            .as_deref()
+    }
+
+    /// The decisions, sorted by domain.
+    pub fn decisions(&self) -> impl Iterator<Item = (&str, &Decision)> {
+        self.decisions
+            .iter()
+            .map(|(domain, decision)| (domain.as_str(), decision))
+    }
+
+    /// Record the outcome of a flow to the domain desynced with the chain, moving to the next
+    /// candidate once the chain in use failed `threshold` times in a row.
+    ///
+    /// Outcomes of flows desynced with a chain that is no longer in use are ignored.
+    pub fn record(&mut self, host: &str, chain: &[Step], outcome: Outcome) -> Result<()> {
+        if !self.is_enabled() {
+            return Ok(());
+        }
+
+        let host = host.to_ascii_lowercase();
+        let decision = self.decisions.entry(host.clone()).or_default();
+
+        if decision
+            .candidate
+            .as_deref()
+            .is_some_and(|candidate| candidate != chain)
+        {
+            return Ok(());
+        }
+
+        if !outcome.is_blocked() {
+            if decision.candidate.is_none() {
+                // the chain of the profile works, there is nothing to remember
+                self.decisions.remove(&host);
+                self.store.remove(NAMESPACE, &host)?;
+                return Ok(());
+            }
+            if decision.confirmed && decision.failures == 0 {
+                return Ok(());
+            }
+
+            if !decision.confirmed {
+                info!("Keeping {} for {host}", describe(decision));
+            }
+            decision.confirmed = true;
+            decision.failures = 0;
+            return self.save(&host);
+        }
+
+        decision.confirmed = false;
+        decision.failures += 1;
+
+        if decision.failures >= self.threshold {
+            let next = match &decision.candidate {
+                Some(chain) => self
+                    .candidates
+                    .iter()
+                    .position(|candidate| candidate == chain)
+                    .map_or(0, |index| index + 1),
+                None => 0,
+            };
+
+            match self.candidates.get(next) {
+                Some(candidate) => {
+                    decision.candidate = Some(candidate.clone());
+                    decision.failures = 0;
+                    info!("Trying {} for {host} ({outcome:?})", describe(decision));
+                }
+                None => {
+                    // start over, the blocking may have changed since the first candidate
+                    warn!("No candidate chain worked for {host}, starting over");
+                    self.decisions.remove(&host);
+                }
+            }
+        }
+
+        self.save(&host)
+    }
+
+    /// Forget the decision for the domain, or for every domain if `None`, returning how many
+    /// were removed.
+    pub fn reset(&mut self, host: Option<&str>) -> Result<usize> {
+        let domains = match host {
+            Some(host) => vec![host.to_ascii_lowercase()],
+            None => self.decisions.keys().cloned().collect(),
+        };
+
+        let mut removed = 0;
+        for domain in domains {
+            if self.decisions.remove(&domain).is_some() {
+                self.store.remove(NAMESPACE, &domain)?;
+                removed += 1;
+            }
+        }
+
+        Ok(removed)
+    }
+
+    /// Sync the decisions changed since the last sync to disk.
+    #[inline]
+    pub fn sync(&mut self) -> Result<()> {
+        self.store.sync()
+    }
+
+    /// Compact the state store if it grew large.
+    #[inline]
+    pub fn maybe_compact(&mut self) -> Result<()> {
+        self.store.maybe_compact()
+    }
+
+    /// Store the decision for the domain, or its removal.
+    fn save(&mut self, host: &str) -> Result<()> {
+        match self.decisions.get(host) {
+            Some(decision) => self.store.set(NAMESPACE, host, decision, self.ttl),
+            None => self.store.remove(NAMESPACE, host).map(drop),
+        }
+    }
+}
+
+impl fmt::Display for StrategyCache {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.decisions.is_empty() {
+            return writeln!(f, "No decisions in {}", self.store.path().display());
+        }
+
+        for (domain, decision) in &self.decisions {
+            let state = match (decision.confirmed, decision.failures) {
+                (true, _) => "working".to_owned(),
+                (false, 0) => "trying".to_owned(),
+                (false, failures) => format!("failures: {failures}"),
+            };
+
+            writeln!(f, "{domain}: {} ({state})", describe(decision))?;
+        }
+
+        Ok(())
+    }
+}
+
+/// The chain of the decision, for messages.
+fn describe(decision: &Decision) -> String {
+    match &decision.candidate {
+        Some(chain) => {
+            let steps = chain.iter().map(Step::to_string).collect::<Vec<_>>();
+            format!("[{}]", steps.join(", "))
+        }
+        None => "the profile chain".to_owned(),
+    }
+}
@@ -0,0 +1,221 @@
+//! Domains learned to need the desync from the outcome of flows that weren't desynced.
+//!
+//! After the first request of a flow, its outcome is observed: a server response means the
+//! domain is reachable, while an inbound RST, a redirect to a blockpage or repeated
+//! retransmissions of the request mean it looks blocked. Domains failing often enough are
+//! added to the auto-hostlist, a plain hostlist file, and desynced from then on.
+
+use std::{
+    collections::{BTreeSet, HashMap},
+    fmt::Write,
+    fs,
+    io::ErrorKind,
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
+
+use color_eyre::{Result, eyre::Context};
+use log::info;
+
+use crate::{hostlist::Hostlist, http::parse_http_response, state::replace_file};
+
+/// Default file name of the auto-hostlist.
+pub const AUTO_HOSTLIST_FILE: &str = "hostlist-auto.txt";
+
+/// Header written at the top of the auto-hostlist.
+const HEADER: &str = "\
+# Domains added by Packetmock after they looked blocked, one per line.
+# Lines can be removed by hand, the file is rewritten when a domain is added.
+";
+
+/// HTTP status of a resource unavailable for legal reasons (RFC 7725).
+const STATUS_LEGAL_REASONS: u16 = 451;
+
+/// How the first request of a flow turned out.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Outcome {
+    /// The server sent a response.
+    Responded,
+    /// The response redirects to another domain, or is unavailable for legal reasons.
+    Blockpage,
+    /// The flow was reset before any response.
+    Reset,
+    /// The request was retransmitted too often without a response.
+    Retransmitted,
+}
+
+impl Outcome {
+    #[inline]
+    pub fn is_blocked(self) -> bool {
+        self != Self::Responded
+    }
+}
+
+/// The outcome of a request to `host` answered with the payload.
+///
+/// Redirects to the same domain, a subdomain or a parent domain are normal responses.
+pub fn response_outcome(host: &str, data: &[u8]) -> Outcome {
+    let Some(response) = parse_http_response(data) else {
+        return Outcome::Responded;
+    };
+
+    if response.status == STATUS_LEGAL_REASONS {
+        return Outcome::Blockpage;
+    }
+    if !(300..400).contains(&response.status) {
+        return Outcome::Responded;
+    }
+
+    let Some(target) = response.location.and_then(location_host) else {
+        // relative redirects stay on the same host
+        return Outcome::Responded;
+    };
+
+    let host = host.to_ascii_lowercase();
+    let target = target.to_ascii_lowercase();
+
+    if is_same_site(&host, &target) {
+        Outcome::Responded
+    } else {
+        Outcome::Blockpage
+    }
+}
+
+/// Host of an absolute `Location`, `None` for relative ones.
+fn location_host(location: &str) -> Option<&str> {
+    let (_, rest) = location.split_once("://")?;
+    let authority = rest.split(['/', '?', '#']).next()?;
+    let host = authority.rsplit('@').next()?;
+
+    match host.strip_prefix('[') {
+        Some(rest) => rest.split(']').next(),
+        None => host.split(':').next(),
+    }
+}
+
+fn is_same_site(a: &str, b: &str) -> bool {
+    let within = |name: &str, domain: &str| {
+        name == domain
+            || name
+                .strip_suffix(domain)
+                .is_some_and(|prefix| prefix.ends_with('.'))
+    };
+
+    within(a, b) || within(b, a)
+}
+
+/// The learned domains, kept in sync with their file.
+pub struct AutoHostlist {
+    path: PathBuf,
+    domains: BTreeSet<String>,
+    hostlist: Hostlist,
+    /// Number of failures and the time of the first one, by domain.
+    failures: HashMap<String, (u32, Instant)>,
+    threshold: u32,
+    window: Duration,
+}
+
+impl AutoHostlist {
+    /// Load the learned domains from the file, if it exists. A domain is added after
+    /// `threshold` failures within `window`.
+    pub fn load(path: PathBuf, threshold: u32, window: Duration) -> Result<Self> {
+        let text = match fs::read_to_string(&path) {
+            Ok(text) => text,
+            Err(e) if e.kind() == ErrorKind::NotFound => String::new(),
+            Err(e) => {
+                return Err(e).wrap_err_with(|| format!("Failed to read {}", path.display()));
+            }
+        };
+
+        let domains = text
+            .lines()
+            .map(|line| line.split('#').next().unwrap_or_default().trim())
+            .filter(|line| !line.is_empty())
+            .map(str::to_ascii_lowercase)
+            .collect::<BTreeSet<_>>();
+
+        let mut list = Self {
+            path,
+            domains,
+            hostlist: Hostlist::default(),
+            failures: HashMap::new(),
+            threshold,
+            window,
+        };
+        list.rebuild();
+
+        Ok(list)
+    }
+
+    #[inline]
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn set_thresholds(&mut self, threshold: u32, window: Duration) {
+        self.threshold = threshold;
+        self.window = window;
+    }
+
+    /// Check if the domain was learned, or is a subdomain of a learned one.
+    #[inline]
+    pub fn matches(&self, host: &str) -> bool {
+        self.hostlist.matches(host)
+    }
+
+    /// The learned domains, sorted.
+    pub fn domains(&self) -> impl Iterator<Item = &str> {
+        self.domains.iter().map(String::as_str)
+    }
+
+    /// Record the outcome of a flow to the domain, returning whether it was added to the list.
+    pub fn record(&mut self, host: &str, outcome: Outcome, now: Instant) -> Result<bool> {
+        let host = host.to_ascii_lowercase();
+
+        if !outcome.is_blocked() {
+            self.failures.remove(&host);
+            return Ok(false);
+        }
+        if self.matches(&host) {
+            return Ok(false);
+        }
+
+        let window = self.window;
+        self.failures
+            .retain(|_, (_, first)| now.saturating_duration_since(*first) < window);
+
+        let (count, _) = self.failures.entry(host.clone()).or_insert((0, now));
+        *count += 1;
+
+        if *count < self.threshold {
+            return Ok(false);
+        }
+
+        self.failures.remove(&host);
+        info!("Adding {host} to the auto-hostlist ({outcome:?})");
+        self.domains.insert(host);
+        self.rebuild();
+        self.save()?;
+
+        Ok(true)
+    }
+
+    fn rebuild(&mut self) {
+        let text = self.domains.iter().fold(String::new(), |mut text, domain| {
+            let _ = writeln!(text, "{domain}");
+            text
+        });
+
+        (self.hostlist, _) = Hostlist::parse(&text);
+    }
+
+    fn save(&self) -> Result<()> {
+        let mut text = HEADER.to_owned();
+        for domain in &self.domains {
+            let _ = writeln!(text, "{domain}");
+        }
+
+        // a crash while writing must not lose the domains learned so far
+        replace_file(&self.path, text.as_bytes())
+    }
+}
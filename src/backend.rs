@@ -0,0 +1,167 @@
+//! Capture backends and the mark on the packets they inject.
+//!
+//! Every packet a backend sends carries a mark, and packets with the mark are never handed to
+//! the interceptor, whichever instance sent them. Without it, an instance would capture its
+//! own fakes and segments again, and two instances would desync each other's packets in a
+//! loop. WinDivert marks packets as impostors, other backends with a firewall mark. Filters
+//! already leave out marked packets where the backend can match them, the trait makes sure of
+//! it for the rest.
+
+use std::{
+    collections::VecDeque,
+    sync::{Arc, Mutex, PoisonError},
+};
+
+use color_eyre::{Result, eyre::ContextCompat};
+
+use crate::stats::STATS;
+
+/// Firewall mark of the packets injected on Linux, also matched by the nftables rules.
+pub const INJECTED_MARK: u32 = 0x4000_0000;
+
+/// Receives captured packets and sends packets, marking them as injected.
+///
+/// Backends only provide the raw operations, `receive` and `inject` enforce the mark.
+pub trait Backend {
+    /// What the backend keeps with a packet besides its bytes, e.g. its direction and mark.
+    type Meta: Clone;
+
+    /// Receive the next captured packet into the buffer, returning its length.
+    fn capture(&self, buffer: &mut [u8]) -> Result<(usize, Self::Meta)>;
+
+    /// Send the packet as is.
+    fn transmit(&self, packet: &[u8], meta: &Self::Meta) -> Result<()>;
+
+    /// Whether the packet carries the mark of injected packets.
+    fn is_marked(meta: &Self::Meta) -> bool;
+
+    /// Put the mark of injected packets on the packet.
+    fn mark(meta: &mut Self::Meta);
+
+    /// Receive the next captured packet that wasn't injected by a Packetmock instance.
+    fn receive(&self, buffer: &mut [u8]) -> Result<(usize, Self::Meta)> {
+        loop {
+            let (len, meta) = self.capture(buffer)?;
+
+            if !Self::is_marked(&meta) {
+                return Ok((len, meta));
+            }
+            STATS.injected_skipped.increment();
+        }
+    }
+
+    /// Send the packet with the mark of injected packets.
+    fn inject(&self, packet: &[u8], meta: &Self::Meta) -> Result<()> {
+        let mut meta = meta.clone();
+        Self::mark(&mut meta);
+
+        self.transmit(packet, &meta)
+    }
+}
+
+/// The firewall mark of a packet, the skb mark of the Linux kernel.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub struct Fwmark(pub u32);
+
+impl Fwmark {
+    #[inline]
+    pub fn is_injected(self) -> bool {
+        self.0 & INJECTED_MARK == INJECTED_MARK
+    }
+
+    /// Add the injected bit, keeping the bits set by other software.
+    #[inline]
+    pub fn set_injected(&mut self) {
+        self.0 |= INJECTED_MARK;
+    }
+}
+
+/// An in-memory network stack shared by backends, standing in for the kernel in tests.
+///
+/// Like packets passing an output hook whose rules don't check the mark, every packet sent by
+/// an application or a backend is captured by every backend opened on the stack, and every
+/// packet sent is recorded as leaving the machine.
+#[derive(Clone, Default)]
+pub struct MemoryStack {
+    state: Arc<Mutex<StackState>>,
+}
+
+#[derive(Default)]
+struct StackState {
+    /// Packets waiting to be captured, by backend.
+    queues: Vec<VecDeque<(Vec<u8>, Fwmark)>>,
+    /// Packets that left the machine.
+    sent: Vec<(Vec<u8>, Fwmark)>,
+}
+
+impl MemoryStack {
+    /// Open a backend capturing every packet sent from now on.
+    pub fn open(&self) -> MemoryBackend {
+        let mut state = self.lock();
+        state.queues.push(VecDeque::new());
+
+        MemoryBackend {
+            stack: self.clone(),
+            queue: state.queues.len() - 1,
+        }
+    }
+
+    /// Send a packet from an application, without any mark.
+    pub fn send(&self, packet: &[u8]) {
+        self.route(packet, Fwmark::default());
+    }
+
+    /// The packets that left the machine, in order.
+    pub fn sent(&self) -> Vec<(Vec<u8>, Fwmark)> {
+        self.lock().sent.clone()
+    }
+
+    fn route(&self, packet: &[u8], mark: Fwmark) {
+        let mut state = self.lock();
+
+        for queue in &mut state.queues {
+            queue.push_back((packet.to_vec(), mark));
+        }
+        state.sent.push((packet.to_vec(), mark));
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, StackState> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+/// A backend on a [`MemoryStack`], failing to capture once its queue is empty.
+pub struct MemoryBackend {
+    stack: MemoryStack,
+    queue: usize,
+}
+
+impl Backend for MemoryBackend {
+    type Meta = Fwmark;
+
+    fn capture(&self, buffer: &mut [u8]) -> Result<(usize, Fwmark)> {
+        let (packet, mark) = self.stack.lock().queues[self.queue]
+            .pop_front()
+            .context("No packet to capture")?;
+
+        let len = packet.len().min(buffer.len());
+        buffer[..len].copy_from_slice(&packet[..len]);
+
+        Ok((len, mark))
+    }
+
+    fn transmit(&self, packet: &[u8], mark: &Fwmark) -> Result<()> {
+        self.stack.route(packet, *mark);
+        Ok(())
+    }
+
+    #[inline]
+    fn is_marked(mark: &Fwmark) -> bool {
+        mark.is_injected()
+    }
+
+    #[inline]
+    fn mark(mark: &mut Fwmark) {
+        mark.set_injected();
+    }
+}
@@ -0,0 +1,456 @@
+//! Probing a domain with every candidate chain to find the ones that get through.
+//!
+//! Each combination of protocol, chain and fake TTL is tried with a real connection: an HTTP
+//! request or a TLS ClientHello is sent, and the first bytes of the answer tell whether the
+//! server was reached. Applying the chains to the probes is up to the interceptor, this module
+//! only plans the probes, runs a single one and reports the results.
+
+use std::{
+    collections::BTreeMap,
+    fmt,
+    io::{ErrorKind, Read, Write},
+    net::{IpAddr, SocketAddr, TcpStream, ToSocketAddrs},
+    time::{Duration, Instant},
+};
+
+use color_eyre::{
+    Result,
+    eyre::{ContextCompat, bail},
+};
+use serde::Serialize;
+
+use crate::{
+    autohostlist::{Outcome, response_outcome},
+    cidr::{Cidr, CidrFilter, CidrSet},
+    http::{FAKE_CLIENT_HELLO, tls::with_server_name},
+    profile::{Profile, Protocol, Rule},
+    settings::{Ports, Settings},
+    strategy::Step,
+};
+
+/// Name of the profile suggested by a report.
+pub const BLOCKCHECK_PROFILE: &str = "blockcheck";
+
+/// TLS record content type of an alert, sent by servers rejecting the ClientHello.
+const CONTENT_TYPE_ALERT: u8 = 0x15;
+/// TLS record content type of a handshake message, e.g. the ServerHello.
+const CONTENT_TYPE_HANDSHAKE: u8 = 0x16;
+
+/// Fake TTLs tried when none are given.
+const DEFAULT_TTLS: &[u8] = &[2, 3, 4, 5, 6, 8];
+/// How long a probe waits for the connection and for the answer.
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// What to probe, parsed from the command line.
+pub struct BlockcheckOptions {
+    pub domain: String,
+    pub protocols: Vec<Protocol>,
+    /// Port to connect to instead of 80 for HTTP and 443 for TLS.
+    pub port: Option<u16>,
+    /// Address to connect to instead of the resolved domain, e.g. a local stand-in server.
+    pub connect: Option<IpAddr>,
+    pub ttls: Vec<u8>,
+    pub timeout: Duration,
+    pub json: bool,
+}
+
+impl BlockcheckOptions {
+    /// Parse the arguments following `blockcheck`.
+    pub fn parse(args: &[String]) -> Result<Self> {
+        const USAGE: &str = "Usage: packetmock blockcheck DOMAIN [--protocol http|tls] \
+                             [--port PORT] [--connect ADDRESS] [--ttl 2,4,...] \
+                             [--timeout SECONDS] [--json]";
+
+        let mut options = Self {
+            domain: String::new(),
+            protocols: vec![Protocol::Http, Protocol::Tls],
+            port: None,
+            connect: None,
+            ttls: DEFAULT_TTLS.to_vec(),
+            timeout: DEFAULT_TIMEOUT,
+            json: false,
+        };
+
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            let mut value = || args.next().context(USAGE);
+
+            match arg.as_str() {
+                "--protocol" => {
+                    options.protocols = match value()?.as_str() {
+                        "http" => vec![Protocol::Http],
+                        "tls" => vec![Protocol::Tls],
+                        protocol => bail!("Unknown protocol {protocol:?}, expected http or tls"),
+                    }
+                }
+                "--port" => options.port = Some(value()?.parse()?),
+                "--connect" => options.connect = Some(value()?.parse()?),
+                "--ttl" => {
+                    options.ttls = value()?
+                        .split(',')
+                        .map(|ttl| ttl.trim().parse())
+                        .collect::<Result<_, _>>()?;
+                }
+                "--timeout" => options.timeout = Duration::from_secs(value()?.parse()?),
+                "--json" => options.json = true,
+                domain if options.domain.is_empty() && !domain.starts_with('-') => {
+                    options.domain = domain.to_owned();
+                }
+                _ => bail!(USAGE),
+            }
+        }
+
+        if options.domain.is_empty() {
+            bail!(USAGE);
+        }
+        if options.ttls.contains(&0) {
+            bail!("TTL must be at least 1");
+        }
+
+        Ok(options)
+    }
+
+    /// The address probes of the protocol connect to.
+    pub fn target(&self, protocol: Protocol) -> Result<SocketAddr> {
+        let port = self.port.unwrap_or(match protocol {
+            Protocol::Http => 80,
+            Protocol::Tls => 443,
+        });
+
+        if let Some(connect) = self.connect {
+            return Ok(SocketAddr::new(connect, port));
+        }
+
+        (self.domain.as_str(), port)
+            .to_socket_addrs()?
+            .next()
+            .with_context(|| format!("{} has no address", self.domain))
+    }
+}
+
+/// The chains tried when no `adaptive.candidates` are configured, from the lightest to the
+/// heaviest.
+pub fn default_chains() -> Vec<Vec<Step>> {
+    let fake = Step::Fake { ttl: None };
+    let split = Step::Split { position: 1 };
+
+    vec![
+        vec![fake.clone()],
+        vec![split.clone()],
+        vec![fake.clone(), split.clone()],
+        vec![fake.clone(), fake, split],
+    ]
+}
+
+/// Every chain to probe: no chain first, then each candidate, once per TTL if it sends fakes
+/// without their own TTL.
+pub fn combinations(candidates: &[Vec<Step>], ttls: &[u8]) -> Vec<Vec<Step>> {
+    let mut combinations = vec![Vec::new()];
+
+    for chain in candidates {
+        let uses_ttl = chain
+            .iter()
+            .any(|step| matches!(step, Step::Fake { ttl: None }));
+
+        if !uses_ttl {
+            combinations.push(chain.clone());
+            continue;
+        }
+
+        for &ttl in ttls {
+            let chain = chain.iter().map(|step| match step {
+                Step::Fake { ttl: None } => Step::Fake { ttl: Some(ttl) },
+                step => step.clone(),
+            });
+            combinations.push(chain.collect());
+        }
+    }
+
+    combinations
+}
+
+/// The settings desyncing every probe to the targets with the chain, and nothing else.
+///
+/// Hostlists and network lists still have to be replaced once loaded, see `probe_addresses`.
+pub fn probe_settings(settings: &Settings, chain: &[Step], targets: &[SocketAddr]) -> Settings {
+    let mut settings = settings.clone();
+
+    let mut ports = targets.iter().map(SocketAddr::port).collect::<Vec<_>>();
+    ports.sort_unstable();
+    ports.dedup();
+    settings.ports = Ports {
+        http: Vec::new(),
+        tls: Vec::new(),
+        auto: ports,
+        any: false,
+    };
+
+    let rule = Rule {
+        chain: chain.to_vec(),
+        ..Default::default()
+    };
+    settings
+        .profiles
+        .insert(BLOCKCHECK_PROFILE.to_owned(), Profile { rules: vec![rule] });
+    settings.active_profile = BLOCKCHECK_PROFILE.to_owned();
+    settings.schedule.clear();
+
+    // the probes must not teach the running configuration anything
+    settings.blocking.auto_hostlist = false;
+    settings.adaptive.candidates.clear();
+
+    settings
+}
+
+/// The networks of the targets alone.
+pub fn probe_addresses(targets: &[SocketAddr]) -> Result<CidrFilter> {
+    let networks = targets
+        .iter()
+        .map(|target| Cidr::new(target.ip(), if target.is_ipv4() { 32 } else { 128 }))
+        .collect::<Result<Vec<_>>>()?;
+
+    Ok(CidrFilter {
+        include: CidrSet::new(&networks),
+        exclude: CidrSet::default(),
+    })
+}
+
+/// How a probe turned out.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Verdict {
+    /// The server answered.
+    Works,
+    /// The answer was a redirect to another domain or unavailable for legal reasons.
+    Blockpage,
+    /// The connection was reset.
+    Reset,
+    /// The connection was closed without an answer.
+    Closed,
+    /// Nothing was answered in time.
+    Timeout,
+    /// The connection couldn't be opened.
+    Unreachable,
+}
+
+impl fmt::Display for Verdict {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Works => "works",
+            Self::Blockpage => "blockpage",
+            Self::Reset => "reset",
+            Self::Closed => "closed",
+            Self::Timeout => "timeout",
+            Self::Unreachable => "unreachable",
+        })
+    }
+}
+
+/// Connect to the target and send the first payload of the protocol for the domain, judging
+/// the server from the start of its answer.
+pub fn probe(target: SocketAddr, domain: &str, protocol: Protocol, timeout: Duration) -> Verdict {
+    let Ok(mut stream) = TcpStream::connect_timeout(&target, timeout) else {
+        return Verdict::Unreachable;
+    };
+    let Some(payload) = probe_payload(domain, protocol) else {
+        return Verdict::Unreachable;
+    };
+
+    let mut answer = [0; 4096];
+    let result = stream
+        .set_read_timeout(Some(timeout))
+        .and_then(|()| stream.write_all(&payload))
+        .and_then(|()| stream.read(&mut answer));
+
+    match result {
+        Ok(0) => Verdict::Closed,
+        Ok(len) => judge(domain, protocol, &answer[..len]),
+        Err(e) if matches!(e.kind(), ErrorKind::TimedOut | ErrorKind::WouldBlock) => {
+            Verdict::Timeout
+        }
+        Err(e)
+            if matches!(
+                e.kind(),
+                ErrorKind::ConnectionReset | ErrorKind::ConnectionAborted
+            ) =>
+        {
+            Verdict::Reset
+        }
+        Err(_) => Verdict::Closed,
+    }
+}
+
+/// The first payload a probe of the protocol sends for the domain, `None` if the domain
+/// doesn't fit in a ClientHello.
+pub fn probe_payload(domain: &str, protocol: Protocol) -> Option<Vec<u8>> {
+    match protocol {
+        Protocol::Http => Some(
+            format!(
+                "GET / HTTP/1.1\r\nHost: {domain}\r\nUser-Agent: curl/8.14.1\r\nAccept: */*\r\n\
+                 Connection: close\r\n\r\n"
+            )
+            .into_bytes(),
+        ),
+        Protocol::Tls => with_server_name(FAKE_CLIENT_HELLO, domain),
+    }
+}
+
+/// Judge the server from the start of its answer to a probe.
+pub fn judge(domain: &str, protocol: Protocol, answer: &[u8]) -> Verdict {
+    match protocol {
+        Protocol::Http => match response_outcome(domain, answer) {
+            Outcome::Responded => Verdict::Works,
+            _ => Verdict::Blockpage,
+        },
+        Protocol::Tls => match answer.first() {
+            Some(&(CONTENT_TYPE_HANDSHAKE | CONTENT_TYPE_ALERT)) => Verdict::Works,
+            // e.g. a plain HTTP blockpage injected into the TLS flow
+            Some(_) => Verdict::Blockpage,
+            None => Verdict::Closed,
+        },
+    }
+}
+
+/// The outcome of a single combination.
+#[derive(Clone, Serialize)]
+pub struct ProbeResult {
+    pub protocol: Protocol,
+    pub target: SocketAddr,
+    /// Steps applied to the probe, empty for the probe without desync.
+    pub chain: Vec<Step>,
+    pub verdict: Verdict,
+    pub millis: u128,
+}
+
+/// Every probe of a domain.
+#[derive(Serialize)]
+pub struct Report {
+    pub domain: String,
+    pub results: Vec<ProbeResult>,
+    /// The profile using the first working chain of each protocol.
+    pub profile: Profile,
+}
+
+impl Report {
+    pub fn new(domain: &str) -> Self {
+        Self {
+            domain: domain.to_owned(),
+            results: Vec::new(),
+            profile: Profile::default(),
+        }
+    }
+
+    /// Time the probe and add its result.
+    pub fn run(
+        &mut self,
+        protocol: Protocol,
+        target: SocketAddr,
+        chain: &[Step],
+        probe: impl FnOnce() -> Verdict,
+    ) -> &ProbeResult {
+        let start = Instant::now();
+        let verdict = probe();
+
+        self.results.push(ProbeResult {
+            protocol,
+            target,
+            chain: chain.to_vec(),
+            verdict,
+            millis: start.elapsed().as_millis(),
+        });
+        self.update_profile();
+
+        self.results.last().expect("A result was just added")
+    }
+
+    /// Whether probes of the protocol work without any desync.
+    pub fn works_without_desync(&self, protocol: Protocol) -> bool {
+        self.results.iter().any(|result| {
+            result.protocol == protocol
+                && result.chain.is_empty()
+                && result.verdict == Verdict::Works
+        })
+    }
+
+    /// The profile as a TOML snippet for the configuration file.
+    pub fn profile_toml(&self) -> Result<String> {
+        let profiles = BTreeMap::from([(BLOCKCHECK_PROFILE, &self.profile)]);
+        let snippet = BTreeMap::from([("profiles", profiles)]);
+
+        Ok(toml::to_string(&snippet)?)
+    }
+
+    fn update_profile(&mut self) {
+        let mut rules = Vec::new();
+
+        for protocol in [Protocol::Http, Protocol::Tls] {
+            let working = self.results.iter().find(|result| {
+                result.protocol == protocol
+                    && !result.chain.is_empty()
+                    && result.verdict == Verdict::Works
+            });
+
+            if let Some(result) = working
+                && !self.works_without_desync(protocol)
+            {
+                rules.push(Rule {
+                    protocol: Some(protocol),
+                    chain: result.chain.clone(),
+                    ..Default::default()
+                });
+            }
+        }
+
+        self.profile = Profile { rules };
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let rows = self
+            .results
+            .iter()
+            .map(|result| {
+                let protocol = match result.protocol {
+                    Protocol::Http => "http",
+                    Protocol::Tls => "tls",
+                };
+                let chain = if result.chain.is_empty() {
+                    "none".to_owned()
+                } else {
+                    let steps = result.chain.iter().map(Step::to_string);
+                    steps.collect::<Vec<_>>().join(", ")
+                };
+
+                [
+                    protocol.to_owned(),
+                    result.target.to_string(),
+                    chain,
+                    result.verdict.to_string(),
+                    format!("{} ms", result.millis),
+                ]
+            })
+            .collect::<Vec<_>>();
+
+        let header = ["protocol", "target", "chain", "result", "time"].map(str::to_owned);
+        let widths = header.clone().map(|title| title.len());
+        let widths = rows.iter().fold(widths, |mut widths, row| {
+            for (width, cell) in widths.iter_mut().zip(row) {
+                *width = (*width).max(cell.len());
+            }
+            widths
+        });
+
+        writeln!(f, "Blockcheck of {}:", self.domain)?;
+        for row in std::iter::once(&header).chain(&rows) {
+            let cells = row
+                .iter()
+                .zip(widths)
+                .map(|(cell, width)| format!("{cell:width$}"))
+                .collect::<Vec<_>>();
+            writeln!(f, "  {}", cells.join("  ").trim_end())?;
+        }
+
+        Ok(())
+    }
+}
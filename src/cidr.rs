@@ -0,0 +1,403 @@
+use std::{
+    fmt, fs,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    path::Path,
+    str::FromStr,
+};
+
+use color_eyre::{
+    Result,
+    eyre::{Context, bail},
+};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::hostlist::InvalidEntry;
+
+/// Default file name of the list of networks the desync is applied to.
+pub const CIDR_FILE: &str = "cidr.txt";
+/// Default file name of the list of networks the desync is never applied to.
+pub const CIDR_EXCLUDE_FILE: &str = "cidr-exclude.txt";
+
+/// Networks that are never worth desyncing: local, private, shared, reserved and multicast
+/// ranges.
+pub const DEFAULT_EXCLUDE: &[&str] = &[
+    "0.0.0.0/8",
+    "10.0.0.0/8",
+    "100.64.0.0/10",
+    "127.0.0.0/8",
+    "169.254.0.0/16",
+    "172.16.0.0/12",
+    "192.168.0.0/16",
+    "198.18.0.0/15",
+    "224.0.0.0/4",
+    "240.0.0.0/4",
+    "::/128",
+    "::1/128",
+    "fc00::/7",
+    "fe80::/10",
+    "ff00::/8",
+];
+
+/// Sentinel for a missing node index.
+const NONE: u32 = u32::MAX;
+
+/// An IPv4 or IPv6 network in CIDR notation, e.g. `10.0.0.0/8` or `fe80::/10`.
+///
+/// A bare address is a network of that address alone. Bits after the prefix are cleared.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct Cidr {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl Cidr {
+    pub fn new(addr: IpAddr, prefix: u8) -> Result<Self> {
+        let bits = bit_length(addr);
+
+        if prefix > bits {
+            bail!("Prefix length {prefix} is too long for {addr}");
+        }
+
+        let mask = prefix_mask(prefix);
+        let addr = from_key(to_key(addr) & mask, addr.is_ipv4());
+
+        Ok(Self { addr, prefix })
+    }
+
+    /// First address of the network.
+    #[inline]
+    pub fn first(&self) -> IpAddr {
+        self.addr
+    }
+
+    /// Last address of the network.
+    pub fn last(&self) -> IpAddr {
+        let mask = prefix_mask(self.prefix);
+        from_key(to_key(self.addr) | !mask, self.addr.is_ipv4())
+    }
+
+    #[inline]
+    pub fn prefix(&self) -> u8 {
+        self.prefix
+    }
+
+    #[inline]
+    pub fn is_ipv4(&self) -> bool {
+        self.addr.is_ipv4()
+    }
+}
+
+impl FromStr for Cidr {
+    type Err = color_eyre::Report;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (addr, prefix) = match s.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (s, None),
+        };
+
+        let addr = addr
+            .parse::<IpAddr>()
+            .wrap_err_with(|| format!("Invalid address in {s:?}"))?;
+        let prefix = match prefix {
+            Some(prefix) => prefix
+                .parse()
+                .wrap_err_with(|| format!("Invalid prefix length in {s:?}"))?,
+            None => bit_length(addr),
+        };
+
+        Self::new(addr, prefix)
+    }
+}
+
+impl TryFrom<String> for Cidr {
+    type Error = color_eyre::Report;
+
+    fn try_from(s: String) -> Result<Self> {
+        s.parse()
+    }
+}
+
+impl From<Cidr> for String {
+    fn from(cidr: Cidr) -> Self {
+        cidr.to_string()
+    }
+}
+
+impl fmt::Display for Cidr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix)
+    }
+}
+
+/// A set of networks stored as a binary prefix tree per address family.
+#[derive(Default)]
+pub struct CidrSet {
+    v4: PrefixTree,
+    v6: PrefixTree,
+}
+
+/// A binary trie over the address bits, a node marked as terminal covers its whole subtree.
+#[derive(Default)]
+struct PrefixTree {
+    nodes: Vec<Node>,
+}
+
+#[derive(Clone, Copy)]
+struct Node {
+    children: [u32; 2],
+    terminal: bool,
+}
+
+impl CidrSet {
+    pub fn new<'a>(networks: impl IntoIterator<Item = &'a Cidr>) -> Self {
+        let mut set = Self::default();
+
+        for cidr in networks {
+            set.insert(cidr);
+        }
+
+        set
+    }
+
+    /// Parse a list of networks, returning the set and the lines that couldn't be parsed.
+    ///
+    /// Every line holds one network, empty lines and everything after `#` are ignored.
+    pub fn parse(text: &str) -> (Self, Vec<InvalidEntry>) {
+        let mut set = Self::default();
+        let mut invalid = Vec::new();
+
+        for (index, line) in text.lines().enumerate() {
+            let entry = line.split('#').next().unwrap_or_default().trim();
+
+            if entry.is_empty() {
+                continue;
+            }
+
+            match entry.parse::<Cidr>() {
+                Ok(cidr) => set.insert(&cidr),
+                Err(_) => invalid.push(InvalidEntry {
+                    line: index + 1,
+                    entry: entry.to_owned(),
+                }),
+            }
+        }
+
+        (set, invalid)
+    }
+
+    /// Load networks from a file into the set if it exists, skipping and logging the lines
+    /// that couldn't be parsed.
+    pub fn extend_from_file(&mut self, path: &Path) -> Result<()> {
+        if !path.exists() {
+            return Ok(());
+        }
+
+        let text = fs::read_to_string(path)
+            .wrap_err_with(|| format!("Failed to read network list {}", path.display()))?;
+
+        let (set, invalid) = Self::parse(&text);
+
+        for entry in invalid {
+            warn!(
+                "{}:{}: ignoring invalid network {:?}",
+                path.display(),
+                entry.line,
+                entry.entry
+            );
+        }
+
+        let networks = set.networks();
+        info!("Loaded {} networks from {}", networks.len(), path.display());
+
+        for cidr in &networks {
+            self.insert(cidr);
+        }
+
+        Ok(())
+    }
+
+    pub fn insert(&mut self, cidr: &Cidr) {
+        let tree = if cidr.is_ipv4() {
+            &mut self.v4
+        } else {
+            &mut self.v6
+        };
+
+        tree.insert(to_key(cidr.addr), cidr.prefix);
+    }
+
+    /// Check if the address is inside any network of the set.
+    pub fn contains(&self, addr: IpAddr) -> bool {
+        match addr {
+            IpAddr::V4(_) => self.v4.contains(to_key(addr), 32),
+            IpAddr::V6(_) => self.v6.contains(to_key(addr), 128),
+        }
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.v4.nodes.is_empty() && self.v6.nodes.is_empty()
+    }
+
+    /// The networks of the set, without the ones covered by larger networks.
+    pub fn networks(&self) -> Vec<Cidr> {
+        let mut networks = Vec::new();
+
+        self.v4.collect(0, 0, 0, true, &mut networks);
+        self.v6.collect(0, 0, 0, false, &mut networks);
+
+        networks
+    }
+}
+
+impl PrefixTree {
+    fn insert(&mut self, key: u128, prefix: u8) {
+        if self.nodes.is_empty() {
+            self.push_node();
+        }
+
+        let mut node = 0;
+
+        for depth in 0..prefix {
+            if self.nodes[node].terminal {
+                // already covered by a larger network
+                return;
+            }
+
+            let bit = bit_at(key, depth);
+            node = match self.nodes[node].children[bit] {
+                NONE => {
+                    let child = self.push_node();
+                    self.nodes[node].children[bit] = child as u32;
+                    child
+                }
+                child => child as usize,
+            };
+        }
+
+        // the subtree is covered now, its nodes stay allocated but unreachable
+        self.nodes[node] = Node {
+            children: [NONE; 2],
+            terminal: true,
+        };
+    }
+
+    fn contains(&self, key: u128, bits: u8) -> bool {
+        if self.nodes.is_empty() {
+            return false;
+        }
+
+        let mut node = &self.nodes[0];
+
+        for depth in 0..bits {
+            if node.terminal {
+                return true;
+            }
+
+            match node.children[bit_at(key, depth)] {
+                NONE => return false,
+                child => node = &self.nodes[child as usize],
+            }
+        }
+
+        node.terminal
+    }
+
+    fn collect(&self, node: usize, key: u128, depth: u8, ipv4: bool, out: &mut Vec<Cidr>) {
+        let Some(current) = self.nodes.get(node) else {
+            return;
+        };
+
+        if current.terminal {
+            out.push(Cidr {
+                addr: from_key(key, ipv4),
+                prefix: depth,
+            });
+            return;
+        }
+
+        for (bit, &child) in current.children.iter().enumerate() {
+            if child != NONE {
+                let key = key | (bit as u128) << (127 - depth);
+                self.collect(child as usize, key, depth + 1, ipv4, out);
+            }
+        }
+    }
+
+    fn push_node(&mut self) -> usize {
+        self.nodes.push(Node {
+            children: [NONE; 2],
+            terminal: false,
+        });
+        self.nodes.len() - 1
+    }
+}
+
+/// Include and exclude network sets deciding which destinations the desync is applied to.
+#[derive(Default)]
+pub struct CidrFilter {
+    /// Networks to desync. If empty, every destination is desynced unless excluded.
+    pub include: CidrSet,
+    /// Networks never to desync.
+    pub exclude: CidrSet,
+}
+
+impl CidrFilter {
+    /// Build the filter from the configured networks and the network list files, if they exist.
+    pub fn load(
+        include: &[Cidr],
+        exclude: &[Cidr],
+        include_file: &Path,
+        exclude_file: &Path,
+    ) -> Result<Self> {
+        let mut filter = Self {
+            include: CidrSet::new(include),
+            exclude: CidrSet::new(exclude),
+        };
+
+        filter.include.extend_from_file(include_file)?;
+        filter.exclude.extend_from_file(exclude_file)?;
+
+        Ok(filter)
+    }
+
+    /// Check if packets to the given address should be desynced.
+    pub fn matches(&self, addr: IpAddr) -> bool {
+        (self.include.is_empty() || self.include.contains(addr)) && !self.exclude.contains(addr)
+    }
+}
+
+/// Number of bits in an address of the family of `addr`.
+#[inline]
+fn bit_length(addr: IpAddr) -> u8 {
+    if addr.is_ipv4() { 32 } else { 128 }
+}
+
+/// The address bits aligned to the most significant bit of a `u128`.
+fn to_key(addr: IpAddr) -> u128 {
+    match addr {
+        IpAddr::V4(addr) => (u32::from(addr) as u128) << 96,
+        IpAddr::V6(addr) => u128::from(addr),
+    }
+}
+
+fn from_key(key: u128, ipv4: bool) -> IpAddr {
+    if ipv4 {
+        IpAddr::V4(Ipv4Addr::from((key >> 96) as u32))
+    } else {
+        IpAddr::V6(Ipv6Addr::from(key))
+    }
+}
+
+/// Mask of the first `prefix` bits of a key.
+fn prefix_mask(prefix: u8) -> u128 {
+    u128::MAX.checked_shl(128 - u32::from(prefix)).unwrap_or(0)
+}
+
+#[inline]
+fn bit_at(key: u128, depth: u8) -> usize {
+    (key >> (127 - depth) & 1) as usize
+}
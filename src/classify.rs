@@ -0,0 +1,57 @@
+//! Telling the application protocol of a flow from the content of its first payload.
+//!
+//! The port of a flow is only a hint: TLS and HTTP are desynced on any port, and ports can be
+//! pinned to a protocol for servers whose first payload can't be recognized.
+
+use serde::{Deserialize, Serialize};
+
+use crate::http::{parse_http_requests, tls::is_client_hello};
+
+/// Prefix of the identification string an SSH client opens a connection with (RFC 4253).
+const SSH_BANNER: &[u8] = b"SSH-";
+
+/// How the protocol of the flows to a port is determined.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Classifier {
+    /// Every flow is HTTP.
+    Http,
+    /// Every flow is TLS.
+    Tls,
+    /// The protocol is detected from the payload.
+    Auto,
+}
+
+/// Protocol recognized in the first payload of a flow.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Detected {
+    Tls,
+    Http,
+    Ssh,
+    Unknown,
+}
+
+impl Classifier {
+    /// The protocol of a payload sent to a port using this classifier.
+    pub fn classify(self, data: &[u8]) -> Detected {
+        match self {
+            Self::Http => Detected::Http,
+            Self::Tls => Detected::Tls,
+            Self::Auto => detect(data),
+        }
+    }
+}
+
+/// Detect the protocol from the first payload of a flow: a TLS ClientHello, an HTTP/1.x
+/// request line or an SSH identification string.
+pub fn detect(data: &[u8]) -> Detected {
+    if is_client_hello(data) {
+        Detected::Tls
+    } else if data.starts_with(SSH_BANNER) {
+        Detected::Ssh
+    } else if parse_http_requests(data).next().is_some() {
+        Detected::Http
+    } else {
+        Detected::Unknown
+    }
+}
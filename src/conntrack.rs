@@ -0,0 +1,251 @@
+//! Tracking of the TCP connections the intercepted packets belong to.
+//!
+//! Packets are only meaningful in the context of their connection: the first payload is
+//! desynced once, while its retransmissions and the segments following it are not. Flows are
+//! keyed by their local and remote endpoints, the protocol of the 5-tuple is always TCP.
+
+use std::{
+    collections::{BTreeSet, HashMap},
+    net::SocketAddr,
+    time::{Duration, Instant},
+};
+
+use crate::{autohostlist::Outcome, stats::STATS, strategy::Step};
+
+/// Endpoints of a flow, seen from this machine.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Debug)]
+pub struct FlowKey {
+    pub local: SocketAddr,
+    pub remote: SocketAddr,
+}
+
+/// Progress of the connection as seen from its packets.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum FlowState {
+    /// The client sent a SYN, the server hasn't answered yet.
+    SynSent,
+    /// The server answered with a SYN-ACK, or data was seen without the handshake.
+    Established,
+    /// Either side sent a FIN.
+    Closing,
+    /// Either side sent a RST, the flow is removed at the next expiry.
+    Reset,
+}
+
+/// What is known about a tracked flow.
+#[derive(Clone, Debug)]
+pub struct FlowEntry {
+    pub state: FlowState,
+    /// Initial sequence number of the client, `None` if the handshake wasn't seen.
+    pub client_isn: Option<u32>,
+    /// Initial sequence number of the server, `None` if the handshake wasn't seen.
+    pub server_isn: Option<u32>,
+    /// The first payload of the client was handled, later payloads aren't desynced.
+    pub first_payload: bool,
+    /// Sequence number of the first payload.
+    pub first_seq: Option<u32>,
+    /// Host name found in the first payload.
+    pub host: Option<String>,
+    /// Steps applied to the first payload, empty if it was passed through.
+    pub chain: Vec<Step>,
+    /// Times the first payload was sent again.
+    pub retransmissions: u32,
+    /// How the first request turned out, once known.
+    pub outcome: Option<Outcome>,
+    pub created: Instant,
+    /// When the last segment was seen, which the table orders the flows by.
+    last_seen: Instant,
+}
+
+impl FlowEntry {
+    /// When the last segment of the flow was seen.
+    #[inline]
+    pub fn last_seen(&self) -> Instant {
+        self.last_seen
+    }
+}
+
+/// TCP flags relevant to connection tracking.
+#[derive(Clone, Copy, Default, PartialEq, Eq, Debug)]
+pub struct TcpFlags {
+    pub syn: bool,
+    pub ack: bool,
+    pub fin: bool,
+    pub rst: bool,
+}
+
+/// The parts of a TCP segment the flow table looks at.
+#[derive(Clone, Copy, Debug)]
+pub struct Segment {
+    pub key: FlowKey,
+    /// Whether the segment was sent by this machine.
+    pub outbound: bool,
+    pub flags: TcpFlags,
+    pub seq: u32,
+    pub payload_length: usize,
+}
+
+/// Flows by their endpoints, bounded in size.
+///
+/// Flows expire after being idle for `idle_timeout`, after `close_timeout` once closing, and
+/// at the first expiry after a RST. When the table is full, the least recently seen flow is evicted.
+pub struct FlowTable {
+    flows: HashMap<FlowKey, FlowEntry>,
+    /// The flows ordered by when they were last seen, the least recently seen first.
+    order: BTreeSet<(Instant, FlowKey)>,
+    capacity: usize,
+    idle_timeout: Duration,
+    close_timeout: Duration,
+}
+
+impl FlowTable {
+    pub fn new(capacity: usize, idle_timeout: Duration, close_timeout: Duration) -> Self {
+        Self {
+            flows: HashMap::new(),
+            order: BTreeSet::new(),
+            capacity,
+            idle_timeout,
+            close_timeout,
+        }
+    }
+
+    /// Change the limits, evicting flows if the table is now too large.
+    pub fn set_limits(&mut self, capacity: usize, idle_timeout: Duration, close_timeout: Duration) {
+        self.capacity = capacity;
+        self.idle_timeout = idle_timeout;
+        self.close_timeout = close_timeout;
+
+        self.evict(capacity, Instant::now());
+    }
+
+    /// Update the flow of the segment, returning its entry if it is tracked.
+    ///
+    /// Flows are created by an outbound SYN, or by outbound data of a connection opened
+    /// before tracking started. Inbound segments of unknown flows are ignored, and segments
+    /// of a reset flow are still reported until it expires.
+    pub fn track(&mut self, segment: &Segment, now: Instant) -> Option<&mut FlowEntry> {
+        let flags = segment.flags;
+
+        let reopened = segment.outbound
+            && flags.syn
+            && !flags.ack
+            && self
+                .flows
+                .get(&segment.key)
+                .is_some_and(|entry| entry.state != FlowState::SynSent);
+
+        // the local port was reused for a new connection
+        if reopened && let Some(entry) = self.flows.remove(&segment.key) {
+            self.order.remove(&(entry.last_seen, segment.key));
+        }
+
+        if !self.flows.contains_key(&segment.key) {
+            let state = match (segment.outbound, flags.syn && !flags.ack) {
+                (true, true) => FlowState::SynSent,
+                (true, false) if segment.payload_length > 0 => FlowState::Established,
+                _ => return None,
+            };
+
+            // room for the new flow
+            self.evict(self.capacity.max(1) - 1, now);
+            self.order.insert((now, segment.key));
+            self.flows.insert(
+                segment.key,
+                FlowEntry {
+                    state,
+                    client_isn: (state == FlowState::SynSent).then_some(segment.seq),
+                    server_isn: None,
+                    first_payload: false,
+                    first_seq: None,
+                    host: None,
+                    chain: Vec::new(),
+                    retransmissions: 0,
+                    outcome: None,
+                    created: now,
+                    last_seen: now,
+                },
+            );
+        }
+
+        let entry = self.flows.get_mut(&segment.key)?;
+        if entry.last_seen != now {
+            self.order.remove(&(entry.last_seen, segment.key));
+            self.order.insert((now, segment.key));
+            entry.last_seen = now;
+        }
+
+        if flags.syn && flags.ack && !segment.outbound {
+            entry.server_isn = Some(segment.seq);
+            if entry.state == FlowState::SynSent {
+                entry.state = FlowState::Established;
+            }
+        }
+        if segment.outbound && segment.payload_length > 0 && entry.first_seq == Some(segment.seq) {
+            entry.retransmissions += 1;
+        }
+
+        if flags.rst {
+            entry.state = FlowState::Reset;
+        } else if flags.fin && entry.state != FlowState::Reset {
+            entry.state = FlowState::Closing;
+        }
+
+        Some(entry)
+    }
+
+    #[inline]
+    pub fn get_mut(&mut self, key: &FlowKey) -> Option<&mut FlowEntry> {
+        self.flows.get_mut(key)
+    }
+
+    /// Remove the flows that were idle for too long, or closing for long enough.
+    pub fn expire(&mut self, now: Instant) {
+        let (idle_timeout, close_timeout) = (self.idle_timeout, self.close_timeout);
+        let order = &mut self.order;
+
+        self.flows.retain(|key, entry| {
+            let idle = now.saturating_duration_since(entry.last_seen);
+
+            let closed = entry.state == FlowState::Reset
+                || (entry.state == FlowState::Closing && idle >= close_timeout);
+            if closed {
+                STATS.flows_closed.increment();
+            } else if idle >= idle_timeout {
+                STATS.flows_expired.increment();
+            } else {
+                return true;
+            }
+
+            order.remove(&(entry.last_seen, *key));
+            false
+        });
+    }
+
+    /// Number of tracked flows.
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.flows.len()
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.flows.is_empty()
+    }
+
+    /// Remove the least recently seen flows until at most `len` are left, in logarithmic time
+    /// per flow. Closing and reset flows seen more recently are left to `expire`.
+    fn evict(&mut self, len: usize, now: Instant) {
+        while self.flows.len() > len {
+            let Some((last_seen, oldest)) = self.order.pop_first() else {
+                break;
+            };
+
+            self.flows.remove(&oldest);
+            if now.saturating_duration_since(last_seen) >= self.idle_timeout {
+                STATS.flows_expired.increment();
+            } else {
+                STATS.flows_evicted.increment();
+            }
+        }
+    }
+}
@@ -0,0 +1,474 @@
+//! DNS queries answered through an encrypted resolver instead of their server.
+//!
+//! Queries to port 53 are taken off the network and resolved over DNS-over-HTTPS or
+//! DNS-over-TLS, then answered as if their server had replied, so a poisoning ISP never sees
+//! them. Answers are cached for the lowest TTL of their records, which is counted down in the
+//! cached copies.
+
+pub mod guard;
+pub mod packet;
+pub mod resolver;
+
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    ops::Range,
+    time::{Duration, Instant},
+};
+
+use color_eyre::Result;
+use log::{debug, warn};
+
+use self::resolver::Resolver;
+use crate::{settings::Dns, stats::STATS};
+
+/// Length of the message header.
+const HEADER_LEN: usize = 12;
+/// Length of the smallest resource record: a root name, the fixed fields and no data.
+const MIN_RECORD_LEN: usize = 11;
+const FLAG_RESPONSE: u16 = 0x8000;
+const FLAG_TRUNCATED: u16 = 0x0200;
+const FLAG_RECURSION_DESIRED: u16 = 0x0100;
+const FLAG_RECURSION_AVAILABLE: u16 = 0x0080;
+const OPCODE_MASK: u16 = 0x7800;
+const RCODE_MASK: u16 = 0x000f;
+const RCODE_SERVFAIL: u16 = 2;
+const RCODE_NXDOMAIN: u16 = 3;
+const TYPE_A: u16 = 1;
+const TYPE_AAAA: u16 = 28;
+/// Type of the EDNS pseudo-record, whose TTL field holds flags.
+const TYPE_OPT: u16 = 41;
+/// Largest answer over UDP to a query that doesn't announce a size (RFC 1035).
+const MAX_UDP_ANSWER: usize = 512;
+/// How long queries fail right away after the resolver failed, rather than each waiting for
+/// it to time out.
+const RESOLVER_BACKOFF: Duration = Duration::from_secs(5);
+/// Seconds answers without records, e.g. for unknown domains, are cached.
+const NEGATIVE_TTL: u32 = 60;
+
+/// The question of a query, which answers are cached by.
+#[derive(Clone, PartialEq, Eq, Hash, Debug)]
+pub struct Question {
+    /// The queried name, lower case.
+    pub name: String,
+    pub kind: u16,
+    pub class: u16,
+}
+
+/// A standard query with a single question.
+pub struct Query<'a> {
+    pub data: &'a [u8],
+    pub id: u16,
+    pub question: Question,
+    /// Largest answer the client takes over UDP.
+    pub udp_size: usize,
+    /// Offset right after the question.
+    question_end: usize,
+}
+
+impl Query<'_> {
+    /// The bytes of the question, as the client cased them.
+    fn question_bytes(&self) -> &[u8] {
+        &self.data[HEADER_LEN..self.question_end]
+    }
+}
+
+/// Parse a standard query with a single question, `None` for anything else.
+pub fn parse_query(data: &[u8]) -> Option<Query<'_>> {
+    let flags = read_u16(data, 2)?;
+    if flags & FLAG_RESPONSE != 0 || flags & OPCODE_MASK != 0 {
+        return None;
+    }
+
+    let (question, question_end) = read_question(data)?;
+
+    let udp_size = records(data)?
+        .iter()
+        .find(|record| record.kind == TYPE_OPT)
+        .map_or(MAX_UDP_ANSWER, |record| usize::from(record.class))
+        .max(MAX_UDP_ANSWER);
+
+    Some(Query {
+        data,
+        id: read_u16(data, 0)?,
+        question,
+        udp_size,
+        question_end,
+    })
+}
+
+/// The single question of a message and the offset right after it.
+fn read_question(data: &[u8]) -> Option<(Question, usize)> {
+    if read_u16(data, 4)? != 1 {
+        return None;
+    }
+
+    let mut labels = Vec::new();
+    let mut offset = HEADER_LEN;
+
+    loop {
+        let len = usize::from(*data.get(offset)?);
+        offset += 1;
+
+        if len == 0 {
+            break;
+        }
+        // the question comes first, there is no earlier name to point to
+        if len > 63 {
+            return None;
+        }
+
+        let label = data.get(offset..offset + len)?;
+        labels.push(String::from_utf8_lossy(label).to_ascii_lowercase());
+        offset += len;
+    }
+
+    let question = Question {
+        name: labels.join("."),
+        kind: read_u16(data, offset)?,
+        class: read_u16(data, offset + 2)?,
+    };
+
+    Some((question, offset + 4))
+}
+
+/// A response to a query with a single question.
+pub struct Response {
+    pub id: u16,
+    pub question: Question,
+    /// Addresses of the A and AAAA records of the answer section.
+    pub addresses: Vec<IpAddr>,
+}
+
+/// Parse a response with a single question, `None` for anything else.
+pub fn parse_response(data: &[u8]) -> Option<Response> {
+    let flags = read_u16(data, 2)?;
+    if flags & FLAG_RESPONSE == 0 {
+        return None;
+    }
+
+    let (question, _) = read_question(data)?;
+    let answers = usize::from(read_u16(data, 6)?);
+
+    let addresses = records(data)?
+        .into_iter()
+        .take(answers)
+        .filter_map(|record| match (record.kind, &data[record.data]) {
+            (TYPE_A, &[a, b, c, d]) => Some(IpAddr::from([a, b, c, d])),
+            (TYPE_AAAA, bytes) => <[u8; 16]>::try_from(bytes).ok().map(IpAddr::from),
+            _ => None,
+        })
+        .collect();
+
+    Some(Response {
+        id: read_u16(data, 0)?,
+        question,
+        addresses,
+    })
+}
+
+/// A resource record, as far as caching is concerned.
+struct Record {
+    kind: u16,
+    class: u16,
+    /// Offset of the TTL field.
+    ttl: usize,
+    data: Range<usize>,
+}
+
+/// The records of the answer, authority and additional sections.
+fn records(data: &[u8]) -> Option<Vec<Record>> {
+    let questions = read_u16(data, 4)?;
+    let count = [6, 8, 10]
+        .into_iter()
+        .map(|offset| read_u16(data, offset).map(usize::from))
+        .sum::<Option<usize>>()?;
+
+    let mut offset = HEADER_LEN;
+    for _ in 0..questions {
+        offset = skip_name(data, offset)? + 4;
+    }
+
+    // the counts are whatever the sender claims, the records have to fit in the message
+    let mut records = Vec::with_capacity(count.min(data.len() / MIN_RECORD_LEN));
+    for _ in 0..count {
+        offset = skip_name(data, offset)?;
+
+        let len = usize::from(read_u16(data, offset + 8)?);
+        let record = Record {
+            kind: read_u16(data, offset)?,
+            class: read_u16(data, offset + 2)?,
+            ttl: offset + 4,
+            data: offset + 10..offset + 10 + len,
+        };
+        offset += 10 + len;
+
+        if offset > data.len() {
+            return None;
+        }
+        records.push(record);
+    }
+
+    Some(records)
+}
+
+/// The offset right after the name at the offset, which may end with a pointer.
+fn skip_name(data: &[u8], mut offset: usize) -> Option<usize> {
+    loop {
+        let len = *data.get(offset)?;
+
+        match len {
+            0 => return Some(offset + 1),
+            len if len & 0xc0 == 0xc0 => return Some(offset + 2),
+            len => offset += 1 + usize::from(len),
+        }
+    }
+}
+
+/// How long the answer can be cached: the lowest TTL of its records, `NEGATIVE_TTL` if it
+/// has none. `None` if it mustn't be cached.
+pub fn cache_ttl(response: &[u8]) -> Option<u32> {
+    let flags = read_u16(response, 2)?;
+    if flags & FLAG_TRUNCATED != 0 {
+        return None;
+    }
+
+    let rcode = flags & RCODE_MASK;
+    if rcode != 0 && rcode != RCODE_NXDOMAIN {
+        return None;
+    }
+
+    let ttls = records(response)?
+        .into_iter()
+        .filter(|record| record.kind != TYPE_OPT)
+        .map(|record| read_u32(response, record.ttl))
+        .collect::<Option<Vec<_>>>()?;
+
+    Some(ttls.into_iter().min().unwrap_or(NEGATIVE_TTL))
+}
+
+/// Subtract the seconds from the TTL of every record.
+fn age(response: &mut [u8], seconds: u32) {
+    let Some(records) = records(response) else {
+        return;
+    };
+
+    for record in records.iter().filter(|record| record.kind != TYPE_OPT) {
+        let ttl = &mut response[record.ttl..record.ttl + 4];
+        let aged = u32::from_be_bytes([ttl[0], ttl[1], ttl[2], ttl[3]]).saturating_sub(seconds);
+        ttl.copy_from_slice(&aged.to_be_bytes());
+    }
+}
+
+/// The response as an answer to the query: with its ID and question, and cut down to the
+/// header and question with the truncated flag if it is too large for UDP.
+pub fn answer_to(query: &Query<'_>, response: &[u8], udp: bool) -> Vec<u8> {
+    let question = query.question_bytes();
+
+    let mut answer = response.to_vec();
+    answer[..2].copy_from_slice(&query.id.to_be_bytes());
+
+    // the client may check the case it randomized (DNS 0x20)
+    if answer
+        .get(HEADER_LEN..HEADER_LEN + question.len())
+        .is_some_and(|bytes| bytes.eq_ignore_ascii_case(question))
+    {
+        answer[HEADER_LEN..HEADER_LEN + question.len()].copy_from_slice(question);
+    }
+
+    if udp && answer.len() > query.udp_size {
+        let flags = read_u16(&answer, 2).unwrap_or_default() | FLAG_TRUNCATED;
+
+        answer.truncate(HEADER_LEN);
+        answer[2..4].copy_from_slice(&flags.to_be_bytes());
+        answer[4..HEADER_LEN].copy_from_slice(&[0, 1, 0, 0, 0, 0, 0, 0]);
+        answer.extend_from_slice(question);
+    }
+
+    answer
+}
+
+/// A server failure answer to the query.
+pub fn servfail(query: &Query<'_>) -> Vec<u8> {
+    let flags = read_u16(query.data, 2).unwrap_or_default();
+    let flags = FLAG_RESPONSE
+        | flags & (OPCODE_MASK | FLAG_RECURSION_DESIRED)
+        | FLAG_RECURSION_AVAILABLE
+        | RCODE_SERVFAIL;
+
+    let mut answer = Vec::with_capacity(query.question_end);
+    answer.extend_from_slice(&query.id.to_be_bytes());
+    answer.extend_from_slice(&flags.to_be_bytes());
+    answer.extend_from_slice(&[0, 1, 0, 0, 0, 0, 0, 0]);
+    answer.extend_from_slice(query.question_bytes());
+
+    answer
+}
+
+/// A cached response.
+struct Cached {
+    response: Vec<u8>,
+    stored: Instant,
+    expires: Instant,
+}
+
+/// Responses by question, dropped once their TTL is over.
+pub struct DnsCache {
+    capacity: usize,
+    entries: HashMap<Question, Cached>,
+}
+
+impl DnsCache {
+    /// A cache of up to `capacity` responses, none if 0.
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            entries: HashMap::new(),
+        }
+    }
+
+    /// The cached response to the question, with the TTLs counted down.
+    pub fn get(&mut self, question: &Question, now: Instant) -> Option<Vec<u8>> {
+        let cached = self.entries.get(question)?;
+
+        if cached.expires <= now {
+            self.entries.remove(question);
+            return None;
+        }
+
+        let mut response = cached.response.clone();
+        let elapsed = now.duration_since(cached.stored).as_secs();
+        age(&mut response, u32::try_from(elapsed).unwrap_or(u32::MAX));
+
+        Some(response)
+    }
+
+    /// Cache the response to the question if it can be, making room if needed.
+    pub fn insert(&mut self, question: Question, response: &[u8], now: Instant) {
+        if self.capacity == 0 {
+            return;
+        }
+        let Some(ttl) = cache_ttl(response).filter(|&ttl| ttl > 0) else {
+            return;
+        };
+
+        if self.entries.len() >= self.capacity && !self.entries.contains_key(&question) {
+            self.entries.retain(|_, cached| cached.expires > now);
+        }
+        if self.entries.len() >= self.capacity
+            && let Some(soonest) = self
+                .entries
+                .iter()
+                .min_by_key(|(_, cached)| cached.expires)
+                .map(|(question, _)| question.clone())
+        {
+            self.entries.remove(&soonest);
+        }
+
+        self.entries.insert(
+            question,
+            Cached {
+                response: response.to_vec(),
+                stored: now,
+                expires: now + Duration::from_secs(u64::from(ttl)),
+            },
+        );
+    }
+
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+/// Answers queries from the cache or through the resolver.
+pub struct DnsForwarder {
+    resolver: Resolver,
+    cache: DnsCache,
+    /// Until when the resolver is taken to be down after failing.
+    down_until: Option<Instant>,
+}
+
+impl DnsForwarder {
+    pub fn new(settings: &Dns) -> Result<Self> {
+        Ok(Self::with_resolver(
+            Resolver::new(settings)?,
+            DnsCache::new(settings.cache_size),
+        ))
+    }
+
+    pub fn with_resolver(resolver: Resolver, cache: DnsCache) -> Self {
+        Self {
+            resolver,
+            cache,
+            down_until: None,
+        }
+    }
+
+    /// The answer to the message if it is a query, a server failure if the resolver failed.
+    /// `udp` tells whether the answer has to fit the UDP size of the query.
+    ///
+    /// Once the resolver failed, queries that aren't cached fail without trying it again for
+    /// `RESOLVER_BACKOFF`.
+    pub fn answer(&mut self, data: &[u8], udp: bool, now: Instant) -> Option<Vec<u8>> {
+        let query = parse_query(data)?;
+        STATS.dns_queries.increment();
+
+        if let Some(response) = self.cache.get(&query.question, now) {
+            STATS.dns_cached.increment();
+            return Some(answer_to(&query, &response, udp));
+        }
+
+        if self.down_until.is_some_and(|until| now < until) {
+            return Some(servfail(&query));
+        }
+        self.down_until = None;
+
+        match self.resolver.exchange(query.data) {
+            Ok(response) if is_complete_response(&response) => {
+                debug!(
+                    "Resolved {} (type {})",
+                    query.question.name, query.question.kind
+                );
+                self.cache.insert(query.question.clone(), &response, now);
+                Some(answer_to(&query, &response, udp))
+            }
+            Ok(_) => {
+                warn!(
+                    "The resolver sent an invalid answer for {}",
+                    query.question.name
+                );
+                Some(servfail(&query))
+            }
+            Err(e) => {
+                warn!("Failed to resolve {}: {e:?}", query.question.name);
+                self.down_until = Some(now + RESOLVER_BACKOFF);
+                Some(servfail(&query))
+            }
+        }
+    }
+}
+
+/// Whether the message looks like a complete response.
+fn is_complete_response(data: &[u8]) -> bool {
+    read_u16(data, 2).is_some_and(|flags| flags & FLAG_RESPONSE != 0) && records(data).is_some()
+}
+
+#[inline]
+fn read_u16(data: &[u8], offset: usize) -> Option<u16> {
+    Some(u16::from_be_bytes(
+        data.get(offset..offset + 2)?.try_into().ok()?,
+    ))
+}
+
+#[inline]
+fn read_u32(data: &[u8], offset: usize) -> Option<u32> {
+    Some(u32::from_be_bytes(
+        data.get(offset..offset + 4)?.try_into().ok()?,
+    ))
+}
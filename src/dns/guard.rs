@@ -0,0 +1,266 @@
+//! Dropping forged answers to plain DNS queries.
+//!
+//! Censors watching DNS race a forged answer ahead of the real one, and the client takes
+//! whichever arrives first. The guard remembers the queries waiting for an answer and drops
+//! the answers that give themselves away, so the genuine one that follows is taken instead:
+//!
+//! - an answer pointing to a bogon, e.g. a blockpage address;
+//! - an IP TTL differing from the usual one of the server, since the censor is fewer hops
+//!   away;
+//! - an IP identification that is zero when the server's never is, or the other way round;
+//! - an answer faster than the server ever answered, since the censor is closer.
+//!
+//! The usual TTL, identification and round trip of a server are learned from the last answer
+//! to a query once the query times out, since a forged answer arrives first and the genuine
+//! one after it. Until a server is learned, its answers are taken without judging them. If
+//! every answer to a query was dropped for looking unusual, the server may have changed, and
+//! it is learned again.
+
+use std::{
+    collections::HashMap,
+    fmt,
+    net::{IpAddr, SocketAddr},
+    time::{Duration, Instant},
+};
+
+use crate::{
+    cidr::CidrSet,
+    dns::{Question, packet::parse_udp_dns, parse_query, parse_response},
+    settings::DnsGuard,
+    stats::STATS,
+};
+
+/// Networks answers never genuinely point to: "this" network, loopback and their IPv6
+/// counterparts.
+pub const DEFAULT_BOGONS: &[&str] = &["0.0.0.0/8", "127.0.0.0/8", "::/128", "::1/128"];
+/// Queries waiting for an answer at most, more aren't guarded.
+const MAX_QUERIES: usize = 4096;
+
+/// Why an answer was taken for a forged one.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Spoof {
+    /// It points to a bogon.
+    Bogon,
+    /// Its IP TTL differs from the usual one of the server.
+    IpTtl,
+    /// Its IP identification is zero when the server's isn't, or the other way round.
+    IpId,
+    /// It arrived faster than the server can answer.
+    Early,
+}
+
+impl fmt::Display for Spoof {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Bogon => "bogon address",
+            Self::IpTtl => "unusual IP TTL",
+            Self::IpId => "unusual IP identification",
+            Self::Early => "too early",
+        })
+    }
+}
+
+/// What to do with a DNS packet.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Verdict {
+    /// Send it, it is a query or an answer to no query the guard knows of.
+    Pass,
+    /// Send it, it is the genuine answer to a query, or an answer from a server that wasn't
+    /// learned yet.
+    Accept,
+    /// Drop the forged answer.
+    Drop(Spoof),
+}
+
+/// A query to a server by a client port.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+struct QueryKey {
+    client: SocketAddr,
+    server: SocketAddr,
+    id: u16,
+}
+
+/// A query waiting for its genuine answer.
+struct Pending {
+    question: Question,
+    sent: Instant,
+    /// An answer was dropped for looking unlike the server's.
+    unusual: bool,
+    /// The last answer from a server that wasn't learned yet, learned when the query times
+    /// out.
+    last: Option<ServerProfile>,
+}
+
+/// What the answers taken from a server look like.
+#[derive(Clone, Copy, Debug)]
+struct ServerProfile {
+    ttl: u8,
+    /// Whether its IPv4 identification is zero, `None` over IPv6.
+    zero_id: Option<bool>,
+    /// Fastest round trip of its answers.
+    rtt: Duration,
+}
+
+impl ServerProfile {
+    /// Take the TTL and identification of a genuine answer, and its round trip if faster.
+    fn learn(&mut self, answer: &ServerProfile) {
+        self.ttl = answer.ttl;
+        self.zero_id = answer.zero_id;
+        self.rtt = self.rtt.min(answer.rtt);
+    }
+}
+
+/// Tracks the queries waiting for an answer and judges their answers.
+pub struct SpoofGuard {
+    settings: DnsGuard,
+    bogons: CidrSet,
+    pending: HashMap<QueryKey, Pending>,
+    servers: HashMap<IpAddr, ServerProfile>,
+}
+
+impl SpoofGuard {
+    pub fn new(settings: &DnsGuard) -> Self {
+        Self {
+            settings: settings.clone(),
+            bogons: CidrSet::new(&settings.bogons),
+            pending: HashMap::new(),
+            servers: HashMap::new(),
+        }
+    }
+
+    /// Apply reloaded settings, keeping the queries and what was learned of the servers.
+    pub fn set_settings(&mut self, settings: &DnsGuard) {
+        self.bogons = CidrSet::new(&settings.bogons);
+        self.settings = settings.clone();
+    }
+
+    /// Judge a captured UDP packet to or from port 53.
+    pub fn inspect(&mut self, packet: &[u8], outbound: bool, now: Instant) -> Verdict {
+        let Some(dns) = parse_udp_dns(packet, outbound) else {
+            return Verdict::Pass;
+        };
+
+        if outbound {
+            if let Some(query) = parse_query(dns.message)
+                && self.pending.len() < MAX_QUERIES
+            {
+                let key = QueryKey {
+                    client: dns.client,
+                    server: dns.server,
+                    id: query.id,
+                };
+                self.pending.insert(
+                    key,
+                    Pending {
+                        question: query.question,
+                        sent: now,
+                        unusual: false,
+                        last: None,
+                    },
+                );
+            }
+            return Verdict::Pass;
+        }
+
+        let Some(response) = parse_response(dns.message) else {
+            return Verdict::Pass;
+        };
+        let key = QueryKey {
+            client: dns.client,
+            server: dns.server,
+            id: response.id,
+        };
+        let Some(pending) = self
+            .pending
+            .get_mut(&key)
+            .filter(|pending| pending.question == response.question)
+        else {
+            return Verdict::Pass;
+        };
+
+        let rtt = now.saturating_duration_since(pending.sent);
+        let zero_id = dns.ip_id.map(|id| id == 0);
+        let server = dns.server.ip();
+
+        let spoof = if response
+            .addresses
+            .iter()
+            .any(|&address| self.bogons.contains(address))
+        {
+            Some(Spoof::Bogon)
+        } else if let Some(profile) = self.servers.get(&server) {
+            let percent = u128::from(self.settings.min_rtt_percent);
+
+            if profile.ttl.abs_diff(dns.ttl) > self.settings.ttl_tolerance {
+                Some(Spoof::IpTtl)
+            } else if profile.zero_id.is_some() && zero_id.is_some() && profile.zero_id != zero_id {
+                Some(Spoof::IpId)
+            } else if rtt.as_micros() * 100 < profile.rtt.as_micros() * percent {
+                Some(Spoof::Early)
+            } else {
+                None
+            }
+        } else {
+            None
+        };
+
+        if let Some(spoof) = spoof {
+            pending.unusual |= spoof != Spoof::Bogon;
+            match spoof {
+                Spoof::Bogon => STATS.dns_spoofed_bogon.increment(),
+                Spoof::IpTtl => STATS.dns_spoofed_ttl.increment(),
+                Spoof::IpId => STATS.dns_spoofed_id.increment(),
+                Spoof::Early => STATS.dns_spoofed_early.increment(),
+            }
+            return Verdict::Drop(spoof);
+        }
+
+        STATS.dns_genuine.increment();
+        let answer = ServerProfile {
+            ttl: dns.ttl,
+            zero_id,
+            rtt,
+        };
+
+        match self.servers.get_mut(&server) {
+            Some(profile) => {
+                self.pending.remove(&key);
+                profile.learn(&answer);
+            }
+            // the first answer may be a forged one, the query waits for the ones after it
+            None => pending.last = Some(answer),
+        }
+
+        Verdict::Accept
+    }
+
+    /// Forget the queries that waited too long, learning their servers from their last
+    /// answer, or forgetting what was learned of them if all of their answers were dropped for
+    /// looking unusual.
+    pub fn expire(&mut self, now: Instant) {
+        let timeout = self.settings.timeout();
+
+        self.pending.retain(|key, pending| {
+            if now.saturating_duration_since(pending.sent) < timeout {
+                return true;
+            }
+
+            let server = key.server.ip();
+            if pending.unusual {
+                self.servers.remove(&server);
+            } else if let Some(answer) = &pending.last {
+                self.servers
+                    .entry(server)
+                    .and_modify(|profile| profile.learn(answer))
+                    .or_insert(*answer);
+            }
+            false
+        });
+    }
+
+    /// Queries waiting for an answer.
+    #[inline]
+    pub fn pending(&self) -> usize {
+        self.pending.len()
+    }
+}
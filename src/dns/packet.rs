@@ -0,0 +1,270 @@
+//! DNS messages in raw IPv4 and IPv6 packets, and answers forged from the server.
+//!
+//! Over TCP, a segment is only taken for a query if it holds exactly one whole length-prefixed
+//! message, which is how clients send them. Segments holding several queries pass through,
+//! since a single answer acknowledging them would leave the others unanswered.
+
+use std::net::{IpAddr, SocketAddr};
+
+/// Port of plain DNS.
+pub const DNS_PORT: u16 = 53;
+/// TTL or hop limit of the answers.
+const ANSWER_TTL: u8 = 64;
+
+const PROTOCOL_TCP: u8 = 6;
+const PROTOCOL_UDP: u8 = 17;
+const TCP_PSH_ACK: u8 = 0x18;
+
+/// The headers of a packet carrying a query, as far as answering it is concerned.
+pub struct DnsPacket<'a> {
+    pub source: IpAddr,
+    pub destination: IpAddr,
+    pub source_port: u16,
+    /// Over UDP, otherwise TCP.
+    pub udp: bool,
+    /// The DNS message, without the TCP length prefix.
+    pub message: &'a [u8],
+    /// Sequence and acknowledgment numbers, and payload length of a TCP segment.
+    seq: u32,
+    ack: u32,
+    payload_len: usize,
+}
+
+/// The IP header fields of a packet and its transport header and payload.
+struct IpPacket<'a> {
+    source: IpAddr,
+    destination: IpAddr,
+    protocol: u8,
+    /// TTL or hop limit.
+    ttl: u8,
+    /// Identification of an IPv4 packet.
+    id: Option<u16>,
+    transport: &'a [u8],
+}
+
+fn parse_ip(packet: &[u8]) -> Option<IpPacket<'_>> {
+    match packet.first()? >> 4 {
+        4 => {
+            let header_len = usize::from(packet[0] & 0x0f) * 4;
+            let total_len = usize::from(u16::from_be_bytes(packet.get(2..4)?.try_into().ok()?));
+            // fragments don't have a whole datagram
+            let fragment = u16::from_be_bytes(packet.get(6..8)?.try_into().ok()?);
+            if fragment & 0x3fff != 0 {
+                return None;
+            }
+
+            let source: [u8; 4] = packet.get(12..16)?.try_into().ok()?;
+            let destination: [u8; 4] = packet.get(16..20)?.try_into().ok()?;
+
+            Some(IpPacket {
+                source: source.into(),
+                destination: destination.into(),
+                protocol: packet[9],
+                ttl: packet[8],
+                id: Some(u16::from_be_bytes([packet[4], packet[5]])),
+                transport: packet.get(header_len..total_len.min(packet.len()))?,
+            })
+        }
+        6 => {
+            let payload_len = usize::from(u16::from_be_bytes(packet.get(4..6)?.try_into().ok()?));
+            let source: [u8; 16] = packet.get(8..24)?.try_into().ok()?;
+            let destination: [u8; 16] = packet.get(24..40)?.try_into().ok()?;
+
+            Some(IpPacket {
+                source: source.into(),
+                destination: destination.into(),
+                protocol: packet[6],
+                ttl: packet[7],
+                id: None,
+                transport: packet.get(40..(40 + payload_len).min(packet.len()))?,
+            })
+        }
+        _ => None,
+    }
+}
+
+/// A DNS message over UDP between a client and port 53, in either direction.
+pub struct UdpDns<'a> {
+    pub client: SocketAddr,
+    pub server: SocketAddr,
+    /// TTL or hop limit of the packet.
+    pub ttl: u8,
+    /// Identification of an IPv4 packet.
+    pub ip_id: Option<u16>,
+    pub message: &'a [u8],
+}
+
+/// The DNS message in a UDP packet to port 53 if `outbound`, from port 53 otherwise.
+pub fn parse_udp_dns(packet: &[u8], outbound: bool) -> Option<UdpDns<'_>> {
+    let ip = parse_ip(packet)?;
+    if ip.protocol != PROTOCOL_UDP {
+        return None;
+    }
+
+    let source_port = u16::from_be_bytes(ip.transport.get(0..2)?.try_into().ok()?);
+    let destination_port = u16::from_be_bytes(ip.transport.get(2..4)?.try_into().ok()?);
+    let source = SocketAddr::new(ip.source, source_port);
+    let destination = SocketAddr::new(ip.destination, destination_port);
+
+    let (client, server) = if outbound {
+        (source, destination)
+    } else {
+        (destination, source)
+    };
+    if server.port() != DNS_PORT {
+        return None;
+    }
+
+    Some(UdpDns {
+        client,
+        server,
+        ttl: ip.ttl,
+        ip_id: ip.id,
+        message: ip.transport.get(8..)?,
+    })
+}
+
+/// The query in an IP packet to port 53, `None` if it isn't one.
+pub fn parse_dns_packet(packet: &[u8]) -> Option<DnsPacket<'_>> {
+    let IpPacket {
+        source,
+        destination,
+        protocol,
+        transport,
+        ..
+    } = parse_ip(packet)?;
+
+    let source_port = u16::from_be_bytes(transport.get(0..2)?.try_into().ok()?);
+    let destination_port = u16::from_be_bytes(transport.get(2..4)?.try_into().ok()?);
+    if destination_port != DNS_PORT {
+        return None;
+    }
+
+    let mut packet = DnsPacket {
+        source,
+        destination,
+        source_port,
+        udp: true,
+        message: &[],
+        seq: 0,
+        ack: 0,
+        payload_len: 0,
+    };
+
+    match protocol {
+        PROTOCOL_UDP => packet.message = transport.get(8..)?,
+        PROTOCOL_TCP => {
+            let header_len = usize::from(transport.get(12)? >> 4) * 4;
+            let payload = transport.get(header_len..)?;
+            let len = usize::from(u16::from_be_bytes(payload.get(0..2)?.try_into().ok()?));
+            if payload.len() != 2 + len {
+                return None;
+            }
+
+            packet.udp = false;
+            packet.message = &payload[2..];
+            packet.seq = u32::from_be_bytes(transport[4..8].try_into().ok()?);
+            packet.ack = u32::from_be_bytes(transport[8..12].try_into().ok()?);
+            packet.payload_len = payload.len();
+        }
+        _ => return None,
+    }
+
+    Some(packet)
+}
+
+impl DnsPacket<'_> {
+    /// An IP packet carrying the answer from the server the query was sent to.
+    pub fn reply(&self, answer: &[u8]) -> Vec<u8> {
+        let mut transport = Vec::with_capacity(answer.len() + 22);
+        transport.extend_from_slice(&DNS_PORT.to_be_bytes());
+        transport.extend_from_slice(&self.source_port.to_be_bytes());
+
+        let protocol = if self.udp {
+            let len = (8 + answer.len()) as u16;
+            transport.extend_from_slice(&len.to_be_bytes());
+            transport.extend_from_slice(&[0, 0]);
+            transport.extend_from_slice(answer);
+            PROTOCOL_UDP
+        } else {
+            let ack = self.seq.wrapping_add(self.payload_len as u32);
+            transport.extend_from_slice(&self.ack.to_be_bytes());
+            transport.extend_from_slice(&ack.to_be_bytes());
+            transport.extend_from_slice(&[5 << 4, TCP_PSH_ACK, 0xff, 0xff, 0, 0, 0, 0]);
+            transport.extend_from_slice(&(answer.len() as u16).to_be_bytes());
+            transport.extend_from_slice(answer);
+            PROTOCOL_TCP
+        };
+
+        // the answer comes from where the query went
+        let (source, destination) = (self.destination, self.source);
+        let checksum_offset = if self.udp { 6 } else { 16 };
+        let mut checksum = transport_checksum(source, destination, protocol, &transport);
+        if self.udp && checksum == 0 {
+            checksum = 0xffff;
+        }
+        transport[checksum_offset..checksum_offset + 2].copy_from_slice(&checksum.to_be_bytes());
+
+        let mut packet = match (source, destination) {
+            (IpAddr::V4(source), IpAddr::V4(destination)) => {
+                let total_len = (20 + transport.len()) as u16;
+                let mut header = vec![0x45, 0];
+                header.extend_from_slice(&total_len.to_be_bytes());
+                header.extend_from_slice(&[0, 0, 0x40, 0, ANSWER_TTL, protocol, 0, 0]);
+                header.extend_from_slice(&source.octets());
+                header.extend_from_slice(&destination.octets());
+
+                let checksum = !fold(sum(&header));
+                header[10..12].copy_from_slice(&checksum.to_be_bytes());
+                header
+            }
+            (source, destination) => {
+                let mut header = vec![0x60, 0, 0, 0];
+                header.extend_from_slice(&(transport.len() as u16).to_be_bytes());
+                header.extend_from_slice(&[protocol, ANSWER_TTL]);
+                header.extend_from_slice(&ipv6_octets(source));
+                header.extend_from_slice(&ipv6_octets(destination));
+                header
+            }
+        };
+
+        packet.extend_from_slice(&transport);
+        packet
+    }
+}
+
+fn ipv6_octets(ip: IpAddr) -> [u8; 16] {
+    match ip {
+        IpAddr::V4(ip) => ip.to_ipv6_mapped().octets(),
+        IpAddr::V6(ip) => ip.octets(),
+    }
+}
+
+/// The checksum of a TCP or UDP header and payload, with the pseudo-header of its addresses.
+pub fn transport_checksum(source: IpAddr, destination: IpAddr, protocol: u8, data: &[u8]) -> u16 {
+    let pseudo = match (source, destination) {
+        (IpAddr::V4(source), IpAddr::V4(destination)) => {
+            sum(&source.octets()) + sum(&destination.octets()) + data.len() as u64
+        }
+        (source, destination) => {
+            sum(&ipv6_octets(source)) + sum(&ipv6_octets(destination)) + data.len() as u64
+        }
+    };
+
+    !fold(pseudo + u64::from(protocol) + sum(data))
+}
+
+/// The sum of the big-endian 16-bit words of the data, padded with a zero byte.
+fn sum(data: &[u8]) -> u64 {
+    data.chunks(2)
+        .map(|word| u64::from(u16::from_be_bytes([word[0], *word.get(1).unwrap_or(&0)])))
+        .sum()
+}
+
+/// Fold a sum into a ones' complement 16-bit sum.
+fn fold(mut sum: u64) -> u16 {
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    sum as u16
+}
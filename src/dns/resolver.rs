@@ -0,0 +1,285 @@
+//! The connection to the encrypted resolver queries are sent to.
+//!
+//! DNS-over-HTTPS queries are POSTed as `application/dns-message` over HTTP/1.1, and
+//! DNS-over-TLS queries are prefixed with their length. Either way, the connection is kept
+//! open between queries and opened again once if the resolver closed it.
+
+use std::{
+    io::{Read, Write},
+    net::{IpAddr, SocketAddr, TcpStream},
+    sync::Arc,
+    time::Duration,
+};
+
+use color_eyre::{
+    Result,
+    eyre::{Context, ContextCompat, bail},
+};
+use rustls::{ClientConfig, ClientConnection, RootCertStore, StreamOwned, pki_types::ServerName};
+
+use crate::{http::parse_http_response, settings::Dns};
+
+/// Media type of DNS messages in HTTP bodies (RFC 8484).
+const DNS_MESSAGE: &str = "application/dns-message";
+/// Largest DNS message, and HTTP head accepted around it.
+const MAX_MESSAGE: usize = 65535;
+
+/// How queries are sent to the resolver.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum Transport {
+    /// DNS-over-HTTPS, to the path.
+    Https { path: String },
+    /// DNS over plain HTTP, for resolvers on the machine itself.
+    Http { path: String },
+    /// DNS-over-TLS.
+    Tls,
+}
+
+/// The resolver as configured by its URL.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Upstream {
+    pub transport: Transport,
+    /// Host name or IP address, also the name its certificate is checked against.
+    pub host: String,
+    pub port: u16,
+}
+
+impl Upstream {
+    /// Parse `https://host[:port]/path`, `tls://host[:port]` or `http://host[:port]/path`.
+    pub fn parse(url: &str) -> Result<Self> {
+        let (scheme, rest) = url
+            .split_once("://")
+            .with_context(|| format!("{url:?} isn't a URL"))?;
+
+        let (authority, path) = match rest.find('/') {
+            Some(slash) => rest.split_at(slash),
+            None => (rest, ""),
+        };
+
+        let (transport, default_port) = match scheme.to_ascii_lowercase().as_str() {
+            "https" => (
+                Transport::Https {
+                    path: http_path(path),
+                },
+                443,
+            ),
+            "http" => (
+                Transport::Http {
+                    path: http_path(path),
+                },
+                80,
+            ),
+            "tls" => (Transport::Tls, 853),
+            _ => bail!("Unsupported resolver scheme {scheme:?}, expected https or tls"),
+        };
+
+        // IPv6 addresses are bracketed so their colons aren't taken for the port
+        let (host, port) = match authority.strip_prefix('[') {
+            Some(bracketed) => {
+                let (host, rest) = bracketed
+                    .split_once(']')
+                    .with_context(|| format!("Unclosed bracket in {url:?}"))?;
+                (host, rest.strip_prefix(':'))
+            }
+            None => match authority.split_once(':') {
+                Some((host, port)) => (host, Some(port)),
+                None => (authority, None),
+            },
+        };
+
+        if host.is_empty() {
+            bail!("{url:?} has no host");
+        }
+        let port = match port {
+            Some(port) => port
+                .parse()
+                .with_context(|| format!("Invalid port in {url:?}"))?,
+            None => default_port,
+        };
+
+        Ok(Self {
+            transport,
+            host: host.to_owned(),
+            port,
+        })
+    }
+
+    /// The address to connect to: the configured one, or the host if it is an IP address.
+    pub fn address(&self, configured: Option<IpAddr>) -> Option<SocketAddr> {
+        let ip = configured.or_else(|| self.host.parse().ok())?;
+        Some(SocketAddr::new(ip, self.port))
+    }
+}
+
+/// The path of a DoH URL, `/dns-query` if it has none.
+fn http_path(path: &str) -> String {
+    match path {
+        "" | "/" => "/dns-query".to_owned(),
+        path => path.to_owned(),
+    }
+}
+
+/// A stream a query can be exchanged over.
+trait Connection: Read + Write + Send {}
+
+impl<T: Read + Write + Send> Connection for T {}
+
+/// Sends queries to the resolver over a connection kept open.
+pub struct Resolver {
+    upstream: Upstream,
+    address: SocketAddr,
+    timeout: Duration,
+    tls: Arc<ClientConfig>,
+    connection: Option<Box<dyn Connection>>,
+}
+
+impl Resolver {
+    pub fn new(settings: &Dns) -> Result<Self> {
+        let upstream = Upstream::parse(&settings.resolver)?;
+        let address = upstream
+            .address(settings.address)
+            .with_context(|| format!("The address of {} isn't set", upstream.host))?;
+
+        Ok(Self::with_upstream(upstream, address, settings.timeout()))
+    }
+
+    pub fn with_upstream(upstream: Upstream, address: SocketAddr, timeout: Duration) -> Self {
+        let roots = RootCertStore {
+            roots: webpki_roots::TLS_SERVER_ROOTS.to_vec(),
+        };
+        let mut tls = ClientConfig::builder()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+
+        if let Transport::Https { .. } = upstream.transport {
+            tls.alpn_protocols = vec![b"http/1.1".to_vec()];
+        }
+
+        Self {
+            upstream,
+            address,
+            timeout,
+            tls: Arc::new(tls),
+            connection: None,
+        }
+    }
+
+    /// Send the query and return the response, on a new connection if the open one failed.
+    pub fn exchange(&mut self, query: &[u8]) -> Result<Vec<u8>> {
+        if let Some(mut connection) = self.connection.take()
+            && let Ok(response) = self.request(connection.as_mut(), query)
+        {
+            self.connection = Some(connection);
+            return Ok(response);
+        }
+
+        let mut connection = self.connect()?;
+        let response = self.request(connection.as_mut(), query)?;
+        self.connection = Some(connection);
+
+        Ok(response)
+    }
+
+    fn connect(&self) -> Result<Box<dyn Connection>> {
+        let tcp = TcpStream::connect_timeout(&self.address, self.timeout)
+            .wrap_err_with(|| format!("Failed to connect to the resolver at {}", self.address))?;
+        tcp.set_read_timeout(Some(self.timeout))?;
+        tcp.set_write_timeout(Some(self.timeout))?;
+        tcp.set_nodelay(true)?;
+
+        if let Transport::Http { .. } = self.upstream.transport {
+            return Ok(Box::new(tcp));
+        }
+
+        let name = ServerName::try_from(self.upstream.host.clone())
+            .wrap_err_with(|| format!("Invalid resolver name {}", self.upstream.host))?;
+        let tls = ClientConnection::new(self.tls.clone(), name)?;
+
+        Ok(Box::new(StreamOwned::new(tls, tcp)))
+    }
+
+    fn request(&self, connection: &mut dyn Connection, query: &[u8]) -> Result<Vec<u8>> {
+        match &self.upstream.transport {
+            Transport::Https { path } | Transport::Http { path } => {
+                self.request_http(connection, path, query)
+            }
+            Transport::Tls => request_tls(connection, query),
+        }
+    }
+
+    fn request_http(
+        &self,
+        connection: &mut dyn Connection,
+        path: &str,
+        query: &[u8],
+    ) -> Result<Vec<u8>> {
+        let host = &self.upstream.host;
+        let head = format!(
+            "POST {path} HTTP/1.1\r\nHost: {host}\r\nContent-Type: {DNS_MESSAGE}\r\n\
+             Accept: {DNS_MESSAGE}\r\nContent-Length: {}\r\n\r\n",
+            query.len()
+        );
+
+        let mut request = head.into_bytes();
+        request.extend_from_slice(query);
+        connection.write_all(&request)?;
+        connection.flush()?;
+
+        let mut data = Vec::new();
+        let mut buffer = [0; 4096];
+
+        let (status, body, length) = loop {
+            let read = connection.read(&mut buffer)?;
+            if read == 0 {
+                bail!("The resolver closed the connection");
+            }
+            data.extend_from_slice(&buffer[..read]);
+
+            if let Some(response) = parse_http_response(&data)
+                && let Some(end) = response.end
+            {
+                let length = response
+                    .content_length
+                    .context("The resolver answered without a Content-Length")?;
+                break (response.status, end, length);
+            }
+            if data.len() > MAX_MESSAGE {
+                bail!("The resolver sent an invalid response");
+            }
+        };
+
+        if length > MAX_MESSAGE {
+            bail!("The resolver sent a {length} bytes answer");
+        }
+        while data.len() < body + length {
+            let read = connection.read(&mut buffer)?;
+            if read == 0 {
+                bail!("The resolver closed the connection");
+            }
+            data.extend_from_slice(&buffer[..read]);
+        }
+
+        if status != 200 {
+            bail!("The resolver answered with status {status}");
+        }
+
+        Ok(data[body..body + length].to_vec())
+    }
+}
+
+fn request_tls(connection: &mut dyn Connection, query: &[u8]) -> Result<Vec<u8>> {
+    let length = u16::try_from(query.len()).context("The query is too long")?;
+
+    let mut request = length.to_be_bytes().to_vec();
+    request.extend_from_slice(query);
+    connection.write_all(&request)?;
+    connection.flush()?;
+
+    let mut length = [0; 2];
+    connection.read_exact(&mut length)?;
+
+    let mut response = vec![0; usize::from(u16::from_be_bytes(length))];
+    connection.read_exact(&mut response)?;
+
+    Ok(response)
+}
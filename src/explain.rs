@@ -0,0 +1,349 @@
+//! Dry run of the settings: what the interceptor would do with them, without capturing
+//! anything, and every problem found in them.
+
+use std::{
+    fmt::{self, Write},
+    fs,
+    io::ErrorKind,
+    path::Path,
+};
+
+use crate::{
+    adaptive::StrategyCache,
+    cidr::{Cidr, CidrFilter, CidrSet},
+    classify::Classifier,
+    filter::{capture_filter, desync_filter},
+    hostlist::{Hostlist, InvalidEntry},
+    profile::Protocol,
+    schedule::{Schedule, SystemClock},
+    settings::{Settings, SettingsStore},
+    strategy::{RetransmitAction, Step},
+};
+
+/// Networks listed before the rest of a set is summarized.
+const MAX_LISTED_NETWORKS: usize = 8;
+
+/// What the interceptor would do with the stored settings.
+pub struct Explanation {
+    /// Description of the resolved settings, empty if they couldn't be loaded.
+    report: String,
+    /// Every problem found, prefixed with where it is.
+    pub errors: Vec<String>,
+}
+
+impl Explanation {
+    /// Load the settings and the lists they refer to, collecting every problem instead of
+    /// stopping at the first one.
+    pub fn new(store: &dyn SettingsStore) -> Self {
+        let mut explanation = Self {
+            report: String::new(),
+            errors: Vec::new(),
+        };
+
+        match store.load() {
+            Ok(settings) => explanation.describe(store, &settings),
+            Err(e) => explanation.errors.push(format!("{e:#}")),
+        }
+
+        explanation
+    }
+
+    fn describe(&mut self, store: &dyn SettingsStore, settings: &Settings) {
+        for problem in settings.problems() {
+            let location = store.locate(&problem.key);
+            self.errors.push(format!("{location}: {problem}"));
+        }
+
+        let lists = &settings.lists;
+        let addresses = CidrFilter {
+            include: self.networks(&settings.addresses.include, &store.resolve(&lists.cidr)),
+            exclude: self.networks(
+                &settings.addresses.exclude,
+                &store.resolve(&lists.cidr_exclude),
+            ),
+        };
+
+        let desync = desync_filter(&settings.ports, &addresses, settings.observes_outcomes());
+        let filter = capture_filter(&desync, settings.dns.enabled, settings.dns_guard.enabled);
+
+        let out = &mut self.report;
+        let _ = writeln!(out, "Filter: {filter}");
+
+        let status = Schedule::new(settings).status(&SystemClock);
+        let _ = writeln!(out, "Active profile: {status}");
+
+        let retransmission = &settings.retransmission;
+        let _ = match retransmission.action {
+            RetransmitAction::Repeat => writeln!(out, "Retransmissions: repeat the chain"),
+            RetransmitAction::Escalate => {
+                let chain = retransmission.escalation.iter().map(Step::to_string);
+                let chain = chain.collect::<Vec<_>>();
+                writeln!(out, "Retransmissions: escalate to {}", chain.join(", "))
+            }
+            RetransmitAction::Pass => writeln!(out, "Retransmissions: pass"),
+        };
+
+        let injection = &settings.injection;
+        let _ = writeln!(
+            out,
+            "Injection limits: {}, {} per destination",
+            describe_limit(injection.rate, injection.burst),
+            describe_limit(injection.destination_rate, injection.destination_burst)
+        );
+
+        let dns = &settings.dns;
+        let _ = if dns.enabled {
+            writeln!(out, "DNS: resolved through {}", dns.resolver)
+        } else {
+            writeln!(out, "DNS: passed through")
+        };
+        let guard = &settings.dns_guard;
+        if guard.enabled {
+            let _ = writeln!(
+                out,
+                "DNS guard: dropping answers to {} bogons, TTL off by over {} hops, or faster than \
+                 {}% of the fastest",
+                guard.bogons.len(),
+                guard.ttl_tolerance,
+                guard.min_rtt_percent
+            );
+        }
+
+        let _ = writeln!(out, "Chains:");
+        let profile = settings.profile(&status.profile).unwrap_or_default();
+        let mut ports = settings
+            .ports
+            .classifiers()
+            .flat_map(|(port, classifier)| {
+                let protocols = match classifier {
+                    Classifier::Http => &[Protocol::Http][..],
+                    Classifier::Tls => &[Protocol::Tls],
+                    Classifier::Auto => &[Protocol::Http, Protocol::Tls],
+                };
+                protocols
+                    .iter()
+                    .map(move |&protocol| (Some(port), classifier, protocol))
+            })
+            .collect::<Vec<_>>();
+        if settings.ports.any {
+            ports.push((None, Classifier::Auto, Protocol::Http));
+            ports.push((None, Classifier::Auto, Protocol::Tls));
+        }
+
+        for (port, classifier, protocol) in ports {
+            let name = match protocol {
+                Protocol::Http => "http",
+                Protocol::Tls => "tls",
+            };
+            let _ = match (port, classifier) {
+                (None, _) => writeln!(out, "  other ports (detected as {name}):"),
+                (Some(port), Classifier::Auto) => {
+                    writeln!(out, "  {port} (detected as {name}):")
+                }
+                (Some(port), _) => writeln!(out, "  {port} ({name}):"),
+            };
+
+            let rules = profile.rules.iter().filter(|rule| {
+                (rule.ports.is_empty() || port.is_some_and(|port| rule.ports.contains(&port)))
+                    && rule.protocol.is_none_or(|p| p == protocol)
+            });
+            let mut matched = false;
+
+            for rule in rules {
+                let mut conditions = Vec::new();
+                if let Some(hostlist) = &rule.hostlist {
+                    conditions.push(format!("hosts in {}", hostlist.display()));
+                }
+                if !rule.cidr.is_empty() {
+                    conditions.push(format!("{} networks", rule.cidr.len()));
+                }
+                if conditions.is_empty() {
+                    conditions.push("any flow".to_owned());
+                }
+
+                let chain = rule.chain.iter().map(|step| step.to_string());
+                let chain = chain.collect::<Vec<_>>();
+
+                let _ = writeln!(
+                    out,
+                    "    {}: {}",
+                    conditions.join(", "),
+                    if chain.is_empty() {
+                        "pass".to_owned()
+                    } else {
+                        chain.join(", ")
+                    }
+                );
+                matched = true;
+            }
+
+            if !matched {
+                let _ = writeln!(out, "    never desynced");
+            }
+        }
+
+        let _ = writeln!(out, "Hostlists:");
+        let mut hostlists = vec![
+            (lists.hostlist.clone(), false),
+            (lists.exclude.clone(), false),
+        ];
+        if settings.blocking.auto_hostlist {
+            hostlists.push((lists.auto_hostlist.clone(), false));
+        }
+        for profile in settings.profiles.values() {
+            for rule in &profile.rules {
+                if let Some(path) = &rule.hostlist
+                    && !hostlists.iter().any(|(listed, _)| listed == path)
+                {
+                    hostlists.push((path.clone(), true));
+                }
+            }
+        }
+        for (path, required) in hostlists {
+            self.hostlist(&store.resolve(&path), required);
+        }
+
+        if !settings.adaptive.candidates.is_empty() {
+            let path = store.resolve(&lists.state);
+            let candidates = settings.candidate_chains();
+            let count = candidates.len();
+            let threshold = settings.blocking.fail_threshold;
+
+            match StrategyCache::load(path.clone(), candidates, threshold, settings.adaptive.ttl())
+            {
+                Ok(cache) => {
+                    let _ = writeln!(
+                        self.report,
+                        "Adaptive: {count} candidates, {} domains decided in {}",
+                        cache.decisions().count(),
+                        path.display()
+                    );
+                }
+                Err(e) => self.errors.push(format!("{e:#}")),
+            }
+        }
+
+        let out = &mut self.report;
+        let _ = writeln!(out, "Networks:");
+        let _ = writeln!(
+            out,
+            "  include: {}",
+            describe_set(&addresses.include, "any")
+        );
+        let _ = writeln!(
+            out,
+            "  exclude: {}",
+            describe_set(&addresses.exclude, "none")
+        );
+    }
+
+    /// Count the entries of a hostlist, collecting the invalid ones. Missing lists are only
+    /// an error if `required`.
+    fn hostlist(&mut self, path: &Path, required: bool) {
+        let Some(text) = self.read(path, required) else {
+            let _ = writeln!(self.report, "  {}: not found", path.display());
+            return;
+        };
+
+        let (hostlist, invalid) = Hostlist::parse(&text);
+        self.invalid_entries(path, "hostlist entry", &invalid);
+
+        let _ = writeln!(
+            self.report,
+            "  {}: {} entries",
+            path.display(),
+            hostlist.len()
+        );
+    }
+
+    /// The configured networks and the ones of the list file, collecting the invalid ones.
+    fn networks(&mut self, configured: &[Cidr], file: &Path) -> CidrSet {
+        let mut set = CidrSet::new(configured);
+
+        if let Some(text) = self.read(file, false) {
+            let (listed, invalid) = CidrSet::parse(&text);
+            self.invalid_entries(file, "network", &invalid);
+
+            for cidr in &listed.networks() {
+                set.insert(cidr);
+            }
+        }
+
+        set
+    }
+
+    /// Read a list file, `None` if it doesn't exist or can't be read.
+    fn read(&mut self, path: &Path, required: bool) -> Option<String> {
+        match fs::read_to_string(path) {
+            Ok(text) => Some(text),
+            Err(e) if e.kind() == ErrorKind::NotFound && !required => None,
+            Err(e) => {
+                self.errors.push(format!("{}: {e}", path.display()));
+                None
+            }
+        }
+    }
+
+    fn invalid_entries(&mut self, path: &Path, kind: &str, invalid: &[InvalidEntry]) {
+        for entry in invalid {
+            self.errors.push(format!(
+                "{}:{}: invalid {kind} {:?}",
+                path.display(),
+                entry.line,
+                entry.entry
+            ));
+        }
+    }
+
+    #[inline]
+    pub fn is_valid(&self) -> bool {
+        self.errors.is_empty()
+    }
+}
+
+impl fmt::Display for Explanation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.report)?;
+
+        if self.errors.is_empty() {
+            return writeln!(f, "No problems found");
+        }
+
+        writeln!(f, "Problems:")?;
+        for error in &self.errors {
+            // parse errors span several lines
+            writeln!(f, "  {}", error.trim_end().replace('\n', "\n    "))?;
+        }
+
+        Ok(())
+    }
+}
+
+/// The networks of the set, `empty` if there are none.
+fn describe_set(set: &CidrSet, empty: &str) -> String {
+    let networks = set.networks();
+
+    if networks.is_empty() {
+        return empty.to_owned();
+    }
+
+    let mut listed = networks
+        .iter()
+        .take(MAX_LISTED_NETWORKS)
+        .map(Cidr::to_string)
+        .collect::<Vec<_>>();
+
+    if networks.len() > MAX_LISTED_NETWORKS {
+        listed.push(format!("and {} more", networks.len() - MAX_LISTED_NETWORKS));
+    }
+
+    format!("{} networks ({})", networks.len(), listed.join(", "))
+}
+
+/// A rate limit of the fakes, for the explanation.
+fn describe_limit(rate: u32, burst: u32) -> String {
+    match rate {
+        0 => "unlimited".to_owned(),
+        rate => format!("{rate}/s (burst {burst})"),
+    }
+}
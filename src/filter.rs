@@ -0,0 +1,436 @@
+//! Backend-independent description of the captured packets.
+//!
+//! A [`Filter`] is built once from the settings and rendered into the filter language of the
+//! capture backend: a WinDivert filter string on Windows, or nftables rules queueing the
+//! packets to user space elsewhere.
+
+use std::{fmt::Write, ops::Range};
+
+use crate::{
+    backend::INJECTED_MARK,
+    cidr::{Cidr, CidrFilter, CidrSet},
+    dns::packet::DNS_PORT,
+    settings::Ports,
+};
+
+/// Largest packet handled, in bytes.
+pub const MAX_PACKET_SIZE: usize = 9016;
+/// Largest network set rendered into a WinDivert filter, larger ones have to be matched in
+/// user mode.
+pub const MAX_WINDIVERT_NETWORKS: usize = 32;
+
+/// Direction of the captured packets, seen from this machine.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Direction {
+    Outbound,
+    Inbound,
+    Both,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Protocol {
+    Tcp,
+    Udp,
+}
+
+/// Packets to capture. Ports and networks are those of the remote end: the destination of
+/// outbound packets and the source of inbound ones.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Filter {
+    pub direction: Direction,
+    pub protocol: Protocol,
+    /// Remote ports, any port if empty.
+    pub ports: Vec<u16>,
+    /// Bounds of the payload length, in bytes.
+    pub payload_length: Option<Range<usize>>,
+    /// Remote networks to capture, any network if empty.
+    pub include: Vec<Cidr>,
+    /// Remote networks never to capture.
+    pub exclude: Vec<Cidr>,
+    /// Capture loopback packets as well.
+    pub loopback: bool,
+    /// Skip the packets injected by Packetmock itself.
+    pub skip_injected: bool,
+    /// Also capture the SYN, FIN and RST segments of both directions, whatever their payload
+    /// length, to track TCP connections.
+    pub track_connections: bool,
+}
+
+/// Builds a [`Filter`] step by step.
+pub struct FilterBuilder {
+    filter: Filter,
+}
+
+impl FilterBuilder {
+    /// Start a filter capturing every non-loopback packet of the protocol in the direction,
+    /// except for the injected ones.
+    pub fn new(direction: Direction, protocol: Protocol) -> Self {
+        Self {
+            filter: Filter {
+                direction,
+                protocol,
+                ports: Vec::new(),
+                payload_length: None,
+                include: Vec::new(),
+                exclude: Vec::new(),
+                loopback: false,
+                skip_injected: true,
+                track_connections: false,
+            },
+        }
+    }
+
+    /// Only capture packets from or to the given remote ports.
+    pub fn ports(mut self, ports: impl IntoIterator<Item = u16>) -> Self {
+        for port in ports {
+            if !self.filter.ports.contains(&port) {
+                self.filter.ports.push(port);
+            }
+        }
+        self
+    }
+
+    /// Only capture packets whose payload length is inside the range.
+    pub fn payload_length(mut self, range: Range<usize>) -> Self {
+        self.filter.payload_length = Some(range);
+        self
+    }
+
+    /// Only capture packets from or to the networks of the set.
+    pub fn include(mut self, set: &CidrSet) -> Self {
+        self.filter.include.extend(set.networks());
+        self
+    }
+
+    /// Never capture packets from or to the networks of the set.
+    pub fn exclude(mut self, set: &CidrSet) -> Self {
+        self.filter.exclude.extend(set.networks());
+        self
+    }
+
+    pub fn loopback(mut self, loopback: bool) -> Self {
+        self.filter.loopback = loopback;
+        self
+    }
+
+    pub fn skip_injected(mut self, skip: bool) -> Self {
+        self.filter.skip_injected = skip;
+        self
+    }
+
+    pub fn track_connections(mut self, track: bool) -> Self {
+        self.filter.track_connections = track;
+        self
+    }
+
+    #[inline]
+    pub fn build(self) -> Filter {
+        self.filter
+    }
+}
+
+/// The filter capturing outbound payloads to the configured ports, or to any port, and
+/// networks. With `responses`, the inbound payloads of the same flows are captured too.
+pub fn desync_filter(ports: &Ports, addresses: &CidrFilter, responses: bool) -> Filter {
+    let listed = ports.classifiers().map(|(port, _)| port);
+    let direction = if responses {
+        Direction::Both
+    } else {
+        Direction::Outbound
+    };
+
+    FilterBuilder::new(direction, Protocol::Tcp)
+        .ports(listed.filter(|_| !ports.any))
+        .track_connections(true)
+        .payload_length(1..MAX_PACKET_SIZE)
+        .include(&addresses.include)
+        .exclude(&addresses.exclude)
+        .build()
+}
+
+/// The filters capturing outbound DNS queries, over UDP and over TCP. Only TCP segments
+/// with a payload are captured, the handshake goes to the server.
+pub fn dns_filters() -> [Filter; 2] {
+    [Protocol::Udp, Protocol::Tcp].map(|protocol| {
+        FilterBuilder::new(Direction::Outbound, protocol)
+            .ports([DNS_PORT])
+            .payload_length(1..MAX_PACKET_SIZE)
+            .build()
+    })
+}
+
+/// The filter capturing DNS queries over UDP and their answers, for the guard against forged
+/// ones.
+pub fn dns_guard_filter() -> Filter {
+    FilterBuilder::new(Direction::Both, Protocol::Udp)
+        .ports([DNS_PORT])
+        .payload_length(1..MAX_PACKET_SIZE)
+        .build()
+}
+
+/// The WinDivert filter capturing the payloads of the desync filter, DNS queries if they are
+/// resolved, and DNS answers as well if they are guarded.
+pub fn capture_filter(desync: &Filter, dns: bool, dns_guard: bool) -> String {
+    let mut filters = vec![desync.to_windivert()];
+
+    if dns {
+        filters.extend(dns_filters().map(|filter| filter.to_windivert()));
+    }
+    if dns_guard {
+        filters.push(dns_guard_filter().to_windivert());
+    }
+
+    if filters.len() == 1 {
+        return filters.remove(0);
+    }
+    let filters = filters
+        .iter()
+        .map(|filter| format!("({filter})"))
+        .collect::<Vec<_>>();
+    filters.join(" or ")
+}
+
+/// Options of the nftables rules that don't depend on the filter.
+pub struct NftablesOptions {
+    /// Name of the `inet` table holding the rules.
+    pub table: String,
+    /// NFQUEUE number the captured packets are sent to.
+    pub queue: u16,
+    /// Firewall mark of the injected packets.
+    pub mark: u32,
+}
+
+impl Default for NftablesOptions {
+    fn default() -> Self {
+        Self {
+            table: "packetmock".to_owned(),
+            queue: 0,
+            mark: INJECTED_MARK,
+        }
+    }
+}
+
+impl Filter {
+    /// Render the filter in the WinDivert filter language.
+    ///
+    /// Network sets larger than [`MAX_WINDIVERT_NETWORKS`] are left out and have to be matched
+    /// in user mode.
+    pub fn to_windivert(&self) -> String {
+        let packets = match self.direction {
+            Direction::Both => format!(
+                "(outbound and {}) or (inbound and {})",
+                self.windivert_terms(Direction::Outbound, false),
+                self.windivert_terms(Direction::Inbound, false)
+            ),
+            Direction::Outbound => format!(
+                "outbound and {}",
+                self.windivert_terms(Direction::Outbound, false)
+            ),
+            Direction::Inbound => format!(
+                "inbound and {}",
+                self.windivert_terms(Direction::Inbound, false)
+            ),
+        };
+
+        if !self.track_connections || self.protocol != Protocol::Tcp {
+            return packets;
+        }
+
+        format!(
+            "({packets}) or (outbound and {}) or (inbound and {})",
+            self.windivert_terms(Direction::Outbound, true),
+            self.windivert_terms(Direction::Inbound, true)
+        )
+    }
+
+    /// Terms of the WinDivert filter for a single direction. `control` replaces the payload
+    /// bounds with the SYN, FIN and RST flags.
+    fn windivert_terms(&self, direction: Direction, control: bool) -> String {
+        let protocol = match self.protocol {
+            Protocol::Tcp => "tcp",
+            Protocol::Udp => "udp",
+        };
+        let (port, v4, v6) = match direction {
+            Direction::Inbound => ("SrcPort", "ip.SrcAddr", "ipv6.SrcAddr"),
+            _ => ("DstPort", "ip.DstAddr", "ipv6.DstAddr"),
+        };
+
+        let mut terms = Vec::new();
+
+        if self.ports.is_empty() {
+            terms.push(protocol.to_owned());
+        } else {
+            let ports = self
+                .ports
+                .iter()
+                .map(|p| format!("{protocol}.{port} == {p}"))
+                .collect::<Vec<_>>();
+            terms.push(format!("({})", ports.join(" or ")));
+        }
+
+        if control {
+            terms.push("(tcp.Syn or tcp.Fin or tcp.Rst)".to_owned());
+        } else if let Some(range) = &self.payload_length {
+            terms.push(format!("{protocol}.PayloadLength >= {}", range.start));
+            terms.push(format!("{protocol}.PayloadLength < {}", range.end));
+        }
+
+        if self.skip_injected {
+            terms.push("!impostor".to_owned());
+        }
+        if !self.loopback {
+            terms.push("!loopback".to_owned());
+        }
+
+        if let Some(include) = windivert_networks(&self.include, v4, v6) {
+            terms.push(format!("({include})"));
+        }
+        if let Some(exclude) = windivert_networks(&self.exclude, v4, v6) {
+            terms.push(format!("!({exclude})"));
+        }
+
+        terms.join(" and ")
+    }
+
+    /// Render the filter as an nftables table queueing the captured packets.
+    ///
+    /// nftables can't match the payload length, so it has to be checked by the consumer of
+    /// the queue.
+    pub fn to_nftables(&self, options: &NftablesOptions) -> String {
+        let mut out = String::new();
+
+        let _ = writeln!(out, "table inet {} {{", options.table);
+
+        let (include_v4, include_v6) = split_families(&self.include);
+        let (exclude_v4, exclude_v6) = split_families(&self.exclude);
+
+        if !self.include.is_empty() {
+            nftables_set(&mut out, "include_v4", "ipv4_addr", &include_v4);
+            nftables_set(&mut out, "include_v6", "ipv6_addr", &include_v6);
+        }
+        if !exclude_v4.is_empty() {
+            nftables_set(&mut out, "exclude_v4", "ipv4_addr", &exclude_v4);
+        }
+        if !exclude_v6.is_empty() {
+            nftables_set(&mut out, "exclude_v6", "ipv6_addr", &exclude_v6);
+        }
+
+        // the control segments of the other direction are queued for connection tracking
+        let tracked = self.track_connections && self.protocol == Protocol::Tcp;
+
+        if self.direction != Direction::Inbound || tracked {
+            let control = self.direction == Direction::Inbound;
+            self.nftables_chain(&mut out, options, Direction::Outbound, control);
+        }
+        if self.direction != Direction::Outbound || tracked {
+            let control = self.direction == Direction::Outbound;
+            self.nftables_chain(&mut out, options, Direction::Inbound, control);
+        }
+
+        out.push_str("}\n");
+        out
+    }
+
+    /// Chain queueing the packets of one direction, only the SYN, FIN and RST segments if
+    /// `control`.
+    fn nftables_chain(
+        &self,
+        out: &mut String,
+        options: &NftablesOptions,
+        direction: Direction,
+        control: bool,
+    ) {
+        let (chain, interface, addr, port) = match direction {
+            Direction::Inbound => ("input", "iifname", "saddr", "sport"),
+            _ => ("output", "oifname", "daddr", "dport"),
+        };
+        let protocol = match self.protocol {
+            Protocol::Tcp => "tcp",
+            Protocol::Udp => "udp",
+        };
+
+        let _ = writeln!(out, "\tchain {chain} {{");
+        let _ = writeln!(
+            out,
+            "\t\ttype filter hook {chain} priority mangle; policy accept;"
+        );
+
+        if self.skip_injected {
+            let mark = options.mark;
+            let _ = writeln!(out, "\t\tmeta mark & {mark:#x} == {mark:#x} return");
+        }
+        if !self.loopback {
+            let _ = writeln!(out, "\t\t{interface} \"lo\" return");
+        }
+
+        if !self.include.is_empty() {
+            let _ = writeln!(out, "\t\tip {addr} != @include_v4 return");
+            let _ = writeln!(out, "\t\tip6 {addr} != @include_v6 return");
+        }
+        if self.exclude.iter().any(Cidr::is_ipv4) {
+            let _ = writeln!(out, "\t\tip {addr} @exclude_v4 return");
+        }
+        if self.exclude.iter().any(|cidr| !cidr.is_ipv4()) {
+            let _ = writeln!(out, "\t\tip6 {addr} @exclude_v6 return");
+        }
+
+        let mut matcher = match self.ports.as_slice() {
+            [] => format!("meta l4proto {protocol}"),
+            [single] => format!("{protocol} {port} {single}"),
+            ports => {
+                let ports = ports.iter().map(u16::to_string).collect::<Vec<_>>();
+                format!("{protocol} {port} {{ {} }}", ports.join(", "))
+            }
+        };
+        if control {
+            matcher.push_str(" tcp flags & (syn | fin | rst) != 0");
+        }
+
+        let _ = writeln!(out, "\t\t{matcher} queue num {} bypass", options.queue);
+        out.push_str("\t}\n");
+    }
+}
+
+/// Terms matching an address inside any of the networks, `None` if there are none or too
+/// many of them.
+fn windivert_networks(networks: &[Cidr], v4: &str, v6: &str) -> Option<String> {
+    if networks.is_empty() || networks.len() > MAX_WINDIVERT_NETWORKS {
+        return None;
+    }
+
+    let ranges = networks
+        .iter()
+        .map(|cidr| {
+            let field = if cidr.is_ipv4() { v4 } else { v6 };
+
+            if cidr.first() == cidr.last() {
+                format!("{field} == {}", cidr.first())
+            } else {
+                format!(
+                    "({field} >= {} and {field} <= {})",
+                    cidr.first(),
+                    cidr.last()
+                )
+            }
+        })
+        .collect::<Vec<_>>();
+
+    Some(ranges.join(" or "))
+}
+
+fn split_families(networks: &[Cidr]) -> (Vec<Cidr>, Vec<Cidr>) {
+    networks.iter().copied().partition(Cidr::is_ipv4)
+}
+
+fn nftables_set(out: &mut String, name: &str, kind: &str, networks: &[Cidr]) {
+    let _ = writeln!(out, "\tset {name} {{");
+    let _ = writeln!(out, "\t\ttype {kind}");
+    let _ = writeln!(out, "\t\tflags interval");
+
+    if !networks.is_empty() {
+        let elements = networks.iter().map(Cidr::to_string).collect::<Vec<_>>();
+        let _ = writeln!(out, "\t\telements = {{ {} }}", elements.join(", "));
+    }
+
+    out.push_str("\t}\n");
+}
@@ -0,0 +1,374 @@
+use std::{collections::HashMap, fs, path::Path};
+
+use color_eyre::{Result, eyre::Context};
+use log::{info, warn};
+
+/// Default file name of the list of domains the desync is applied to.
+pub const HOSTLIST_FILE: &str = "hostlist.txt";
+/// Default file name of the list of domains the desync is never applied to.
+pub const HOSTLIST_EXCLUDE_FILE: &str = "hostlist-exclude.txt";
+
+/// Maximum length of a domain name, as per RFC 1035.
+const MAX_DOMAIN_LENGTH: usize = 253;
+/// Maximum number of labels in a domain name.
+const MAX_LABELS: usize = MAX_DOMAIN_LENGTH.div_ceil(2);
+
+/// The entry matches the domain itself.
+const MATCH_EXACT: u8 = 1 << 0;
+/// The entry matches any subdomain of the domain.
+const MATCH_SUBDOMAINS: u8 = 1 << 1;
+
+/// Sentinel for a missing node index.
+const NONE: u32 = u32::MAX;
+
+/// A set of domain patterns stored as a trie of reversed labels.
+///
+/// Every line of a hostlist holds one entry:
+/// - `example.com` matches `example.com` and all of its subdomains
+/// - `=example.com` matches `example.com` only
+/// - `*.example.com` matches the subdomains of `example.com`, but not `example.com` itself
+/// - `*` in any other position matches exactly one label, e.g. `cdn.*.example.com`
+///
+/// Matching is case-insensitive. Empty lines and everything after `#` are ignored.
+#[derive(Default)]
+pub struct Hostlist {
+    /// Interned labels, shared by all nodes.
+    labels: HashMap<Box<str>, u32>,
+    /// Nodes in breadth-first order, the root is always at index 0.
+    nodes: Vec<Node>,
+    /// Edges of all nodes, sorted by label within each node.
+    edges: Vec<Edge>,
+    /// Number of entries in the list.
+    len: usize,
+}
+
+struct Node {
+    first_edge: u32,
+    edge_count: u32,
+    /// Child matching any single label.
+    wildcard: u32,
+    flags: u8,
+}
+
+struct Edge {
+    label: u32,
+    node: u32,
+}
+
+/// A line of a hostlist that couldn't be parsed.
+pub struct InvalidEntry {
+    /// 1-based line number.
+    pub line: usize,
+    pub entry: String,
+}
+
+impl Hostlist {
+    /// Parse a hostlist, returning the list and the lines that couldn't be parsed.
+    pub fn parse(text: &str) -> (Self, Vec<InvalidEntry>) {
+        let mut builder = Builder::default();
+        let mut invalid = Vec::new();
+
+        for (index, line) in text.lines().enumerate() {
+            let entry = line.split('#').next().unwrap_or_default().trim();
+
+            if !entry.is_empty() && !builder.insert(entry) {
+                invalid.push(InvalidEntry {
+                    line: index + 1,
+                    entry: entry.to_owned(),
+                });
+            }
+        }
+
+        (builder.build(), invalid)
+    }
+
+    /// Load a hostlist from a file, skipping and logging the lines that couldn't be parsed.
+    pub fn load(path: &Path) -> Result<Self> {
+        let text = fs::read_to_string(path)
+            .wrap_err_with(|| format!("Failed to read hostlist {}", path.display()))?;
+
+        let (hostlist, invalid) = Self::parse(&text);
+
+        for entry in invalid {
+            warn!(
+                "{}:{}: ignoring invalid hostlist entry {:?}",
+                path.display(),
+                entry.line,
+                entry.entry
+            );
+        }
+
+        Ok(hostlist)
+    }
+
+    /// Load a hostlist from a file if it exists.
+    pub fn load_if_exists(path: &Path) -> Result<Option<Self>> {
+        if !path.exists() {
+            return Ok(None);
+        }
+
+        let hostlist = Self::load(path)?;
+        info!("Loaded {} entries from {}", hostlist.len(), path.display());
+
+        Ok(Some(hostlist))
+    }
+
+    /// Number of entries in the list.
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Check if the given domain name matches any entry of the list.
+    pub fn matches(&self, domain: &str) -> bool {
+        if self.is_empty() || domain.len() > MAX_DOMAIN_LENGTH {
+            return false;
+        }
+
+        let mut buffer = [0; MAX_DOMAIN_LENGTH];
+        let lowercase = &mut buffer[..domain.len()];
+        lowercase.copy_from_slice(domain.as_bytes());
+
+        // lowercasing ASCII bytes keeps UTF-8 intact
+        lowercase.make_ascii_lowercase();
+        let Ok(domain) = std::str::from_utf8(lowercase) else {
+            return false;
+        };
+
+        let domain = domain.strip_suffix('.').unwrap_or(domain);
+
+        let mut labels = [None; MAX_LABELS];
+        let mut count = 0;
+
+        for label in domain.rsplit('.') {
+            if count == MAX_LABELS {
+                return false;
+            }
+            // unknown labels can still be matched by wildcards
+            labels[count] = self.labels.get(label).copied();
+            count += 1;
+        }
+
+        self.matches_from(0, &labels[..count])
+    }
+
+    /// Match the remaining (reversed) labels against the subtree of the given node.
+    fn matches_from(&self, node: u32, labels: &[Option<u32>]) -> bool {
+        let node = &self.nodes[node as usize];
+
+        let Some((label, rest)) = labels.split_first() else {
+            return node.flags & MATCH_EXACT != 0;
+        };
+
+        if node.flags & MATCH_SUBDOMAINS != 0 {
+            return true;
+        }
+
+        if let Some(label) = label {
+            let start = node.first_edge as usize;
+            let edges = &self.edges[start..start + node.edge_count as usize];
+
+            if let Ok(index) = edges.binary_search_by_key(label, |edge| edge.label)
+                && self.matches_from(edges[index].node, rest)
+            {
+                return true;
+            }
+        }
+
+        node.wildcard != NONE && self.matches_from(node.wildcard, rest)
+    }
+}
+
+/// A mutable trie used while parsing, frozen into a compact `Hostlist` afterwards.
+#[derive(Default)]
+struct Builder {
+    labels: HashMap<Box<str>, u32>,
+    nodes: Vec<BuilderNode>,
+    len: usize,
+}
+
+#[derive(Default)]
+struct BuilderNode {
+    children: HashMap<u32, usize>,
+    wildcard: Option<usize>,
+    flags: u8,
+}
+
+impl Builder {
+    /// Insert an entry, returning `false` if it isn't a valid pattern.
+    fn insert(&mut self, entry: &str) -> bool {
+        let (pattern, flags) = if let Some(domain) = entry.strip_prefix('=') {
+            (domain, MATCH_EXACT)
+        } else if let Some(domain) = entry.strip_prefix("*.") {
+            (domain, MATCH_SUBDOMAINS)
+        } else {
+            (entry, MATCH_EXACT | MATCH_SUBDOMAINS)
+        };
+
+        let pattern = pattern.to_ascii_lowercase();
+        let pattern = pattern.strip_suffix('.').unwrap_or(&pattern);
+
+        if pattern.is_empty()
+            || pattern.len() > MAX_DOMAIN_LENGTH
+            || !pattern.split('.').all(is_valid_label)
+        {
+            return false;
+        }
+
+        if self.nodes.is_empty() {
+            self.nodes.push(BuilderNode::default());
+        }
+
+        let mut node = 0;
+
+        for label in pattern.rsplit('.') {
+            node = if label == "*" {
+                match self.nodes[node].wildcard {
+                    Some(child) => child,
+                    None => {
+                        let child = self.push_node();
+                        self.nodes[node].wildcard = Some(child);
+                        child
+                    }
+                }
+            } else {
+                let label = self.intern(label);
+                match self.nodes[node].children.get(&label) {
+                    Some(&child) => child,
+                    None => {
+                        let child = self.push_node();
+                        self.nodes[node].children.insert(label, child);
+                        child
+                    }
+                }
+            };
+        }
+
+        self.nodes[node].flags |= flags;
+        self.len += 1;
+
+        true
+    }
+
+    fn push_node(&mut self) -> usize {
+        self.nodes.push(BuilderNode::default());
+        self.nodes.len() - 1
+    }
+
+    fn intern(&mut self, label: &str) -> u32 {
+        if let Some(&id) = self.labels.get(label) {
+            return id;
+        }
+
+        let id = self.labels.len() as u32;
+        self.labels.insert(label.into(), id);
+        id
+    }
+
+    /// Freeze the trie into flat, breadth-first ordered node and edge arrays.
+    fn build(self) -> Hostlist {
+        if self.nodes.is_empty() {
+            return Hostlist::default();
+        }
+
+        let mut order = vec![NONE; self.nodes.len()];
+        let mut queue = vec![0];
+        order[0] = 0;
+
+        let mut nodes = Vec::with_capacity(self.nodes.len());
+        let mut edges = Vec::with_capacity(self.nodes.len() - 1);
+
+        let mut next = 1;
+        let mut index = 0;
+
+        while index < queue.len() {
+            let old = &self.nodes[queue[index]];
+            index += 1;
+
+            let mut children = old.children.iter().collect::<Vec<_>>();
+            children.sort_unstable_by_key(|(label, _)| **label);
+
+            let first_edge = edges.len() as u32;
+
+            for (&label, &child) in children {
+                order[child] = next;
+                next += 1;
+                queue.push(child);
+                edges.push(Edge {
+                    label,
+                    node: order[child],
+                });
+            }
+
+            let wildcard = match old.wildcard {
+                Some(child) => {
+                    order[child] = next;
+                    next += 1;
+                    queue.push(child);
+                    order[child]
+                }
+                None => NONE,
+            };
+
+            nodes.push(Node {
+                first_edge,
+                edge_count: edges.len() as u32 - first_edge,
+                wildcard,
+                flags: old.flags,
+            });
+        }
+
+        Hostlist {
+            labels: self.labels,
+            nodes,
+            edges,
+            len: self.len,
+        }
+    }
+}
+
+/// Check if a lowercased label is a valid host name label or a wildcard.
+fn is_valid_label(label: &str) -> bool {
+    label == "*"
+        || (!label.is_empty()
+            && label
+                .bytes()
+                .all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_' || !b.is_ascii()))
+}
+
+/// Include and exclude hostlists deciding which flows the desync is applied to.
+#[derive(Default)]
+pub struct HostFilter {
+    /// Domains to desync. If absent, every flow is desynced unless excluded.
+    pub include: Option<Hostlist>,
+    /// Domains never to desync.
+    pub exclude: Hostlist,
+}
+
+impl HostFilter {
+    /// Load the include and exclude hostlists from the given files, if they exist.
+    pub fn load(include: &Path, exclude: &Path) -> Result<Self> {
+        Ok(Self {
+            include: Hostlist::load_if_exists(include)?,
+            exclude: Hostlist::load_if_exists(exclude)?.unwrap_or_default(),
+        })
+    }
+
+    /// Check if a flow to the given host should be desynced.
+    ///
+    /// Flows whose host is unknown are only desynced if there is no include list.
+    pub fn matches(&self, host: Option<&str>) -> bool {
+        match (host, &self.include) {
+            (Some(host), include) => {
+                include.as_ref().is_none_or(|include| include.matches(host))
+                    && !self.exclude.matches(host)
+            }
+            (None, include) => include.is_none(),
+        }
+    }
+}
@@ -1,18 +1,275 @@
-use crate::windivert::Packet;
+pub mod tls;
 
-/// Check if the given packet is a TLS ClientHello message
-/// This is a very naive check and may not cover all cases
-/// but works for most common scenarios
-/// It checks if the first byte is 0x16 (Handshake)
-/// and the sixth byte is 0x01 (ClientHello)
+use std::ops::Range;
+
+/// Request methods accepted at the start of an HTTP/1.x request line.
+const HTTP_METHODS: &[&[u8]] = &[
+    b"GET", b"POST", b"HEAD", b"PUT", b"DELETE", b"OPTIONS", b"PATCH", b"CONNECT", b"TRACE",
+];
+
+/// Upper bound on the number of headers collected for a single request.
+const MAX_HEADERS: usize = 64;
+
+/// An HTTP/1.x request head found in a TCP payload.
 ///
-/// # Safety
-/// This function assumes that the packet has a valid TCP payload
-pub fn is_client_hello(packet: &Packet<'_>) -> bool {
-    let data = packet.data_unchecked();
+/// All offsets are relative to the start of the payload the request was parsed from. If the
+/// request was split across segments, `end` is `None` and only the headers that fit in this
+/// segment are available.
+pub struct HttpRequest<'a> {
+    data: &'a [u8],
+    /// Offset of the first byte of the request line.
+    pub start: usize,
+    pub method: Range<usize>,
+    pub target: Range<usize>,
+    pub headers: Vec<HttpHeader>,
+    /// Offset right after the empty line terminating the headers.
+    pub end: Option<usize>,
+}
+
+/// Byte offsets of a single header line inside the payload.
+pub struct HttpHeader {
+    pub name: Range<usize>,
+    pub value: Range<usize>,
+}
+
+impl<'a> HttpRequest<'a> {
+    /// The request method, e.g. `GET`.
+    pub fn method(&self) -> &'a str {
+        as_str(&self.data[self.method.clone()])
+    }
+
+    /// The request target. This may be truncated if the request line is split across segments.
+    pub fn target(&self) -> &'a str {
+        as_str(&self.data[self.target.clone()])
+    }
+
+    /// Find the first header with the given name (case-insensitive).
+    pub fn header(&self, name: &str) -> Option<&HttpHeader> {
+        self.headers
+            .iter()
+            .find(|header| self.data[header.name.clone()].eq_ignore_ascii_case(name.as_bytes()))
+    }
+
+    /// The value of the `Host` header, including the port if present.
+    pub fn host(&self) -> Option<&'a str> {
+        self.header("Host")
+            .map(|header| as_str(&self.data[header.value.clone()]))
+    }
+
+    /// The value of the `Host` header without the port.
+    pub fn hostname(&self) -> Option<&'a str> {
+        let host = self.host()?;
+
+        if let Some(rest) = host.strip_prefix('[') {
+            return rest.split(']').next();
+        }
+
+        host.split(':').next()
+    }
+
+    /// The length of the body as announced by `Content-Length`, if any.
+    fn content_length(&self) -> Option<usize> {
+        self.header("Content-Length")
+            .and_then(|header| as_str(&self.data[header.value.clone()]).parse().ok())
+    }
+
+    /// Whether the request body uses chunked transfer encoding.
+    fn is_chunked(&self) -> bool {
+        self.header("Transfer-Encoding").is_some_and(|header| {
+            as_str(&self.data[header.value.clone()])
+                .to_ascii_lowercase()
+                .contains("chunked")
+        })
+    }
+}
+
+/// An iterator over the (possibly pipelined) HTTP requests starting in a TCP payload.
+///
+/// Iteration stops at the first byte that can't be the start of a request, so continuation
+/// segments of a request body yield nothing.
+pub struct HttpRequests<'a> {
+    data: &'a [u8],
+    offset: Option<usize>,
+}
+
+impl<'a> Iterator for HttpRequests<'a> {
+    type Item = HttpRequest<'a>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let offset = self.offset.take()?;
+        let request = parse_http_request_at(self.data, offset)?;
+
+        // the next request can only be located if this one is complete within the segment
+        if let Some(end) = request.end
+            && !request.is_chunked()
+        {
+            // a body length past the end of memory can't be followed
+            let next = end.checked_add(request.content_length().unwrap_or(0));
+            if let Some(next) = next
+                && next < self.data.len()
+            {
+                self.offset = Some(next);
+            }
+        }
+
+        Some(request)
+    }
+}
+
+/// Iterate over the HTTP requests that start in the given TCP payload.
+pub fn parse_http_requests(data: &[u8]) -> HttpRequests<'_> {
+    HttpRequests {
+        data,
+        offset: Some(0),
+    }
+}
+
+/// Parse a single request head starting at `start`.
+fn parse_http_request_at(data: &[u8], start: usize) -> Option<HttpRequest<'_>> {
+    let rest = data.get(start..)?;
+
+    let method_len = HTTP_METHODS
+        .iter()
+        .find(|method| rest.starts_with(method) && rest.get(method.len()) == Some(&b' '))?
+        .len();
+
+    let method = start..start + method_len;
+    let target_start = method.end + 1;
+
+    let line_end = find(data, target_start, b"\r\n");
+    let line = &data[target_start..line_end.unwrap_or(data.len())];
+
+    let target = match line_end {
+        // a complete request line must end with the protocol version
+        Some(_) => {
+            let space = line.iter().rposition(|&b| b == b' ')?;
+            if !line[space + 1..].starts_with(b"HTTP/1.") {
+                return None;
+            }
+            target_start..target_start + space
+        }
+        // the request line continues in the next segment
+        None => target_start..data.len(),
+    };
+
+    if target.is_empty() || !data[target.clone()].iter().all(u8::is_ascii_graphic) {
+        return None;
+    }
+
+    let mut request = HttpRequest {
+        data,
+        start,
+        method,
+        target,
+        headers: Vec::new(),
+        end: None,
+    };
+
+    let Some(line_end) = line_end else {
+        return Some(request);
+    };
+
+    let mut offset = line_end + 2;
+
+    while let Some(end) = find(data, offset, b"\r\n") {
+        if end == offset {
+            request.end = Some(end + 2);
+            break;
+        }
+
+        if request.headers.len() < MAX_HEADERS
+            && let Some(header) = parse_header(data, offset..end)
+        {
+            request.headers.push(header);
+        }
+
+        offset = end + 2;
+    }
+
+    Some(request)
+}
+
+/// The start of an HTTP/1.x response head.
+pub struct HttpResponse<'a> {
+    pub status: u16,
+    /// Value of the `Location` header, if it is in the segment.
+    pub location: Option<&'a str>,
+    /// Value of the `Content-Length` header, if it is in the segment.
+    pub content_length: Option<usize>,
+    /// Offset right after the empty line terminating the headers, `None` if it isn't in the
+    /// segment.
+    pub end: Option<usize>,
+}
+
+/// Parse the status line and the `Location` and `Content-Length` headers of a response
+/// starting the payload.
+pub fn parse_http_response(data: &[u8]) -> Option<HttpResponse<'_>> {
+    if !data.starts_with(b"HTTP/1.") {
+        return None;
+    }
+
+    let status = as_str(data.get(9..12)?).parse().ok()?;
+    let mut response = HttpResponse {
+        status,
+        location: None,
+        content_length: None,
+        end: None,
+    };
+
+    let mut offset = find(data, 0, b"\r\n")? + 2;
+
+    while let Some(end) = find(data, offset, b"\r\n") {
+        if end == offset {
+            response.end = Some(end + 2);
+            break;
+        }
+
+        if let Some(header) = parse_header(data, offset..end) {
+            let name = &data[header.name.clone()];
+            let value = as_str(&data[header.value]);
+
+            if name.eq_ignore_ascii_case(b"Location") {
+                response.location = Some(value);
+            } else if name.eq_ignore_ascii_case(b"Content-Length") {
+                response.content_length = value.parse().ok();
+            }
+        }
+
+        offset = end + 2;
+    }
+
+    Some(response)
+}
+
+/// Parse a `name: value` header line, trimming whitespace around the value.
+fn parse_header(data: &[u8], line: Range<usize>) -> Option<HttpHeader> {
+    let colon = line.start + data[line.clone()].iter().position(|&b| b == b':')?;
+
+    let mut value = colon + 1..line.end;
+    while value.start < value.end && matches!(data[value.start], b' ' | b'\t') {
+        value.start += 1;
+    }
+    while value.start < value.end && matches!(data[value.end - 1], b' ' | b'\t') {
+        value.end -= 1;
+    }
+
+    Some(HttpHeader {
+        name: line.start..colon,
+        value,
+    })
+}
+
+/// Find the first occurrence of `needle` in `data` at or after `from`.
+fn find(data: &[u8], from: usize, needle: &[u8]) -> Option<usize> {
+    data.get(from..)?
+        .windows(needle.len())
+        .position(|window| window == needle)
+        .map(|pos| from + pos)
+}
 
-    data.first().map(|&b| b == 0x16).unwrap_or(false) // Handshake
-        && data.get(5).map(|&b| b == 0x01).unwrap_or(false) // ClientHello
+/// Interpret header bytes as a string, falling back to an empty string on invalid UTF-8.
+fn as_str(bytes: &[u8]) -> &str {
+    std::str::from_utf8(bytes).unwrap_or_default()
 }
 
 /// A minimal HTTP GET request for "http://www.w3.org/"
@@ -0,0 +1,216 @@
+use std::ops::Range;
+
+/// TLS record content type for handshake messages.
+const CONTENT_TYPE_HANDSHAKE: u8 = 0x16;
+/// Handshake message type of a ClientHello.
+const HANDSHAKE_CLIENT_HELLO: u8 = 0x01;
+/// Extension type of the server name indication.
+const EXTENSION_SERVER_NAME: u16 = 0x0000;
+/// Extension type of the encrypted client hello (draft-ietf-tls-esni).
+const EXTENSION_ENCRYPTED_CLIENT_HELLO: u16 = 0xfe0d;
+/// Server name type of a DNS host name.
+const SERVER_NAME_HOST_NAME: u8 = 0x00;
+/// ECH type of the outer ClientHello, which carries the encrypted inner one.
+const ECH_CLIENT_HELLO_OUTER: u8 = 0x00;
+
+/// Check if the given TCP payload is a TLS ClientHello message
+/// This is a very naive check and may not cover all cases
+/// but works for most common scenarios
+/// It checks if the first byte is 0x16 (Handshake)
+/// and the sixth byte is 0x01 (ClientHello)
+pub fn is_client_hello(data: &[u8]) -> bool {
+    data.first().map(|&b| b == CONTENT_TYPE_HANDSHAKE).unwrap_or(false) // Handshake
+        && data.get(5).map(|&b| b == HANDSHAKE_CLIENT_HELLO).unwrap_or(false) // ClientHello
+}
+
+/// A TLS ClientHello found at the start of a TCP payload.
+///
+/// All offsets are relative to the start of the payload. A ClientHello may be larger than a
+/// single segment, in which case only the extensions that fit in this segment are visible.
+pub struct ClientHello<'a> {
+    data: &'a [u8],
+    /// Offsets of the extensions inside the payload.
+    pub extensions: Vec<Extension>,
+    /// Offset of the host name inside the server name extension.
+    ///
+    /// If the ClientHello uses ECH, this is the public name of the outer ClientHello.
+    pub sni: Option<Range<usize>>,
+    /// Whether this is the outer ClientHello of an encrypted client hello.
+    ///
+    /// GREASE ECH (sent by browsers when the server has no ECH config) can't be told apart
+    /// from real ECH on the wire, so it is reported here as well.
+    pub ech: bool,
+    /// Whether any cipher suite or extension uses a reserved GREASE value (RFC 8701).
+    pub grease: bool,
+}
+
+/// Byte offsets of a single ClientHello extension.
+pub struct Extension {
+    pub kind: u16,
+    /// Offset of the extension type field.
+    pub start: usize,
+    /// Offsets of the extension data, clipped to the end of the segment.
+    pub data: Range<usize>,
+}
+
+impl Extension {
+    /// Whether the extension type is a reserved GREASE value.
+    #[inline]
+    pub fn is_grease(&self) -> bool {
+        is_grease(self.kind)
+    }
+}
+
+impl<'a> ClientHello<'a> {
+    /// The server name indication, if present and valid UTF-8.
+    pub fn server_name(&self) -> Option<&'a str> {
+        self.sni
+            .clone()
+            .and_then(|sni| std::str::from_utf8(&self.data[sni]).ok())
+    }
+}
+
+/// Parse the TLS ClientHello at the start of the given TCP payload.
+pub fn parse_client_hello(data: &[u8]) -> Option<ClientHello<'_>> {
+    if !is_client_hello(data) {
+        return None;
+    }
+
+    // record header (5) + handshake header (4) + version (2) + random (32)
+    let mut offset = 5 + 4 + 2 + 32;
+
+    let session_id_len = *data.get(offset)? as usize;
+    offset += 1 + session_id_len;
+
+    let cipher_suites_len = read_u16(data, offset)? as usize;
+    offset += 2;
+
+    let grease = (offset..offset + cipher_suites_len)
+        .step_by(2)
+        .filter_map(|suite| read_u16(data, suite))
+        .any(is_grease);
+    offset += cipher_suites_len;
+
+    let compression_len = *data.get(offset)? as usize;
+    offset += 1 + compression_len;
+
+    let extensions_len = read_u16(data, offset)? as usize;
+    offset += 2;
+
+    let extensions_end = offset + extensions_len;
+
+    let mut hello = ClientHello {
+        data,
+        extensions: Vec::new(),
+        sni: None,
+        ech: false,
+        grease,
+    };
+
+    while offset + 4 <= extensions_end.min(data.len()) {
+        let kind = read_u16(data, offset)?;
+        let len = read_u16(data, offset + 2)? as usize;
+
+        let start = offset + 4;
+        let end = (start + len).min(data.len());
+
+        match kind {
+            EXTENSION_SERVER_NAME => hello.sni = parse_server_name(data, start..end),
+            EXTENSION_ENCRYPTED_CLIENT_HELLO => {
+                hello.ech = data.get(start) == Some(&ECH_CLIENT_HELLO_OUTER)
+            }
+            kind if is_grease(kind) => hello.grease = true,
+            _ => {}
+        }
+
+        hello.extensions.push(Extension {
+            kind,
+            start: offset,
+            data: start..end,
+        });
+
+        offset = start + len;
+    }
+
+    Some(hello)
+}
+
+/// Check if a cipher suite, extension type or group is a reserved GREASE value.
+///
+/// GREASE values have the form `0x?A?A` with both bytes equal (RFC 8701).
+#[inline]
+pub fn is_grease(value: u16) -> bool {
+    value & 0x0f0f == 0x0a0a && value >> 8 == value & 0xff
+}
+
+/// Parse the host name out of the server name extension data.
+fn parse_server_name(data: &[u8], extension: Range<usize>) -> Option<Range<usize>> {
+    // server name list length (2) + name type (1) + name length (2)
+    let name_type = *data.get(extension.start + 2)?;
+    let name_len = read_u16(data, extension.start + 3)? as usize;
+
+    let start = extension.start + 5;
+    let end = start + name_len;
+
+    if name_type != SERVER_NAME_HOST_NAME || end > extension.end {
+        return None;
+    }
+
+    Some(start..end)
+}
+
+/// Copy a complete ClientHello, replacing its server name with `name`.
+///
+/// The record, handshake, extensions and server name lengths are rewritten to match. Returns
+/// `None` if the ClientHello has no server name or doesn't fit the payload.
+pub fn with_server_name(data: &[u8], name: &str) -> Option<Vec<u8>> {
+    let hello = parse_client_hello(data)?;
+    let sni = hello.sni.clone()?;
+    let extension = hello
+        .extensions
+        .iter()
+        .find(|extension| extension.kind == EXTENSION_SERVER_NAME)?;
+
+    let record_len = read_u16(data, 3)? as usize;
+    if 5 + record_len != data.len() {
+        return None;
+    }
+
+    let shift = name.len() as isize - sni.len() as isize;
+    let adjust = |offset: usize| -> Option<[u8; 2]> {
+        let len = read_u16(data, offset)? as isize + shift;
+        Some(u16::try_from(len).ok()?.to_be_bytes())
+    };
+
+    let extensions_len_offset = hello.extensions.first()?.start - 2;
+    // the extension length, the server name list length and the name length
+    let lengths = [
+        3,
+        extensions_len_offset,
+        extension.start + 2,
+        extension.data.start,
+        sni.start - 2,
+    ];
+    let lengths = lengths.map(|offset| adjust(offset).map(|bytes| (offset, bytes)));
+
+    let mut hello = Vec::with_capacity((data.len() as isize + shift) as usize);
+    hello.extend_from_slice(&data[..sni.start]);
+    hello.extend_from_slice(name.as_bytes());
+    hello.extend_from_slice(&data[sni.end..]);
+
+    // every length field lies in front of the name, so its offset doesn't change
+    for length in lengths {
+        let (offset, bytes) = length?;
+        hello[offset..offset + 2].copy_from_slice(&bytes);
+    }
+    let handshake_len = u32::try_from(hello.len() - 9).ok()?;
+    hello[6..9].copy_from_slice(&handshake_len.to_be_bytes()[1..]);
+
+    Some(hello)
+}
+
+/// Read a big-endian `u16` at the given offset.
+pub(crate) fn read_u16(data: &[u8], offset: usize) -> Option<u16> {
+    data.get(offset..offset + 2)
+        .map(|bytes| u16::from_be_bytes([bytes[0], bytes[1]]))
+}
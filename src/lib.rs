@@ -0,0 +1,25 @@
+//! Platform-independent parts of Packetmock: protocol parsing, host matching and settings.
+//!
+//! Everything in here builds on any platform, so it can be tested and benchmarked outside of
+//! Windows. Packet interception itself lives in the binary.
+
+pub mod adaptive;
+pub mod autohostlist;
+pub mod backend;
+pub mod blockcheck;
+pub mod cidr;
+pub mod classify;
+pub mod conntrack;
+pub mod dns;
+pub mod explain;
+pub mod filter;
+pub mod hostlist;
+pub mod http;
+pub mod profile;
+pub mod ratelimit;
+pub mod schedule;
+pub mod settings;
+pub mod simulator;
+pub mod state;
+pub mod stats;
+pub mod strategy;
@@ -1,22 +1,38 @@
-#![cfg(windows)]
 #![windows_subsystem = "windows"]
 
-mod http;
+#[cfg(windows)]
 mod mutex;
+#[cfg(windows)]
 mod service;
+#[cfg(windows)]
 mod tasksch;
+#[cfg(windows)]
 mod tray;
+#[cfg(windows)]
 mod windivert;
 
-use std::{env::args_os, sync::mpsc};
+#[cfg(windows)]
+use std::sync::mpsc;
+use std::{env::args_os, process::exit};
 
-use color_eyre::{Result, config::HookBuilder};
+use color_eyre::{Result, config::HookBuilder, eyre::bail};
 use env_logger::Env;
-use log::{error, info, warn};
+use log::{LevelFilter, warn};
+#[cfg(windows)]
+use log::{error, info};
+#[cfg(windows)]
+use packetmock::blockcheck::BlockcheckOptions;
+use packetmock::{
+    adaptive::StrategyCache, explain::Explanation, settings::open_store, state::StateStore,
+};
+#[cfg(windows)]
 use smol::{block_on, future::or, unblock};
+#[cfg(windows)]
 use winapi::um::wincon::{ATTACH_PARENT_PROCESS, AttachConsole, FreeConsole};
+#[cfg(windows)]
 use windows::Win32::System::Com::{COINIT_APARTMENTTHREADED, CoInitializeEx, CoUninitialize};
 
+#[cfg(windows)]
 use crate::{
     mutex::MutexGuard,
     service::handle_service,
@@ -24,15 +40,18 @@ use crate::{
     tray::{run_tray, toast::show_toast},
 };
 
-const REGISTRY_NAME: &str = "Packetmock";
-
 /// Main entry point for the application.
+#[cfg(windows)]
 fn main() -> Result<()> {
     let is_terminal = unsafe { AttachConsole(ATTACH_PARENT_PROCESS) } != 0;
 
-    init_logger();
     init_color_eyre()?;
+    init_logger();
 
+    handle_explain()?;
+    handle_strategy_cache()?;
+    handle_state()?;
+    handle_blockcheck()?;
     handle_service()?;
 
     let silent = args_os().any(|arg| arg == "--task" || arg == "-t");
@@ -69,7 +88,126 @@ fn main() -> Result<()> {
     Ok(())
 }
 
+/// Packet interception is only available on Windows, the library still builds elsewhere.
+#[cfg(not(windows))]
+fn main() -> Result<()> {
+    init_color_eyre()?;
+    init_logger();
+
+    handle_explain()?;
+    handle_strategy_cache()?;
+    handle_state()?;
+
+    if args_os().nth(1).is_some_and(|arg| arg == "blockcheck") {
+        bail!("Blockcheck needs packet interception, which is only available on Windows");
+    }
+
+    bail!("Packetmock can only intercept packets on Windows");
+}
+
+/// Print what would be done with the settings and every problem found in them if the program
+/// was started with the "explain" argument, without capturing any packets. Exits with status 1
+/// if there are problems.
+fn handle_explain() -> Result<()> {
+    if args_os().nth(1).is_some_and(|arg| arg == "explain") {
+        let explanation = Explanation::new(&*open_store()?);
+        print!("{explanation}");
+        exit(if explanation.is_valid() { 0 } else { 1 });
+    }
+    Ok(())
+}
+
+/// List the strategy chains chosen for the domains if the program was started with the
+/// "strategy-cache" argument, or forget them with "strategy-cache reset [DOMAIN]".
+fn handle_strategy_cache() -> Result<()> {
+    let args = args_os().skip(1).collect::<Vec<_>>();
+    if args.first().is_none_or(|arg| arg != "strategy-cache") {
+        return Ok(());
+    }
+
+    let store = open_store()?;
+    let settings = store.load()?;
+    let mut cache = StrategyCache::load(
+        store.resolve(&settings.lists.state),
+        settings.candidate_chains(),
+        settings.blocking.fail_threshold,
+        settings.adaptive.ttl(),
+    )?;
+
+    let args = args[1..]
+        .iter()
+        .map(|arg| arg.to_string_lossy())
+        .collect::<Vec<_>>();
+
+    match args.iter().map(AsRef::as_ref).collect::<Vec<_>>()[..] {
+        [] => print!("{cache}"),
+        ["reset", ref host @ ..] if host.len() <= 1 => {
+            let removed = cache.reset(host.first().copied())?;
+            println!("Removed {removed} decisions");
+
+            // the running service keeps the decisions in memory until it reloads
+            #[cfg(windows)]
+            if removed > 0 && service::query_service()? == service::ServiceState::Running {
+                service::reload_service()?;
+            }
+        }
+        _ => bail!("Usage: packetmock strategy-cache [reset [DOMAIN]]"),
+    }
+
+    exit(0);
+}
+
+/// Print every entry of the state store if the program was started with the "state" argument.
+fn handle_state() -> Result<()> {
+    if args_os().nth(1).is_some_and(|arg| arg == "state") {
+        let store = open_store()?;
+        let settings = store.load()?;
+        print!(
+            "{}",
+            StateStore::open(store.resolve(&settings.lists.state))?
+        );
+        exit(0);
+    }
+    Ok(())
+}
+
+/// Probe a domain with every candidate chain if the program was started with the "blockcheck"
+/// argument, printing which ones work and a profile using them.
+#[cfg(windows)]
+fn handle_blockcheck() -> Result<()> {
+    let args = args_os()
+        .skip(1)
+        .map(|arg| arg.to_string_lossy().into_owned())
+        .collect::<Vec<_>>();
+    if args.first().is_none_or(|arg| arg != "blockcheck") {
+        return Ok(());
+    }
+
+    let options = BlockcheckOptions::parse(&args[1..])?;
+    let report = windivert::blockcheck::blockcheck(&options)?;
+
+    if options.json {
+        println!("{}", serde_json::to_string_pretty(&report)?);
+    } else {
+        print!("{report}");
+        if report.profile.rules.is_empty() {
+            println!(
+                "\nNo profile to suggest, {} is reached either without desync or with no chain.",
+                report.domain
+            );
+        } else {
+            println!(
+                "\nProfile for the configuration file:\n\n{}",
+                report.profile_toml()?
+            );
+        }
+    }
+
+    exit(0);
+}
+
 /// Set up a Ctrl-C handler to gracefully handle termination signals.
+#[cfg(windows)]
 fn ctrlc_handler() -> Result<impl FnOnce() -> Result<()>> {
     let (sx, rx) = mpsc::channel();
 
@@ -86,9 +224,18 @@ fn ctrlc_handler() -> Result<impl FnOnce() -> Result<()>> {
     })
 }
 
-/// Initialize the logger with environment variable configuration.
+/// Initialize the logger with the configured level, which environment variables override.
 fn init_logger() {
-    env_logger::Builder::from_env(Env::default().default_filter_or("info")).init();
+    let settings = open_store().and_then(|store| store.load());
+    let level = settings
+        .as_ref()
+        .map_or(LevelFilter::Info, |settings| settings.logging.level);
+
+    env_logger::Builder::from_env(Env::default().default_filter_or(level.as_str())).init();
+
+    if let Err(e) = settings {
+        warn!("Failed to load settings: {e:?}");
+    }
 }
 
 /// Initialize the color_eyre error reporting library.
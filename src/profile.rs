@@ -0,0 +1,157 @@
+use std::{net::IpAddr, path::PathBuf};
+
+use color_eyre::{Result, eyre::Context};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    cidr::{Cidr, CidrSet},
+    hostlist::Hostlist,
+    settings::{SettingsStore, Strategies},
+    strategy::Step,
+};
+
+/// Name of the profile used when none is selected.
+pub const DEFAULT_PROFILE: &str = "default";
+
+/// Application protocol of a flow.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Protocol {
+    Http,
+    Tls,
+}
+
+/// A named set of rules choosing the strategy chain of each flow.
+#[derive(Clone, PartialEq, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Profile {
+    /// Rules in order of precedence, flows matching none of them are not desynced.
+    pub rules: Vec<Rule>,
+}
+
+/// Conditions a flow has to meet for the chain to be applied. Empty conditions match anything.
+#[derive(Clone, PartialEq, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Rule {
+    /// Destination ports.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub ports: Vec<u16>,
+    pub protocol: Option<Protocol>,
+    /// Hostlist the host name has to match. Flows without a host name never match.
+    pub hostlist: Option<PathBuf>,
+    /// Networks the destination has to be in.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub cidr: Vec<Cidr>,
+    /// Steps applied to matching flows, in order.
+    pub chain: Vec<Step>,
+}
+
+impl Profile {
+    /// The profile equivalent to the `[strategies]` settings, used when no `default` profile is
+    /// configured.
+    pub fn from_strategies(strategies: &Strategies) -> Self {
+        let mut tls = vec![Step::Fake { ttl: None }];
+
+        if let Some(offset) = strategies.pad_sni_offset {
+            tls.push(Step::PadSni { offset });
+        }
+        if let Some(case) = strategies.sni_case {
+            tls.push(Step::SniCase { case });
+        }
+        if let Some(position) = strategies.split_position {
+            tls.push(Step::Split { position });
+        }
+
+        Self {
+            rules: vec![
+                Rule {
+                    protocol: Some(Protocol::Http),
+                    chain: vec![Step::Fake { ttl: None }],
+                    ..Default::default()
+                },
+                Rule {
+                    protocol: Some(Protocol::Tls),
+                    chain: tls,
+                    ..Default::default()
+                },
+            ],
+        }
+    }
+}
+
+/// A profile with its hostlists and networks loaded, ready to match flows.
+pub struct ActiveProfile {
+    pub name: String,
+    rules: Vec<ActiveRule>,
+}
+
+struct ActiveRule {
+    ports: Vec<u16>,
+    protocol: Option<Protocol>,
+    hostlist: Option<Hostlist>,
+    cidr: Option<CidrSet>,
+    chain: Vec<Step>,
+}
+
+/// What is known about a flow when its chain is chosen.
+pub struct Flow<'a> {
+    pub port: u16,
+    pub protocol: Protocol,
+    pub host: Option<&'a str>,
+    pub addr: IpAddr,
+}
+
+impl ActiveProfile {
+    /// Load the hostlists of the profile, resolving their paths against the store.
+    pub fn load(name: &str, profile: &Profile, store: &dyn SettingsStore) -> Result<Self> {
+        let rules = profile
+            .rules
+            .iter()
+            .map(|rule| {
+                let hostlist = match &rule.hostlist {
+                    Some(path) => Some(Hostlist::load(&store.resolve(path))?),
+                    None => None,
+                };
+
+                Ok(ActiveRule {
+                    ports: rule.ports.clone(),
+                    protocol: rule.protocol,
+                    hostlist,
+                    cidr: (!rule.cidr.is_empty()).then(|| CidrSet::new(&rule.cidr)),
+                    chain: rule.chain.clone(),
+                })
+            })
+            .collect::<Result<_>>()
+            .wrap_err_with(|| format!("Failed to load profile {name:?}"))?;
+
+        Ok(Self {
+            name: name.to_owned(),
+            rules,
+        })
+    }
+
+    /// The chain of the first rule matching the flow, `None` if the flow isn't desynced.
+    pub fn select(&self, flow: &Flow<'_>) -> Option<&[Step]> {
+        self.rules
+            .iter()
+            .find(|rule| rule.matches(flow))
+            .map(|rule| rule.chain.as_slice())
+    }
+}
+
+impl ActiveRule {
+    fn matches(&self, flow: &Flow<'_>) -> bool {
+        (self.ports.is_empty() || self.ports.contains(&flow.port))
+            && self
+                .protocol
+                .is_none_or(|protocol| protocol == flow.protocol)
+            && self
+                .hostlist
+                .as_ref()
+                .is_none_or(|hostlist| flow.host.is_some_and(|host| hostlist.matches(host)))
+            && self
+                .cidr
+                .as_ref()
+                .is_none_or(|cidr| cidr.contains(flow.addr))
+    }
+}
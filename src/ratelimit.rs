@@ -0,0 +1,140 @@
+//! Token buckets limiting the fakes injected, globally and per destination address.
+//!
+//! A bucket holds up to `burst` tokens and gains `rate` tokens per second. Injecting a fake
+//! takes a token from the global bucket and from the bucket of its destination, and a flow is
+//! only desynced if both have enough tokens for all of its fakes.
+
+use std::{collections::HashMap, net::IpAddr, time::Instant};
+
+use crate::settings::Injection;
+
+/// A bucket of tokens refilling at a constant rate.
+#[derive(Clone, Debug)]
+pub struct TokenBucket {
+    /// Tokens gained per second.
+    rate: f64,
+    burst: f64,
+    tokens: f64,
+    updated: Instant,
+}
+
+impl TokenBucket {
+    /// A full bucket.
+    pub fn new(rate: u32, burst: u32, now: Instant) -> Self {
+        Self {
+            rate: f64::from(rate),
+            burst: f64::from(burst),
+            tokens: f64::from(burst),
+            updated: now,
+        }
+    }
+
+    /// Take `count` tokens if there are enough of them.
+    pub fn try_take(&mut self, count: u32, now: Instant) -> bool {
+        self.refill(now);
+
+        if self.tokens < f64::from(count) {
+            return false;
+        }
+
+        self.tokens -= f64::from(count);
+        true
+    }
+
+    /// Whether `count` tokens could be taken.
+    pub fn has(&mut self, count: u32, now: Instant) -> bool {
+        self.refill(now);
+        self.tokens >= f64::from(count)
+    }
+
+    /// Whether the bucket refilled completely, so it is no different from a new one.
+    pub fn is_full(&mut self, now: Instant) -> bool {
+        self.refill(now);
+        self.tokens >= self.burst
+    }
+
+    /// Change the rate and burst, keeping the tokens left up to the new burst.
+    pub fn set_limits(&mut self, rate: u32, burst: u32, now: Instant) {
+        self.refill(now);
+        self.rate = f64::from(rate);
+        self.burst = f64::from(burst);
+        self.tokens = self.tokens.min(self.burst);
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.burst);
+        self.updated = now;
+    }
+}
+
+/// The global bucket and the buckets of the destinations fakes were injected to lately.
+pub struct InjectionLimiter {
+    limits: Injection,
+    /// `None` without a global rate.
+    global: Option<TokenBucket>,
+    destinations: HashMap<IpAddr, TokenBucket>,
+}
+
+impl InjectionLimiter {
+    pub fn new(limits: &Injection) -> Self {
+        let mut limiter = Self {
+            limits: limits.clone(),
+            global: None,
+            destinations: HashMap::new(),
+        };
+        limiter.set_limits(limits, Instant::now());
+
+        limiter
+    }
+
+    /// Apply reloaded limits to the buckets.
+    pub fn set_limits(&mut self, limits: &Injection, now: Instant) {
+        self.global = match (self.global.take(), limits.rate) {
+            (_, 0) => None,
+            (Some(mut bucket), rate) => {
+                bucket.set_limits(rate, limits.burst, now);
+                Some(bucket)
+            }
+            (None, rate) => Some(TokenBucket::new(rate, limits.burst, now)),
+        };
+
+        if limits.destination_rate == 0 {
+            self.destinations.clear();
+        }
+        for bucket in self.destinations.values_mut() {
+            bucket.set_limits(limits.destination_rate, limits.destination_burst, now);
+        }
+
+        self.limits = limits.clone();
+    }
+
+    /// Take the tokens for `count` fakes to the destination, returning `false` without taking
+    /// any if either bucket is short of them.
+    pub fn allow(&mut self, destination: IpAddr, count: u32, now: Instant) -> bool {
+        let limits = &self.limits;
+        let mut bucket = (limits.destination_rate > 0).then(|| {
+            self.destinations.entry(destination).or_insert_with(|| {
+                TokenBucket::new(limits.destination_rate, limits.destination_burst, now)
+            })
+        });
+
+        let allowed = bucket.as_mut().is_none_or(|bucket| bucket.has(count, now))
+            && self
+                .global
+                .as_mut()
+                .is_none_or(|global| global.try_take(count, now));
+
+        if allowed && let Some(bucket) = bucket {
+            bucket.try_take(count, now);
+        }
+
+        allowed
+    }
+
+    /// Forget the buckets of destinations that refilled.
+    pub fn expire(&mut self, now: Instant) {
+        self.destinations.retain(|_, bucket| !bucket.is_full(now));
+    }
+}
@@ -0,0 +1,206 @@
+use std::{cmp::Ordering, fmt, iter::once, str::FromStr};
+
+use chrono::{Datelike, Days, Local, NaiveDateTime, TimeDelta, Timelike, Weekday};
+use color_eyre::{Result, eyre::bail};
+use serde::{Deserialize, Serialize};
+
+use crate::settings::Settings;
+
+/// Minutes in a day.
+const DAY_MINUTES: u16 = 24 * 60;
+
+/// Source of the local wall-clock time, replaceable to test schedules.
+pub trait Clock {
+    fn now(&self) -> NaiveDateTime;
+}
+
+/// The local time of the system.
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> NaiveDateTime {
+        Local::now().naive_local()
+    }
+}
+
+/// A time window in which a profile replaces the selected one.
+#[derive(Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ScheduleEntry {
+    /// Days the window starts on, every day if empty.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub days: Vec<Weekday>,
+    /// Start of the window, inclusive.
+    pub start: TimeOfDay,
+    /// End of the window, exclusive. A window ending before it starts ends on the next day, one
+    /// ending when it starts lasts the whole day.
+    pub end: TimeOfDay,
+    pub profile: String,
+}
+
+/// A time of day with minute precision, written as `HH:MM`. `24:00` is the end of the day.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct TimeOfDay {
+    minutes: u16,
+}
+
+impl TimeOfDay {
+    pub fn new(hour: u16, minute: u16) -> Result<Self> {
+        let minutes = u32::from(hour) * 60 + u32::from(minute);
+
+        if minute >= 60 || minutes > u32::from(DAY_MINUTES) {
+            bail!("Invalid time of day {hour:02}:{minute:02}");
+        }
+
+        Ok(Self {
+            minutes: minutes as u16,
+        })
+    }
+
+    fn of(time: &NaiveDateTime) -> Self {
+        Self {
+            minutes: (time.hour() * 60 + time.minute()) as u16,
+        }
+    }
+}
+
+impl FromStr for TimeOfDay {
+    type Err = color_eyre::Report;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let Some((hour, minute)) = s.split_once(':') else {
+            bail!("Invalid time of day {s:?}, expected HH:MM");
+        };
+
+        match (hour.parse(), minute.parse()) {
+            (Ok(hour), Ok(minute)) => Self::new(hour, minute),
+            _ => bail!("Invalid time of day {s:?}, expected HH:MM"),
+        }
+    }
+}
+
+impl TryFrom<String> for TimeOfDay {
+    type Error = color_eyre::Report;
+
+    fn try_from(s: String) -> Result<Self> {
+        s.parse()
+    }
+}
+
+impl From<TimeOfDay> for String {
+    fn from(time: TimeOfDay) -> Self {
+        time.to_string()
+    }
+}
+
+impl fmt::Display for TimeOfDay {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:02}:{:02}", self.minutes / 60, self.minutes % 60)
+    }
+}
+
+impl ScheduleEntry {
+    /// Check if the window covers the time.
+    fn covers(&self, time: &NaiveDateTime) -> bool {
+        let day = time.weekday();
+        let minute = TimeOfDay::of(time);
+        let on = |day: Weekday| self.days.is_empty() || self.days.contains(&day);
+
+        match self.start.cmp(&self.end) {
+            Ordering::Less => on(day) && self.start <= minute && minute < self.end,
+            Ordering::Greater => {
+                (on(day) && minute >= self.start) || (on(day.pred()) && minute < self.end)
+            }
+            Ordering::Equal => on(day),
+        }
+    }
+}
+
+/// Chooses the profile in use from the schedule and the selected profile.
+#[derive(Clone)]
+pub struct Schedule {
+    entries: Vec<ScheduleEntry>,
+    /// Profile used outside of all windows.
+    default: String,
+}
+
+/// The profile in use and when it changes next.
+pub struct ScheduleStatus {
+    pub profile: String,
+    pub next: Option<(NaiveDateTime, String)>,
+}
+
+impl Schedule {
+    pub fn new(settings: &Settings) -> Self {
+        Self {
+            entries: settings.schedule.clone(),
+            default: settings.active_profile.clone(),
+        }
+    }
+
+    /// The profile in use at the time: the one of the first window covering it, otherwise
+    /// the selected one.
+    pub fn profile_at(&self, time: &NaiveDateTime) -> &str {
+        self.entries
+            .iter()
+            .find(|entry| entry.covers(time))
+            .map_or(&self.default, |entry| &entry.profile)
+    }
+
+    /// When the profile in use changes next and to which one, looking a week ahead.
+    pub fn next_change(&self, time: &NaiveDateTime) -> Option<(NaiveDateTime, &str)> {
+        let current = self.profile_at(time);
+
+        // the profile can only change where a window starts or ends
+        let mut boundaries = (0..=7)
+            .filter_map(|days| time.date().checked_add_days(Days::new(days)))
+            .flat_map(|date| {
+                self.entries.iter().flat_map(move |entry| {
+                    [entry.start, entry.end].map(|boundary| {
+                        date.and_time(Default::default())
+                            + TimeDelta::minutes(boundary.minutes.into())
+                    })
+                })
+            })
+            .filter(|boundary| boundary > time)
+            .collect::<Vec<_>>();
+
+        boundaries.sort_unstable();
+        boundaries.dedup();
+
+        boundaries.into_iter().find_map(|boundary| {
+            let profile = self.profile_at(&boundary);
+            (profile != current).then_some((boundary, profile))
+        })
+    }
+
+    pub fn status(&self, clock: &dyn Clock) -> ScheduleStatus {
+        let now = clock.now();
+
+        ScheduleStatus {
+            profile: self.profile_at(&now).to_owned(),
+            next: self
+                .next_change(&now)
+                .map(|(time, profile)| (time, profile.to_owned())),
+        }
+    }
+
+    /// Names of the profiles the schedule can switch to, including the selected one.
+    pub fn profiles(&self) -> impl Iterator<Item = &str> {
+        once(self.default.as_str()).chain(self.entries.iter().map(|entry| entry.profile.as_str()))
+    }
+}
+
+impl fmt::Display for ScheduleStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.profile)?;
+
+        match &self.next {
+            Some((time, profile)) => {
+                write!(f, ", next change: {profile} at {}", time.format("%a %H:%M"))
+            }
+            None => write!(f, ", no scheduled change"),
+        }
+    }
+}
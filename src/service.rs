@@ -8,19 +8,24 @@ use std::{
 
 use color_eyre::Result;
 use log::{error, info};
+use packetmock::stats::STATS;
 use smol::{block_on, future::or, unblock};
 use windows_service::{
     Error as WSError, define_windows_service,
     service::{
         ServiceAccess, ServiceControl, ServiceControlAccept, ServiceErrorControl, ServiceExitCode,
         ServiceInfo, ServiceStartType, ServiceState as WSServiceState, ServiceStatus, ServiceType,
+        UserEventCode,
     },
     service_control_handler::{self, ServiceControlHandlerResult},
     service_dispatcher,
     service_manager::{ServiceManager, ServiceManagerAccess},
 };
 
-use crate::{tasksch::Scheduler, windivert::intercept};
+use crate::{
+    tasksch::Scheduler,
+    windivert::{intercept, profile::get_schedule_status},
+};
 
 /// Name of the Windows service.
 #[cfg(not(debug_assertions))]
@@ -31,16 +36,30 @@ const SERVICE_NAME: &str = "PacketmockDevSrv";
 const SERVICE_DISPLAY_NAME: &str = "Packetmock Service";
 /// Type of the Windows service.
 const SERVICE_TYPE: ServiceType = ServiceType::OWN_PROCESS;
+/// User-defined control code asking the service to reload its settings.
+const RELOAD_CONTROL: u32 = 128;
 
 define_windows_service!(ffi_service_main, service_main);
 
-/// Run the service if the program was started with the "run-service" argument.
+/// Run the service if the program was started with the "run-service" argument, ask the
+/// running service to reload its settings with the "reload" argument, or print the state of
+/// the service and the scheduled profile with the "status" argument.
 pub fn handle_service() -> Result<()> {
     let args = args_os().skip(1).take(1).collect::<Vec<_>>();
     if args.first().is_some_and(|arg| arg == "run-service") {
         service_dispatcher::start(SERVICE_NAME, ffi_service_main)?;
         exit(0);
     }
+    if args.first().is_some_and(|arg| arg == "reload") {
+        reload_service()?;
+        info!("Asked the service to reload its settings");
+        exit(0);
+    }
+    if args.first().is_some_and(|arg| arg == "status") {
+        info!("Service: {:?}", query_service()?);
+        info!("Profile: {}", get_schedule_status());
+        exit(0);
+    }
     Ok(())
 }
 
@@ -55,6 +74,7 @@ fn service_main(_: Vec<OsString>) {
 /// Main logic for running the Windows service.
 fn run_service() -> Result<()> {
     let (shudown_tx, shutdown_rx) = mpsc::channel();
+    let (reload_tx, reload_rx) = mpsc::channel();
 
     let event_handler = move |control_event| -> ServiceControlHandlerResult {
         match control_event {
@@ -66,6 +86,13 @@ fn run_service() -> Result<()> {
                 }
                 ServiceControlHandlerResult::NoError
             }
+            ServiceControl::UserEvent(code) if code.to_raw() == RELOAD_CONTROL => {
+                if let Err(e) = reload_tx.send(()) {
+                    error!("Failed to send reload signal: {e:?}");
+                    return ServiceControlHandlerResult::NotImplemented;
+                }
+                ServiceControlHandlerResult::NoError
+            }
             ServiceControl::Interrogate => ServiceControlHandlerResult::NoError,
             _ => ServiceControlHandlerResult::NotImplemented,
         }
@@ -91,7 +118,7 @@ fn run_service() -> Result<()> {
         Ok(())
     };
 
-    block_on(or(unblock(shutdown), unblock(intercept)))?;
+    block_on(or(unblock(shutdown), unblock(move || intercept(reload_rx))))?;
 
     status_handle.set_service_status(ServiceStatus {
         service_type: SERVICE_TYPE,
@@ -103,7 +130,7 @@ fn run_service() -> Result<()> {
         process_id: None,
     })?;
 
-    info!("Service has stopped");
+    info!("Service has stopped ({STATS})");
 
     Ok(())
 }
@@ -168,6 +195,16 @@ pub fn stop_service() -> Result<()> {
     Ok(())
 }
 
+/// Ask the running Windows service to reload its settings
+pub fn reload_service() -> Result<()> {
+    let manager = ServiceManager::local_computer(None::<&str>, ServiceManagerAccess::CONNECT)?;
+    let service = manager.open_service(SERVICE_NAME, ServiceAccess::USER_DEFINED_CONTROL)?;
+
+    service.notify(UserEventCode::from_raw(RELOAD_CONTROL)?)?;
+
+    Ok(())
+}
+
 pub fn query_service() -> Result<ServiceState> {
     let manager = ServiceManager::local_computer(None::<&str>, ServiceManagerAccess::CONNECT)?;
 
@@ -187,7 +224,7 @@ pub fn query_service() -> Result<ServiceState> {
 }
 
 /// Represents the state of the Windows service.
-#[derive(Clone, Copy, PartialEq, Eq)]
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
 pub enum ServiceState {
     NotInstalled,
     Stopped,
@@ -0,0 +1,693 @@
+//! Typed settings and the stores they are loaded from.
+//!
+//! The settings are read from a TOML file (`packetmock.toml` next to the executable, or the
+//! path in `PACKETMOCK_CONFIG`). On Windows, the registry is used instead when there is no
+//! configuration file.
+
+mod file;
+#[cfg(windows)]
+mod registry;
+mod watch;
+
+use std::{
+    collections::BTreeMap,
+    env::{current_exe, var_os},
+    fmt,
+    net::IpAddr,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use color_eyre::{
+    Result,
+    eyre::{ContextCompat, bail},
+};
+use log::LevelFilter;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    autohostlist::AUTO_HOSTLIST_FILE,
+    cidr::{CIDR_EXCLUDE_FILE, CIDR_FILE, Cidr, DEFAULT_EXCLUDE},
+    classify::{Classifier, Detected, detect},
+    dns::{guard::DEFAULT_BOGONS, resolver::Upstream},
+    hostlist::{HOSTLIST_EXCLUDE_FILE, HOSTLIST_FILE},
+    profile::{DEFAULT_PROFILE, Profile},
+    schedule::ScheduleEntry,
+    state::STATE_FILE,
+    strategy::{EchPolicy, RetransmitAction, SniCase, Step},
+};
+
+#[cfg(windows)]
+pub use self::registry::{REGISTRY_NAME, RegistryStore};
+pub use self::{file::FileStore, watch::SettingsWatcher};
+
+/// File name of the configuration file.
+pub const CONFIG_FILE: &str = "packetmock.toml";
+/// Environment variable overriding the path of the configuration file.
+pub const CONFIG_ENV: &str = "PACKETMOCK_CONFIG";
+
+/// All user-configurable settings.
+#[derive(Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Settings {
+    /// Show the tray icon when the user logs in.
+    pub run_tray_on_startup: bool,
+    /// Name of the profile in use.
+    pub active_profile: String,
+    pub ports: Ports,
+    pub addresses: Addresses,
+    pub strategies: Strategies,
+    pub lists: Lists,
+    pub logging: Logging,
+    pub conntrack: Conntrack,
+    pub blocking: Blocking,
+    pub adaptive: Adaptive,
+    pub retransmission: Retransmission,
+    pub injection: Injection,
+    pub dns: Dns,
+    pub dns_guard: DnsGuard,
+    /// Profiles by name. Without a `default` profile, one is derived from `strategies`.
+    pub profiles: BTreeMap<String, Profile>,
+    /// Time windows replacing the active profile, the first one covering the time wins.
+    pub schedule: Vec<ScheduleEntry>,
+}
+
+/// Destination ports of the intercepted flows, by how their protocol is determined.
+#[derive(Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Ports {
+    /// Ports whose flows are always HTTP.
+    pub http: Vec<u16>,
+    /// Ports whose flows are always TLS.
+    pub tls: Vec<u16>,
+    /// Ports whose protocol is detected from the first payload, e.g. 8080 or 8443.
+    pub auto: Vec<u16>,
+    /// Detect the protocol of flows to every other port as well.
+    pub any: bool,
+}
+
+/// Destination networks, in addition to the ones in the network list files.
+#[derive(Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Addresses {
+    /// Networks to desync. Every destination is desynced if there are none.
+    pub include: Vec<Cidr>,
+    /// Networks never to desync.
+    pub exclude: Vec<Cidr>,
+}
+
+/// How desynced flows are modified.
+#[derive(Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Strategies {
+    /// TTL of the fake packets, low enough for them to expire before reaching the server.
+    pub ttl: u8,
+    pub ech: EchPolicy,
+    /// Offset in the fake ClientHello the server name is moved to with a padding extension.
+    pub pad_sni_offset: Option<usize>,
+    /// Offset in the payload at which the ClientHello is split into two segments.
+    pub split_position: Option<usize>,
+    pub sni_case: Option<SniCase>,
+}
+
+/// Hostlist and network list files. Relative paths are resolved against the directory of the settings store.
+#[derive(Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Lists {
+    /// Domains to desync. Every domain is desynced if the file doesn't exist.
+    pub hostlist: PathBuf,
+    /// Domains never to desync.
+    pub exclude: PathBuf,
+    /// Networks to desync, added to `addresses.include`.
+    pub cidr: PathBuf,
+    /// Networks never to desync, added to `addresses.exclude`.
+    pub cidr_exclude: PathBuf,
+    /// Domains learned to look blocked, desynced like the ones of `hostlist`.
+    pub auto_hostlist: PathBuf,
+    /// State learned while intercepting, e.g. the chains chosen for the domains by `adaptive`.
+    pub state: PathBuf,
+}
+
+#[derive(Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Logging {
+    /// Log level, overridden by `RUST_LOG`.
+    pub level: LevelFilter,
+}
+
+/// Limits of the table of tracked TCP flows.
+#[derive(Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Conntrack {
+    /// Most flows tracked at once, the least recently seen one is evicted beyond that.
+    pub max_flows: usize,
+    /// Seconds after which an idle flow is forgotten.
+    pub idle_timeout: u64,
+    /// Seconds a flow is kept after a FIN.
+    pub close_timeout: u64,
+}
+
+/// Detection of blocked domains from the outcome of flows that weren't desynced.
+#[derive(Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Blocking {
+    /// Add domains that look blocked to `lists.auto_hostlist`.
+    pub auto_hostlist: bool,
+    /// Failed flows to a domain before it is added.
+    pub fail_threshold: u32,
+    /// Seconds in which the failures must happen.
+    pub fail_window: u64,
+    /// Retransmissions of the first request without a response that count as a failure.
+    pub retransmissions: u32,
+}
+
+/// Chains tried in turn for domains that stay blocked with the chain of their profile.
+#[derive(Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Adaptive {
+    /// Candidates in the order they are tried, none to always use the chain of the profile.
+    /// A candidate is left after `blocking.fail_threshold` blocked flows in a row.
+    pub candidates: Vec<Candidate>,
+    /// Seconds a decision is kept after it last changed, 0 to keep it forever.
+    pub decision_ttl: u64,
+}
+
+#[derive(Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Candidate {
+    pub chain: Vec<Step>,
+}
+
+/// Handling of the retransmissions of a desynced first payload, detected by their sequence
+/// number.
+#[derive(Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Retransmission {
+    pub action: RetransmitAction,
+    /// Chain applied to the retransmissions with the `escalate` action.
+    pub escalation: Vec<Step>,
+}
+
+/// Limits on the fakes injected, protecting the network from a client that opens flows in a
+/// loop. Flows over a limit are sent without desync.
+#[derive(Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Injection {
+    /// Fakes injected per second to all destinations together, 0 for no limit.
+    pub rate: u32,
+    /// Fakes that can be injected at once before the rate applies.
+    pub burst: u32,
+    /// Fakes injected per second to a single destination address, 0 for no limit.
+    pub destination_rate: u32,
+    /// Fakes that can be injected at once to a destination before its rate applies.
+    pub destination_burst: u32,
+}
+
+/// Resolving the DNS queries of the machine through an encrypted resolver, out of reach of
+/// DNS poisoning.
+#[derive(Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Dns {
+    pub enabled: bool,
+    /// URL of the resolver: `https://host[:port]/path` for DNS-over-HTTPS, `tls://host[:port]`
+    /// for DNS-over-TLS.
+    pub resolver: String,
+    /// Address of the resolver, needed if its URL has a host name.
+    pub address: Option<IpAddr>,
+    /// Answers kept for their TTL, 0 to disable the cache.
+    pub cache_size: usize,
+    /// Seconds to wait for the resolver.
+    pub timeout: u64,
+}
+
+/// Dropping forged answers to plain DNS queries, raced ahead of the real ones by censors.
+#[derive(Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DnsGuard {
+    pub enabled: bool,
+    /// Networks no genuine answer points to, e.g. the blockpage addresses of an ISP.
+    pub bogons: Vec<Cidr>,
+    /// Hops the IP TTL of an answer may differ from the usual one of its server.
+    pub ttl_tolerance: u8,
+    /// Percentage of the fastest round trip to a server under which its answers are forged.
+    pub min_rtt_percent: u32,
+    /// Seconds a query waits for its genuine answer.
+    pub timeout: u64,
+}
+
+impl Settings {
+    /// Check the values that deserialize fine but can't be used.
+    pub fn validate(&self) -> Result<()> {
+        let problems = self.problems();
+
+        if !problems.is_empty() {
+            let problems = problems.iter().map(Problem::to_string).collect::<Vec<_>>();
+            bail!("Invalid settings: {}", problems.join("; "));
+        }
+
+        Ok(())
+    }
+
+    /// All the values that deserialize fine but can't be used.
+    pub fn problems(&self) -> Vec<Problem> {
+        let mut problems = Vec::new();
+        let mut problem = |key: String, message: String| problems.push(Problem { key, message });
+
+        if self.strategies.ttl == 0 {
+            problem("strategies.ttl".into(), "TTL must be at least 1".into());
+        }
+        if self.conntrack.max_flows == 0 {
+            problem(
+                "conntrack.max_flows".into(),
+                "At least one flow must be tracked".into(),
+            );
+        }
+        if self.blocking.fail_threshold == 0 {
+            problem(
+                "blocking.fail_threshold".into(),
+                "At least one failure must be required".into(),
+            );
+        }
+        if self.blocking.retransmissions == 0 {
+            problem(
+                "blocking.retransmissions".into(),
+                "At least one retransmission must be required".into(),
+            );
+        }
+        if self.injection.rate > 0 && self.injection.burst == 0 {
+            problem(
+                "injection.burst".into(),
+                "The burst must be at least 1 with a rate".into(),
+            );
+        }
+        if self.injection.destination_rate > 0 && self.injection.destination_burst == 0 {
+            problem(
+                "injection.destination_burst".into(),
+                "The burst must be at least 1 with a rate".into(),
+            );
+        }
+        if self.dns.enabled {
+            match Upstream::parse(&self.dns.resolver) {
+                Ok(upstream) if upstream.address(self.dns.address).is_none() => problem(
+                    "dns.address".into(),
+                    format!("The address of {} must be set", upstream.host),
+                ),
+                Ok(_) => {}
+                Err(e) => problem("dns.resolver".into(), e.to_string()),
+            }
+            if self.dns.timeout == 0 {
+                problem(
+                    "dns.timeout".into(),
+                    "The timeout must be at least 1 second".into(),
+                );
+            }
+        }
+        if self.dns_guard.enabled && self.dns_guard.timeout == 0 {
+            problem(
+                "dns_guard.timeout".into(),
+                "The timeout must be at least 1 second".into(),
+            );
+        }
+        if self.ports.classifiers().next().is_none() && !self.ports.any {
+            problem(
+                "ports".into(),
+                "At least one port must be configured".into(),
+            );
+        }
+        for &port in &self.ports.tls {
+            if self.ports.http.contains(&port) {
+                problem(
+                    "ports.tls".into(),
+                    format!("Port {port} is also an HTTP port"),
+                );
+            }
+        }
+        for &port in &self.ports.auto {
+            if self.ports.http.contains(&port) || self.ports.tls.contains(&port) {
+                problem(
+                    "ports.auto".into(),
+                    format!("Port {port} is already an HTTP or TLS port"),
+                );
+            }
+        }
+
+        if self.profile(&self.active_profile).is_none() {
+            let name = &self.active_profile;
+            problem(
+                "active_profile".into(),
+                format!("Profile {name:?} doesn't exist"),
+            );
+        }
+        for (index, entry) in self.schedule.iter().enumerate() {
+            if self.profile(&entry.profile).is_none() {
+                let name = &entry.profile;
+                problem(
+                    format!("schedule[{index}].profile"),
+                    format!("Profile {name:?} doesn't exist"),
+                );
+            }
+        }
+
+        let mut chains = Vec::new();
+        for (name, profile) in &self.profiles {
+            for (index, rule) in profile.rules.iter().enumerate() {
+                chains.push((format!("profiles.{name}.rules[{index}].chain"), &rule.chain));
+            }
+        }
+        if self.retransmission.action == RetransmitAction::Escalate {
+            if self.retransmission.escalation.is_empty() {
+                problem(
+                    "retransmission.escalation".into(),
+                    "Escalating needs at least one step".into(),
+                );
+            }
+            chains.push((
+                "retransmission.escalation".into(),
+                &self.retransmission.escalation,
+            ));
+        }
+        for (index, candidate) in self.adaptive.candidates.iter().enumerate() {
+            let key = format!("adaptive.candidates[{index}].chain");
+            if candidate.chain.is_empty() {
+                problem(key.clone(), "A candidate needs at least one step".into());
+            }
+            chains.push((key, &candidate.chain));
+        }
+
+        for (key, chain) in chains {
+            for (step_index, step) in chain.iter().enumerate() {
+                if let Step::Fake { ttl: Some(0) } = step {
+                    problem(
+                        format!("{key}[{step_index}].ttl"),
+                        "TTL must be at least 1".into(),
+                    );
+                }
+            }
+        }
+
+        problems
+    }
+
+    /// Whether the outcome of flows is observed, which needs their inbound payloads.
+    pub fn observes_outcomes(&self) -> bool {
+        self.blocking.auto_hostlist || !self.adaptive.candidates.is_empty()
+    }
+
+    /// The candidate chains of `adaptive`, in order.
+    pub fn candidate_chains(&self) -> Vec<Vec<Step>> {
+        self.adaptive
+            .candidates
+            .iter()
+            .map(|candidate| candidate.chain.clone())
+            .collect()
+    }
+
+    /// The profile with the given name, `None` if it doesn't exist.
+    pub fn profile(&self, name: &str) -> Option<Profile> {
+        match self.profiles.get(name) {
+            Some(profile) => Some(profile.clone()),
+            None if name == DEFAULT_PROFILE => Some(Profile::from_strategies(&self.strategies)),
+            None => None,
+        }
+    }
+
+    /// Names of the profiles that can be activated.
+    pub fn profile_names(&self) -> Vec<&str> {
+        let mut names = self.profiles.keys().map(String::as_str).collect::<Vec<_>>();
+
+        if !self.profiles.contains_key(DEFAULT_PROFILE) {
+            names.insert(0, DEFAULT_PROFILE);
+        }
+
+        names
+    }
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self {
+            run_tray_on_startup: true,
+            active_profile: DEFAULT_PROFILE.to_owned(),
+            ports: Ports::default(),
+            addresses: Addresses::default(),
+            strategies: Strategies::default(),
+            lists: Lists::default(),
+            logging: Logging::default(),
+            conntrack: Conntrack::default(),
+            blocking: Blocking::default(),
+            adaptive: Adaptive::default(),
+            retransmission: Retransmission::default(),
+            injection: Injection::default(),
+            dns: Dns::default(),
+            dns_guard: DnsGuard::default(),
+            profiles: BTreeMap::new(),
+            schedule: Vec::new(),
+        }
+    }
+}
+
+impl Ports {
+    /// How the protocol of the flows to the port is determined, `None` if it isn't
+    /// intercepted.
+    pub fn classifier(&self, port: u16) -> Option<Classifier> {
+        self.classifiers()
+            .find(|&(listed, _)| listed == port)
+            .map(|(_, classifier)| classifier)
+    }
+
+    /// The protocol of a payload sent to the port.
+    pub fn classify(&self, port: u16, data: &[u8]) -> Detected {
+        match self.classifier(port) {
+            Some(classifier) => classifier.classify(data),
+            None if self.any => detect(data),
+            None => Detected::Unknown,
+        }
+    }
+
+    /// Every listed port with its classifier.
+    pub fn classifiers(&self) -> impl Iterator<Item = (u16, Classifier)> {
+        let http = self.http.iter().map(|&port| (port, Classifier::Http));
+        let tls = self.tls.iter().map(|&port| (port, Classifier::Tls));
+        let auto = self.auto.iter().map(|&port| (port, Classifier::Auto));
+
+        http.chain(tls).chain(auto)
+    }
+}
+
+impl Default for Ports {
+    fn default() -> Self {
+        Self {
+            http: Vec::new(),
+            tls: Vec::new(),
+            auto: vec![80, 443],
+            any: false,
+        }
+    }
+}
+
+impl Default for Addresses {
+    fn default() -> Self {
+        Self {
+            include: Vec::new(),
+            exclude: DEFAULT_EXCLUDE
+                .iter()
+                .map(|cidr| cidr.parse().expect("Default networks are valid"))
+                .collect(),
+        }
+    }
+}
+
+impl Default for Strategies {
+    fn default() -> Self {
+        Self {
+            ttl: 4,
+            ech: EchPolicy::OuterSni,
+            pad_sni_offset: None,
+            split_position: None,
+            sni_case: None,
+        }
+    }
+}
+
+impl Default for Lists {
+    fn default() -> Self {
+        Self {
+            hostlist: HOSTLIST_FILE.into(),
+            exclude: HOSTLIST_EXCLUDE_FILE.into(),
+            cidr: CIDR_FILE.into(),
+            cidr_exclude: CIDR_EXCLUDE_FILE.into(),
+            auto_hostlist: AUTO_HOSTLIST_FILE.into(),
+            state: STATE_FILE.into(),
+        }
+    }
+}
+
+impl Default for Conntrack {
+    fn default() -> Self {
+        Self {
+            max_flows: 65536,
+            idle_timeout: 300,
+            close_timeout: 10,
+        }
+    }
+}
+
+impl Default for Blocking {
+    fn default() -> Self {
+        Self {
+            auto_hostlist: false,
+            fail_threshold: 3,
+            fail_window: 60,
+            retransmissions: 3,
+        }
+    }
+}
+
+impl Adaptive {
+    /// How long a decision is kept, forever if `None`.
+    pub fn ttl(&self) -> Option<Duration> {
+        (self.decision_ttl > 0).then(|| Duration::from_secs(self.decision_ttl))
+    }
+}
+
+impl Default for Adaptive {
+    fn default() -> Self {
+        Self {
+            candidates: Vec::new(),
+            decision_ttl: 30 * 24 * 60 * 60,
+        }
+    }
+}
+
+impl Default for Injection {
+    fn default() -> Self {
+        Self {
+            rate: 1000,
+            burst: 2000,
+            destination_rate: 50,
+            destination_burst: 100,
+        }
+    }
+}
+
+impl Dns {
+    #[inline]
+    pub fn timeout(&self) -> Duration {
+        Duration::from_secs(self.timeout)
+    }
+}
+
+impl Default for Dns {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            resolver: "https://1.1.1.1/dns-query".to_owned(),
+            address: None,
+            cache_size: 4096,
+            timeout: 5,
+        }
+    }
+}
+
+impl DnsGuard {
+    #[inline]
+    pub fn timeout(&self) -> Duration {
+        Duration::from_secs(self.timeout)
+    }
+}
+
+impl Default for DnsGuard {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            bogons: DEFAULT_BOGONS
+                .iter()
+                .map(|cidr| cidr.parse().expect("Default bogons are valid"))
+                .collect(),
+            ttl_tolerance: 2,
+            min_rtt_percent: 50,
+            timeout: 5,
+        }
+    }
+}
+
+impl Default for Retransmission {
+    fn default() -> Self {
+        Self {
+            action: RetransmitAction::Repeat,
+            escalation: vec![
+                Step::Fake { ttl: None },
+                Step::Fake { ttl: None },
+                Step::Split { position: 1 },
+            ],
+        }
+    }
+}
+
+impl Default for Logging {
+    fn default() -> Self {
+        Self {
+            level: LevelFilter::Info,
+        }
+    }
+}
+
+/// A value of the settings that can't be used.
+pub struct Problem {
+    /// Dotted path of the value, e.g. `profiles.work.rules[0].chain[1].ttl`.
+    pub key: String,
+    pub message: String,
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.key, self.message)
+    }
+}
+
+/// A backend the settings are loaded from and saved to.
+pub trait SettingsStore: Send {
+    /// Load the settings, falling back to the defaults for missing values.
+    fn load(&self) -> Result<Settings>;
+
+    /// Save the settings, replacing the stored ones.
+    fn save(&self, settings: &Settings) -> Result<()>;
+
+    /// Directory that relative paths in the settings are resolved against.
+    fn dir(&self) -> &Path;
+
+    /// Resolve a path from the settings against the directory of the store.
+    fn resolve(&self, path: &Path) -> PathBuf {
+        self.dir().join(path)
+    }
+
+    /// Where the value at the dotted path is stored, for error messages.
+    fn locate(&self, key: &str) -> String {
+        key.to_owned()
+    }
+}
+
+/// Path of the configuration file, whether it exists or not.
+pub fn config_path() -> Result<PathBuf> {
+    if let Some(path) = var_os(CONFIG_ENV) {
+        return Ok(path.into());
+    }
+
+    let exe = current_exe()?;
+    let dir = exe.parent().context("Executable has no parent directory")?;
+
+    Ok(dir.join(CONFIG_FILE))
+}
+
+/// Open the store the settings are kept in: the configuration file if it exists, otherwise
+/// the registry on Windows.
+pub fn open_store() -> Result<Box<dyn SettingsStore>> {
+    let path = config_path()?;
+
+    #[cfg(windows)]
+    if !path.exists() {
+        return Ok(Box::new(RegistryStore::new()?));
+    }
+
+    Ok(Box::new(FileStore::new(path)?))
+}
@@ -0,0 +1,177 @@
+use std::{
+    fs,
+    io::ErrorKind,
+    path::{Path, PathBuf},
+};
+
+use color_eyre::{
+    Result,
+    eyre::{Context, ContextCompat},
+};
+use toml::de::DeTable;
+use toml_edit::{DocumentMut, Item, Table, TableLike};
+
+use super::{Settings, SettingsStore};
+use crate::state::replace_file;
+
+/// Settings kept in a TOML configuration file.
+pub struct FileStore {
+    path: PathBuf,
+    dir: PathBuf,
+}
+
+impl FileStore {
+    /// Create a store for the configuration file at the given path.
+    pub fn new(path: PathBuf) -> Result<Self> {
+        let dir = path
+            .parent()
+            .context("Configuration file has no parent directory")?
+            .to_owned();
+
+        Ok(Self { path, dir })
+    }
+
+    /// Path of the configuration file.
+    #[inline]
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl SettingsStore for FileStore {
+    fn load(&self) -> Result<Settings> {
+        let text = match fs::read_to_string(&self.path) {
+            Ok(text) => text,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(Settings::default()),
+            Err(e) => {
+                return Err(e).wrap_err_with(|| format!("Failed to read {}", self.path.display()));
+            }
+        };
+
+        toml::from_str(&text)
+            .wrap_err_with(|| format!("Invalid configuration in {}", self.path.display()))
+    }
+
+    /// Save the settings, rewriting only the values that changed so the comments and the
+    /// order of the file are kept.
+    fn save(&self, settings: &Settings) -> Result<()> {
+        let text = match fs::read_to_string(&self.path) {
+            Ok(text) => text,
+            Err(e) if e.kind() == ErrorKind::NotFound => String::new(),
+            Err(e) => {
+                return Err(e).wrap_err_with(|| format!("Failed to read {}", self.path.display()));
+            }
+        };
+        let mut document = text
+            .parse::<DocumentMut>()
+            .wrap_err_with(|| format!("Invalid configuration in {}", self.path.display()))?;
+
+        let old = toml::Table::try_from(self.load()?)?;
+        let new = toml::Table::try_from(settings)?;
+        let formatted = toml::to_string_pretty(settings)?.parse::<DocumentMut>()?;
+        update(document.as_table_mut(), formatted.as_table(), &old, &new);
+
+        // the settings are reloaded when the file changes, they must never see a part of it
+        replace_file(&self.path, document.to_string().as_bytes())
+    }
+
+    #[inline]
+    fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    fn locate(&self, key: &str) -> String {
+        let line = fs::read_to_string(&self.path)
+            .ok()
+            .and_then(|text| key_line(&text, key));
+
+        match line {
+            Some(line) => format!("{}:{line}", self.path.display()),
+            None => format!("{} ({key})", self.path.display()),
+        }
+    }
+}
+
+/// Bring the values of the file that differ between the old and the new settings up to date,
+/// taking them from the formatted new settings. Tables are updated key by key.
+fn update(
+    file: &mut dyn TableLike,
+    formatted: &dyn TableLike,
+    old: &toml::Table,
+    new: &toml::Table,
+) {
+    for (key, value) in new {
+        let old_value = old.get(key);
+        if old_value == Some(value) {
+            continue;
+        }
+        let Some(item) = formatted.get(key) else {
+            continue;
+        };
+
+        if let (toml::Value::Table(new), Some(toml::Value::Table(old))) = (value, old_value)
+            && let Some(formatted) = item.as_table_like()
+        {
+            if file.get(key).is_none() {
+                let mut table = Table::new();
+                table.set_implicit(true);
+                file.insert(key, Item::Table(table));
+            }
+            if let Some(file) = file.get_mut(key).and_then(Item::as_table_like_mut) {
+                update(file, formatted, old, new);
+                continue;
+            }
+        }
+
+        // a value replaced in place keeps the comments around it
+        if let Some(existing) = file.get_mut(key)
+            && let (Some(old), Some(new)) = (existing.as_value(), item.as_value())
+        {
+            let mut value = new.clone();
+            *value.decor_mut() = old.decor().clone();
+            *existing = Item::Value(value);
+            continue;
+        }
+
+        file.insert(key, item.clone());
+    }
+
+    for key in old.keys().filter(|key| !new.contains_key(*key)) {
+        file.remove(key);
+    }
+}
+
+/// 1-based line of the value at the dotted path, or of its closest parent written in the text.
+fn key_line(text: &str, key: &str) -> Option<usize> {
+    let root = DeTable::parse(text).ok()?;
+    let mut segments = key.split('.').flat_map(|part| {
+        let mut parts = part.split('[');
+        let name = parts.next().map(Segment::Key);
+        let indices = parts.filter_map(|index| index.trim_end_matches(']').parse().ok());
+
+        name.into_iter().chain(indices.map(Segment::Index))
+    });
+
+    let Some(Segment::Key(first)) = segments.next() else {
+        return None;
+    };
+    let mut value = root.get_ref().get(first)?;
+
+    for segment in segments {
+        let next = match segment {
+            Segment::Key(name) => value.get_ref().get(name),
+            Segment::Index(index) => value.get_ref().get(index),
+        };
+        match next {
+            Some(next) => value = next,
+            None => break,
+        }
+    }
+
+    Some(text[..value.span().start].matches('\n').count() + 1)
+}
+
+enum Segment<'a> {
+    Key(&'a str),
+    Index(usize),
+}
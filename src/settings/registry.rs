@@ -0,0 +1,148 @@
+use std::{
+    env::current_exe,
+    path::{Path, PathBuf},
+};
+
+use color_eyre::{
+    Result,
+    eyre::{ContextCompat, bail},
+};
+use windows_registry::{Key, LOCAL_MACHINE};
+
+use super::{Settings, SettingsStore};
+use crate::strategy::{EchPolicy, SniCase};
+
+/// Name of the key under `HKLM\Software` holding the settings.
+pub const REGISTRY_NAME: &str = "Packetmock";
+
+/// Settings kept as values under `HKLM\Software\Packetmock`.
+///
+/// Only scalar settings are stored, everything else keeps its default value, so the only
+/// profile is the one derived from the strategies. Relative paths
+/// are resolved against the directory of the executable.
+pub struct RegistryStore {
+    dir: PathBuf,
+}
+
+impl RegistryStore {
+    pub fn new() -> Result<Self> {
+        let exe = current_exe()?;
+        let dir = exe
+            .parent()
+            .context("Executable has no parent directory")?
+            .to_owned();
+
+        Ok(Self { dir })
+    }
+}
+
+impl SettingsStore for RegistryStore {
+    fn load(&self) -> Result<Settings> {
+        let mut settings = Settings::default();
+
+        let Ok(key) = LOCAL_MACHINE.open(format!("Software\\{REGISTRY_NAME}")) else {
+            return Ok(settings);
+        };
+
+        let strategies = &mut settings.strategies;
+
+        if let Some(run) = get_u32(&key, "RunTrayOnStartup") {
+            settings.run_tray_on_startup = run == 1;
+        }
+        if let Ok(profile) = key.get_string("ActiveProfile") {
+            settings.active_profile = profile;
+        }
+        if let Some(ttl) = get_u32(&key, "TTL") {
+            let Ok(ttl) = u8::try_from(ttl) else {
+                bail!("Registry value TTL is out of range: {ttl}");
+            };
+            strategies.ttl = ttl;
+        }
+        if let Some(ech) = get_u32(&key, "EchPolicy").and_then(ech_policy_from_u32) {
+            strategies.ech = ech;
+        }
+        // a missing or zero value disables the modification
+        strategies.pad_sni_offset = get_u32(&key, "PadSniOffset")
+            .filter(|&offset| offset != 0)
+            .map(|offset| offset as usize);
+        strategies.split_position = get_u32(&key, "SplitPosition")
+            .filter(|&position| position != 0)
+            .map(|position| position as usize);
+        strategies.sni_case = match get_u32(&key, "SniCase") {
+            Some(1) => Some(SniCase::Random),
+            Some(2) => Some(SniCase::Alternate),
+            _ => None,
+        };
+
+        Ok(settings)
+    }
+
+    fn save(&self, settings: &Settings) -> Result<()> {
+        let key = LOCAL_MACHINE.create(format!("Software\\{REGISTRY_NAME}"))?;
+        let strategies = &settings.strategies;
+
+        key.set_u32("RunTrayOnStartup", settings.run_tray_on_startup as u32)?;
+        key.set_string("ActiveProfile", &settings.active_profile)?;
+        key.set_u32("TTL", strategies.ttl as u32)?;
+        key.set_u32("EchPolicy", ech_policy_to_u32(strategies.ech))?;
+        key.set_u32(
+            "PadSniOffset",
+            strategies.pad_sni_offset.unwrap_or(0) as u32,
+        )?;
+        key.set_u32(
+            "SplitPosition",
+            strategies.split_position.unwrap_or(0) as u32,
+        )?;
+        key.set_u32(
+            "SniCase",
+            match strategies.sni_case {
+                None => 0,
+                Some(SniCase::Random) => 1,
+                Some(SniCase::Alternate) => 2,
+            },
+        )?;
+
+        Ok(())
+    }
+
+    #[inline]
+    fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    fn locate(&self, key: &str) -> String {
+        let value = match key {
+            "run_tray_on_startup" => "RunTrayOnStartup",
+            "active_profile" => "ActiveProfile",
+            "strategies.ttl" => "TTL",
+            "strategies.ech" => "EchPolicy",
+            "strategies.pad_sni_offset" => "PadSniOffset",
+            "strategies.split_position" => "SplitPosition",
+            "strategies.sni_case" => "SniCase",
+            _ => return format!("HKLM\\Software\\{REGISTRY_NAME} ({key})"),
+        };
+
+        format!("HKLM\\Software\\{REGISTRY_NAME}\\{value}")
+    }
+}
+
+fn get_u32(key: &Key, name: &str) -> Option<u32> {
+    key.get_u32(name).ok()
+}
+
+fn ech_policy_from_u32(value: u32) -> Option<EchPolicy> {
+    match value {
+        0 => Some(EchPolicy::OuterSni),
+        1 => Some(EchPolicy::Pass),
+        2 => Some(EchPolicy::Fake),
+        _ => None,
+    }
+}
+
+fn ech_policy_to_u32(policy: EchPolicy) -> u32 {
+    match policy {
+        EchPolicy::OuterSni => 0,
+        EchPolicy::Pass => 1,
+        EchPolicy::Fake => 2,
+    }
+}
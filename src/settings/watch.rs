@@ -0,0 +1,95 @@
+use std::{fs, time::SystemTime};
+
+use color_eyre::Result;
+
+use super::{Settings, SettingsStore};
+
+/// Detects changes to the settings and to the files they refer to.
+///
+/// Stores have no common change notification, so the watcher is polled: the settings are
+/// loaded again and compared with the last ones, and the modification times of the hostlists
+/// are checked.
+pub struct SettingsWatcher {
+    store: Box<dyn SettingsStore>,
+    /// The last settings that loaded successfully.
+    settings: Settings,
+    /// Whether the settings failed to load the last time.
+    failed: bool,
+    /// Modification times of the files referred to by the settings.
+    modified: Vec<Option<SystemTime>>,
+}
+
+impl SettingsWatcher {
+    /// Start watching from the settings currently in use.
+    pub fn new(store: Box<dyn SettingsStore>, settings: &Settings) -> Self {
+        let modified = modification_times(&*store, settings);
+
+        Self {
+            store,
+            settings: settings.clone(),
+            failed: false,
+            modified,
+        }
+    }
+
+    #[inline]
+    pub fn store(&self) -> &dyn SettingsStore {
+        &*self.store
+    }
+
+    /// Load the settings again if they or the files they refer to changed since the last call.
+    ///
+    /// A failure is reported once, not again until the settings change.
+    pub fn poll(&mut self) -> Option<Result<Settings>> {
+        let loaded = self.store.load();
+
+        let settings = loaded.as_ref().unwrap_or(&self.settings);
+        let modified = modification_times(&*self.store, settings);
+
+        let changed = match &loaded {
+            Ok(settings) => self.failed || *settings != self.settings,
+            Err(_) => !self.failed,
+        };
+
+        if !changed && modified == self.modified {
+            return None;
+        }
+
+        if let Ok(settings) = &loaded {
+            self.settings = settings.clone();
+        }
+        self.failed = loaded.is_err();
+        self.modified = modified;
+
+        Some(loaded)
+    }
+
+    /// Load the settings again, whether they changed or not.
+    pub fn reload(&mut self) -> Result<Settings> {
+        self.poll().unwrap_or_else(|| self.store.load())
+    }
+}
+
+/// Modification times of the files referred to by the settings, `None` for missing files.
+fn modification_times(store: &dyn SettingsStore, settings: &Settings) -> Vec<Option<SystemTime>> {
+    let lists = &settings.lists;
+    let profiles = settings
+        .profiles
+        .values()
+        .flat_map(|profile| &profile.rules);
+
+    [
+        &lists.hostlist,
+        &lists.exclude,
+        &lists.cidr,
+        &lists.cidr_exclude,
+    ]
+    .into_iter()
+    .chain(profiles.filter_map(|rule| rule.hostlist.as_ref()))
+    .map(|path| {
+        fs::metadata(store.resolve(path))
+            .and_then(|meta| meta.modified())
+            .ok()
+    })
+    .collect()
+}
@@ -0,0 +1,284 @@
+//! An in-process model of a censored path, for testing strategy chains without one.
+//!
+//! A client backend desyncs the first payload of a flow like the interceptor does and puts the
+//! resulting packets on the wire. Each packet travels as far as its TTL allows: past the DPI
+//! middlebox if it is at least the DPI distance, and to the server if it is at least the server
+//! distance. The DPI looks for a blocked host name in what it sees and answers for the server
+//! when it finds one, while the server answers whatever first message it reassembled. A
+//! ClientHello the server got other bytes of than the client sent fails the handshake, since
+//! the Finished messages are computed over the transcript.
+//!
+//! Both sides keep the first bytes they saw at each stream offset, so a fake sent ahead of the
+//! real payload takes its place for whoever receives it.
+
+use crate::{
+    blockcheck::{Verdict, judge, probe_payload},
+    http::{FAKE_CLIENT_HELLO, FAKE_HTTP_REQUEST, parse_http_requests, tls::parse_client_hello},
+    profile::Protocol,
+    strategy::{Step, desync},
+};
+
+/// TTL of the packets the client sends itself.
+pub const CLIENT_TTL: u8 = 64;
+
+/// Blockpage the DPI answers HTTP requests to blocked domains with.
+const BLOCKPAGE: &[u8] =
+    b"HTTP/1.1 302 Found\r\nLocation: http://blocked.example/\r\nContent-Length: 0\r\n\r\n";
+/// Start of the ServerHello record the server answers a ClientHello with.
+const SERVER_HELLO: &[u8] = &[0x16, 0x03, 0x03, 0x00, 0x04, 0x02, 0x00, 0x00, 0x00];
+/// Response the server answers an HTTP request with.
+const OK_RESPONSE: &[u8] = b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n";
+
+/// A TCP segment of the first payload of a flow.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct WirePacket {
+    pub ttl: u8,
+    /// Offset of the data into the stream.
+    pub offset: usize,
+    pub data: Vec<u8>,
+}
+
+/// The sending side, desyncing the first payload with a chain.
+pub struct ClientBackend {
+    /// TTL of fakes whose step doesn't set one, like `strategies.ttl`.
+    pub fake_ttl: u8,
+}
+
+impl ClientBackend {
+    /// The packets the first payload of the protocol for the domain is sent as, in order.
+    pub fn send(&self, domain: &str, protocol: Protocol, chain: &[Step]) -> Vec<WirePacket> {
+        let payload = probe_payload(domain, protocol).expect("The domain fits a ClientHello");
+
+        let fake = match protocol {
+            Protocol::Http => FAKE_HTTP_REQUEST,
+            Protocol::Tls => FAKE_CLIENT_HELLO,
+        };
+
+        let desync = desync(&payload, chain, fake);
+
+        let fakes = desync.fakes.iter().map(|ttl| WirePacket {
+            ttl: ttl.unwrap_or(self.fake_ttl),
+            offset: 0,
+            data: desync.fake.clone(),
+        });
+        let segments = desync.segments().map(|(offset, data)| WirePacket {
+            ttl: CLIENT_TTL,
+            offset,
+            data: data.to_vec(),
+        });
+
+        fakes.chain(segments).collect()
+    }
+}
+
+/// How much of a flow the DPI looks at to find the host name.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Inspection {
+    /// Only the first payload packet it sees, on its own.
+    PerPacket,
+    /// The stream reassembled from the start, until the first message is complete.
+    Reassembly,
+}
+
+/// What the DPI does to flows to blocked domains.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Action {
+    /// Drop the packet and reset the connection.
+    Reset,
+    /// Drop the packet and answer with a redirect to a blockpage.
+    Blockpage,
+}
+
+/// A DPI middlebox and the domains it blocks.
+#[derive(Clone, Debug)]
+pub struct Dpi {
+    /// Blocked domains, their subdomains are blocked as well.
+    pub blocked: Vec<String>,
+    pub inspection: Inspection,
+    /// Host names only match if their case matches, as with a byte comparison.
+    pub case_sensitive: bool,
+    /// Hops from the client to the DPI.
+    pub distance: u8,
+    pub action: Action,
+}
+
+impl Dpi {
+    /// Whether the host name is blocked.
+    fn blocks(&self, host: &str) -> bool {
+        let host = if self.case_sensitive {
+            host.to_owned()
+        } else {
+            host.to_ascii_lowercase()
+        };
+
+        self.blocked.iter().any(|domain| {
+            host == *domain
+                || host
+                    .strip_suffix(domain.as_str())
+                    .is_some_and(|rest| rest.ends_with('.'))
+        })
+    }
+}
+
+/// The state of a flow seen by the DPI.
+struct DpiFlow<'a> {
+    dpi: &'a Dpi,
+    protocol: Protocol,
+    stream: Stream,
+    /// Whether the DPI made up its mind about the flow.
+    decided: bool,
+}
+
+impl DpiFlow<'_> {
+    /// Inspect a packet, returning the answer injected in place of the server's if it is
+    /// blocked.
+    fn inspect(&mut self, packet: &WirePacket) -> Option<Action> {
+        if self.decided {
+            return None;
+        }
+
+        let first = match self.dpi.inspection {
+            Inspection::PerPacket => {
+                // segments not starting the stream hold no message to parse
+                self.decided = true;
+                if packet.offset != 0 {
+                    return None;
+                }
+                First::parse(self.protocol, &packet.data)
+            }
+            Inspection::Reassembly => {
+                self.stream.write(packet);
+                First::parse(self.protocol, &self.stream.contiguous())
+            }
+        };
+
+        match first {
+            First::Host(host) => {
+                self.decided = true;
+                self.dpi.blocks(&host).then_some(self.dpi.action)
+            }
+            First::Complete => {
+                self.decided = true;
+                None
+            }
+            First::Incomplete => None,
+        }
+    }
+}
+
+/// What can be told from the start of a stream.
+enum First {
+    /// The host name of the first message.
+    Host(String),
+    /// The first message is complete and names no host.
+    Complete,
+    Incomplete,
+}
+
+impl First {
+    fn parse(protocol: Protocol, data: &[u8]) -> Self {
+        let (host, complete) = match protocol {
+            Protocol::Http => match parse_http_requests(data).next() {
+                Some(request) => (request.hostname().map(str::to_owned), request.end.is_some()),
+                None => (None, false),
+            },
+            Protocol::Tls => match parse_client_hello(data) {
+                Some(hello) => (
+                    hello.server_name().map(str::to_owned),
+                    data.len() >= 5 + usize::from(u16::from_be_bytes([data[3], data[4]])),
+                ),
+                None => (None, false),
+            },
+        };
+
+        match (host, complete) {
+            (Some(host), _) => Self::Host(host),
+            (None, true) => Self::Complete,
+            (None, false) => Self::Incomplete,
+        }
+    }
+}
+
+/// A stream reassembled from packets, keeping the first byte received at each offset.
+#[derive(Default)]
+struct Stream {
+    bytes: Vec<Option<u8>>,
+}
+
+impl Stream {
+    fn write(&mut self, packet: &WirePacket) {
+        let end = packet.offset + packet.data.len();
+        if self.bytes.len() < end {
+            self.bytes.resize(end, None);
+        }
+
+        for (slot, &byte) in self.bytes[packet.offset..end].iter_mut().zip(&packet.data) {
+            slot.get_or_insert(byte);
+        }
+    }
+
+    /// The bytes received from the start up to the first gap.
+    fn contiguous(&self) -> Vec<u8> {
+        self.bytes.iter().map_while(|byte| *byte).collect()
+    }
+}
+
+/// The path from the client through the DPI to the server.
+pub struct Simulation {
+    pub client: ClientBackend,
+    pub dpi: Dpi,
+    /// Hops from the client to the server, more than to the DPI.
+    pub server_distance: u8,
+}
+
+impl Simulation {
+    /// Send the first payload of a flow to the domain desynced with the chain, judging the
+    /// answer the client gets like blockcheck does.
+    pub fn run(&self, domain: &str, protocol: Protocol, chain: &[Step]) -> Verdict {
+        let mut dpi = DpiFlow {
+            dpi: &self.dpi,
+            protocol,
+            stream: Stream::default(),
+            decided: false,
+        };
+        let mut server = Stream::default();
+        let payload = probe_payload(domain, protocol).expect("The domain fits a ClientHello");
+
+        for packet in self.client.send(domain, protocol, chain) {
+            if packet.ttl < self.dpi.distance {
+                continue;
+            }
+
+            match dpi.inspect(&packet) {
+                Some(Action::Reset) => return Verdict::Reset,
+                Some(Action::Blockpage) => return judge(domain, protocol, BLOCKPAGE),
+                None => {}
+            }
+
+            if packet.ttl >= self.server_distance {
+                server.write(&packet);
+            }
+        }
+
+        let received = server.contiguous();
+
+        match First::parse(protocol, &received) {
+            // the server gets the fake if it went too far
+            First::Host(host) if !host.eq_ignore_ascii_case(domain) => Verdict::Closed,
+            // the client hashed the ClientHello it sent, the server the one it got, and the
+            // server aborts the handshake when the client's Finished doesn't verify
+            First::Host(_) if protocol == Protocol::Tls && !received.starts_with(&payload) => {
+                Verdict::Closed
+            }
+            First::Host(_) => {
+                let answer = match protocol {
+                    Protocol::Http => OK_RESPONSE,
+                    Protocol::Tls => SERVER_HELLO,
+                };
+                judge(domain, protocol, answer)
+            }
+            First::Complete => Verdict::Closed,
+            First::Incomplete => Verdict::Timeout,
+        }
+    }
+}
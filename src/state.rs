@@ -0,0 +1,411 @@
+//! State learned while intercepting that is kept across restarts.
+//!
+//! The store is a log of JSON lines: a header naming the schema version, then one record per
+//! change. Records are appended as they change, and synced to disk in batches by `sync`, so a
+//! crash of the system loses the records since the last sync and a crash of the program at most
+//! the record being written, whose partial line is skipped when the log is read again. The log
+//! is compacted by rewriting the live entries to a new file that replaces the old one. Entries can
+//! expire, expired ones are skipped when reading and dropped when compacting.
+
+use std::{
+    collections::BTreeMap,
+    fmt,
+    fs::{self, File, OpenOptions},
+    io::{ErrorKind, Write},
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use color_eyre::{
+    Result,
+    eyre::{Context, bail},
+};
+use log::{debug, warn};
+use serde::{Deserialize, Serialize, de::DeserializeOwned};
+use serde_json::Value;
+
+/// Default file name of the store.
+pub const STATE_FILE: &str = "packetmock-state.jsonl";
+
+/// Name of the schema in the header line.
+const SCHEMA: &str = "packetmock-state";
+/// Version of the schema written by this build, logs of newer versions aren't read.
+const SCHEMA_VERSION: u32 = 1;
+
+/// Records in the log before it is worth compacting.
+const COMPACT_MIN_RECORDS: usize = 1024;
+
+/// First line of the log.
+#[derive(Serialize, Deserialize)]
+struct Header {
+    schema: String,
+    version: u32,
+}
+
+/// A change appended to the log.
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "kebab-case")]
+enum Record {
+    Set {
+        ns: String,
+        key: String,
+        /// Unix time in seconds after which the entry is gone.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        expires: Option<u64>,
+        value: Value,
+    },
+    Remove {
+        ns: String,
+        key: String,
+    },
+}
+
+/// A stored value.
+#[derive(Clone, Debug)]
+pub struct Entry {
+    pub value: Value,
+    /// Unix time in seconds after which the entry is gone, never if `None`.
+    pub expires: Option<u64>,
+}
+
+impl Entry {
+    fn is_expired(&self, now: u64) -> bool {
+        self.expires.is_some_and(|expires| expires <= now)
+    }
+}
+
+/// Values by namespace and key, kept in sync with their log.
+pub struct StateStore {
+    path: PathBuf,
+    /// The log opened for appending, `None` until the first change.
+    file: Option<File>,
+    entries: BTreeMap<(String, String), Entry>,
+    /// Records in the log, including the ones replaced or removed since.
+    records: usize,
+    /// The log ends with a partial line, which the next record must not continue.
+    partial: bool,
+    /// The log holds only part of a header, it is rewritten with the first record.
+    truncate: bool,
+    /// Records were appended since the log was last synced.
+    unsynced: bool,
+}
+
+impl StateStore {
+    /// Read the log, if it exists. Logs written by a newer version are refused rather than
+    /// overwritten. The log is left as it is until the first change.
+    pub fn open(path: PathBuf) -> Result<Self> {
+        let mut store = Self {
+            path,
+            file: None,
+            entries: BTreeMap::new(),
+            records: 0,
+            partial: false,
+            truncate: false,
+            unsynced: false,
+        };
+
+        let text = match fs::read_to_string(&store.path) {
+            Ok(text) => text,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(store),
+            Err(e) => {
+                return Err(e).wrap_err_with(|| format!("Failed to read {}", store.path.display()));
+            }
+        };
+
+        if !text.is_empty() && !text.contains('\n') {
+            // interrupted while writing the header of a new log, there is nothing in it
+            if !header_line()?.starts_with(&text) {
+                bail!("{} isn't a state store", store.path.display());
+            }
+            warn!("Ignoring the partial state store {}", store.path.display());
+            store.truncate = true;
+            return Ok(store);
+        }
+
+        store.partial = !text.is_empty() && !text.ends_with('\n');
+        let mut lines = text.lines().enumerate();
+
+        if let Some((_, line)) = lines.next() {
+            let header: Header = serde_json::from_str(line)
+                .wrap_err_with(|| format!("{} isn't a state store", store.path.display()))?;
+
+            if header.schema != SCHEMA {
+                bail!("{} isn't a state store", store.path.display());
+            }
+            if header.version > SCHEMA_VERSION {
+                bail!(
+                    "{} was written by a newer version (schema {})",
+                    store.path.display(),
+                    header.version
+                );
+            }
+        }
+
+        let now = unix_now();
+
+        for (index, line) in lines {
+            let record = match serde_json::from_str(line) {
+                Ok(record) => record,
+                Err(e) => {
+                    // the last line is partial if writing it was interrupted
+                    warn!("Skipping {}:{}: {e}", store.path.display(), index + 1);
+                    continue;
+                }
+            };
+
+            store.records += 1;
+
+            match record {
+                Record::Set {
+                    ns,
+                    key,
+                    expires,
+                    value,
+                } => {
+                    let entry = Entry { value, expires };
+                    if !entry.is_expired(now) {
+                        store.entries.insert((ns, key), entry);
+                    } else {
+                        store.entries.remove(&(ns, key));
+                    }
+                }
+                Record::Remove { ns, key } => {
+                    store.entries.remove(&(ns, key));
+                }
+            }
+        }
+
+        Ok(store)
+    }
+
+    #[inline]
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// The value of the key, `None` if it isn't set or expired.
+    pub fn get(&self, ns: &str, key: &str) -> Option<&Entry> {
+        self.entries
+            .get(&(ns.to_owned(), key.to_owned()))
+            .filter(|entry| !entry.is_expired(unix_now()))
+    }
+
+    /// The live entries of the namespace, sorted by key, with their values deserialized.
+    /// Values of another type are skipped.
+    pub fn entries<T: DeserializeOwned>(&self, ns: &str) -> impl Iterator<Item = (&str, T)> {
+        let now = unix_now();
+
+        self.entries
+            .iter()
+            .filter(move |((entry_ns, _), entry)| entry_ns == ns && !entry.is_expired(now))
+            .filter_map(|((_, key), entry)| {
+                let value = serde_json::from_value(entry.value.clone()).ok()?;
+                Some((key.as_str(), value))
+            })
+    }
+
+    /// Set the key, for `ttl` or forever if `None`.
+    pub fn set<T: Serialize>(
+        &mut self,
+        ns: &str,
+        key: &str,
+        value: &T,
+        ttl: Option<Duration>,
+    ) -> Result<()> {
+        let value = serde_json::to_value(value).wrap_err("Failed to serialize the state")?;
+        let expires = ttl.map(|ttl| unix_now().saturating_add(ttl.as_secs()));
+
+        self.append(&Record::Set {
+            ns: ns.to_owned(),
+            key: key.to_owned(),
+            expires,
+            value: value.clone(),
+        })?;
+        self.entries
+            .insert((ns.to_owned(), key.to_owned()), Entry { value, expires });
+
+        Ok(())
+    }
+
+    /// Remove the key, returning whether it was set.
+    pub fn remove(&mut self, ns: &str, key: &str) -> Result<bool> {
+        let id = (ns.to_owned(), key.to_owned());
+
+        if !self.entries.contains_key(&id) {
+            return Ok(false);
+        }
+
+        self.append(&Record::Remove {
+            ns: ns.to_owned(),
+            key: key.to_owned(),
+        })?;
+        self.entries.remove(&id);
+
+        Ok(true)
+    }
+
+    /// Rewrite the log if most of its records are outdated.
+    pub fn maybe_compact(&mut self) -> Result<()> {
+        if self.records >= COMPACT_MIN_RECORDS && self.records > 2 * self.entries.len() {
+            self.compact()?;
+        }
+
+        Ok(())
+    }
+
+    /// Rewrite the log with only the live entries. The new log is written next to the old one
+    /// and replaces it once synced, so either of them is complete after a crash.
+    pub fn compact(&mut self) -> Result<()> {
+        let now = unix_now();
+        self.entries.retain(|_, entry| !entry.is_expired(now));
+
+        let mut text = header_line()?;
+        for ((ns, key), entry) in &self.entries {
+            let record = Record::Set {
+                ns: ns.clone(),
+                key: key.clone(),
+                expires: entry.expires,
+                value: entry.value.clone(),
+            };
+            text.push_str(&serde_json::to_string(&record)?);
+            text.push('\n');
+        }
+
+        // the old log must be closed before it can be replaced on Windows
+        self.file = None;
+        replace_file(&self.path, text.as_bytes())?;
+        self.unsynced = false;
+        self.truncate = false;
+        self.partial = false;
+
+        debug!(
+            "Compacted {} from {} to {} records",
+            self.path.display(),
+            self.records,
+            self.entries.len()
+        );
+        self.records = self.entries.len();
+
+        Ok(())
+    }
+
+    /// Sync the records appended since the last sync to disk.
+    pub fn sync(&mut self) -> Result<()> {
+        if let Some(file) = &self.file
+            && self.unsynced
+        {
+            file.sync_data()
+                .wrap_err_with(|| format!("Failed to sync {}", self.path.display()))?;
+        }
+        self.unsynced = false;
+
+        Ok(())
+    }
+
+    /// Append a record to the log, creating the log if needed. It is synced by `sync`.
+    fn append(&mut self, record: &Record) -> Result<()> {
+        let mut line = serde_json::to_string(record)?;
+        line.push('\n');
+
+        let path = &self.path;
+        let file = match &mut self.file {
+            Some(file) => file,
+            None => {
+                let started =
+                    !self.truncate && path.metadata().is_ok_and(|metadata| metadata.len() > 0);
+                let mut options = OpenOptions::new();
+                if self.truncate {
+                    options.write(true).truncate(true);
+                } else {
+                    options.create(true).append(true);
+                }
+                let mut file = options
+                    .open(path)
+                    .wrap_err_with(|| format!("Failed to open {}", path.display()))?;
+                self.truncate = false;
+
+                if !started {
+                    file.write_all(header_line()?.as_bytes())?;
+                } else if self.partial {
+                    file.write_all(b"\n")?;
+                    self.partial = false;
+                }
+                self.file.insert(file)
+            }
+        };
+
+        file.write_all(line.as_bytes())
+            .wrap_err_with(|| format!("Failed to write {}", path.display()))?;
+        self.records += 1;
+        self.unsynced = true;
+
+        Ok(())
+    }
+}
+
+impl Drop for StateStore {
+    fn drop(&mut self) {
+        if let Err(e) = self.sync() {
+            warn!("{e:?}");
+        }
+    }
+}
+
+impl fmt::Display for StateStore {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "{} (schema {SCHEMA_VERSION}, {} entries, {} records)",
+            self.path.display(),
+            self.entries.len(),
+            self.records
+        )?;
+
+        let now = unix_now();
+
+        for ((ns, key), entry) in &self.entries {
+            if entry.is_expired(now) {
+                continue;
+            }
+
+            write!(f, "  {ns} {key} = {}", entry.value)?;
+            match entry.expires {
+                Some(expires) => writeln!(f, " (expires in {}s)", expires - now)?,
+                None => writeln!(f)?,
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Replace the file with the contents through a temporary file next to it, so that a crash
+/// leaves either the old or the new file and never a part of one.
+pub fn replace_file(path: &Path, contents: &[u8]) -> Result<()> {
+    let temporary = path.with_extension("tmp");
+    let write = || -> Result<()> {
+        let mut file = File::create(&temporary)?;
+        file.write_all(contents)?;
+        file.sync_all()?;
+        Ok(())
+    };
+    write().wrap_err_with(|| format!("Failed to write {}", temporary.display()))?;
+
+    fs::rename(&temporary, path).wrap_err_with(|| format!("Failed to replace {}", path.display()))
+}
+
+fn header_line() -> Result<String> {
+    let header = Header {
+        schema: SCHEMA.to_owned(),
+        version: SCHEMA_VERSION,
+    };
+
+    Ok(serde_json::to_string(&header)? + "\n")
+}
+
+/// The current Unix time in seconds.
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |duration| duration.as_secs())
+}
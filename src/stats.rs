@@ -0,0 +1,115 @@
+use std::{
+    fmt,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+/// Counters of the interceptor, shared by the whole process.
+pub static STATS: Stats = Stats {
+    packets: Counter::new(),
+    fakes: Counter::new(),
+    fakes_limited: Counter::new(),
+    ech_flows: Counter::new(),
+    flows_expired: Counter::new(),
+    flows_closed: Counter::new(),
+    flows_evicted: Counter::new(),
+    injected_skipped: Counter::new(),
+    retransmissions_repeated: Counter::new(),
+    retransmissions_escalated: Counter::new(),
+    retransmissions_passed: Counter::new(),
+    dns_queries: Counter::new(),
+    dns_cached: Counter::new(),
+    dns_genuine: Counter::new(),
+    dns_spoofed_bogon: Counter::new(),
+    dns_spoofed_ttl: Counter::new(),
+    dns_spoofed_id: Counter::new(),
+    dns_spoofed_early: Counter::new(),
+};
+
+/// Statistics about the intercepted traffic.
+pub struct Stats {
+    /// Packets received from the capture handle.
+    pub packets: Counter,
+    /// Fake packets injected in front of real ones.
+    pub fakes: Counter,
+    /// Flows sent without desync because their fakes were over an injection limit.
+    pub fakes_limited: Counter,
+    /// TLS flows whose ClientHello carries an encrypted client hello.
+    pub ech_flows: Counter,
+    /// Tracked flows removed after being idle.
+    pub flows_expired: Counter,
+    /// Tracked flows removed after a FIN or RST.
+    pub flows_closed: Counter,
+    /// Tracked flows removed to make room in a full flow table.
+    pub flows_evicted: Counter,
+    /// Packets injected by a Packetmock instance that were captured again and skipped.
+    pub injected_skipped: Counter,
+    /// Retransmitted first payloads desynced with their chain again.
+    pub retransmissions_repeated: Counter,
+    /// Retransmitted first payloads desynced with the escalation chain.
+    pub retransmissions_escalated: Counter,
+    /// Retransmitted first payloads sent unchanged.
+    pub retransmissions_passed: Counter,
+    /// DNS queries answered through the encrypted resolver.
+    pub dns_queries: Counter,
+    /// DNS queries answered from the cache.
+    pub dns_cached: Counter,
+    /// DNS answers the guard took for genuine.
+    pub dns_genuine: Counter,
+    /// Forged DNS answers dropped for pointing to a bogon.
+    pub dns_spoofed_bogon: Counter,
+    /// Forged DNS answers dropped for their IP TTL.
+    pub dns_spoofed_ttl: Counter,
+    /// Forged DNS answers dropped for their IP identification.
+    pub dns_spoofed_id: Counter,
+    /// Forged DNS answers dropped for arriving too early.
+    pub dns_spoofed_early: Counter,
+}
+
+impl fmt::Display for Stats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "packets: {}, fakes: {}, limited: {}, ECH flows: {}, flows expired: {}, closed: {}, \
+             evicted: {}, injected skipped: {}, retransmissions repeated: {}, escalated: {}, \
+             passed: {}, DNS queries: {}, cached: {}, DNS answers genuine: {}, \
+             spoofed bogon: {}, TTL: {}, ID: {}, early: {}",
+            self.packets.get(),
+            self.fakes.get(),
+            self.fakes_limited.get(),
+            self.ech_flows.get(),
+            self.flows_expired.get(),
+            self.flows_closed.get(),
+            self.flows_evicted.get(),
+            self.injected_skipped.get(),
+            self.retransmissions_repeated.get(),
+            self.retransmissions_escalated.get(),
+            self.retransmissions_passed.get(),
+            self.dns_queries.get(),
+            self.dns_cached.get(),
+            self.dns_genuine.get(),
+            self.dns_spoofed_bogon.get(),
+            self.dns_spoofed_ttl.get(),
+            self.dns_spoofed_id.get(),
+            self.dns_spoofed_early.get(),
+        )
+    }
+}
+
+/// A monotonically increasing counter.
+pub struct Counter(AtomicU64);
+
+impl Counter {
+    const fn new() -> Self {
+        Self(AtomicU64::new(0))
+    }
+
+    #[inline]
+    pub fn increment(&self) {
+        self.0.fetch_add(1, Ordering::Relaxed);
+    }
+
+    #[inline]
+    pub fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
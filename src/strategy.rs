@@ -0,0 +1,294 @@
+//! Modifications of the real and fake payloads applied to desynced flows.
+
+use std::{
+    fmt,
+    hash::{BuildHasher, RandomState},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::http::tls::{parse_client_hello, read_u16};
+
+/// Extension type of the padding extension (RFC 7685).
+const EXTENSION_PADDING: u16 = 0x0015;
+/// Extension type of the pre-shared key, whose binders are computed over the ClientHello.
+const EXTENSION_PRE_SHARED_KEY: u16 = 0x0029;
+/// Maximum length of a TLS plaintext record.
+const MAX_RECORD_LENGTH: usize = 1 << 14;
+
+/// Add or resize a padding extension in front of the server name extension, so that the
+/// server name starts at `sni_offset` bytes into the payload.
+///
+/// The record, handshake and extensions lengths are rewritten to match. Returns `None` if the
+/// ClientHello can't be padded to that offset: it has no server name, continues in another
+/// segment or record, carries a pre-shared key, already has a padding extension after the
+/// server name, or the offset lies before the server name with no padding that could be
+/// shrunk.
+///
+/// Only the fake ClientHello is padded. The server would hash a padded real ClientHello into
+/// the handshake transcript and the client the one it sent, so the Finished messages wouldn't
+/// verify.
+pub fn pad_client_hello(data: &[u8], sni_offset: usize) -> Option<Vec<u8>> {
+    let hello = parse_client_hello(data)?;
+    let sni = hello.sni.clone()?;
+
+    let record_len = read_u16(data, 3)? as usize;
+    if 5 + record_len > data.len() {
+        return None;
+    }
+    // the record holds the whole ClientHello and nothing else
+    let handshake_len = u32::from_be_bytes([0, data[6], data[7], data[8]]) as usize;
+    if handshake_len + 4 != record_len {
+        return None;
+    }
+
+    if hello
+        .extensions
+        .iter()
+        .any(|extension| extension.kind == EXTENSION_PRE_SHARED_KEY)
+    {
+        return None;
+    }
+
+    let shift = sni_offset as isize - sni.start as isize;
+    if shift == 0 {
+        return None;
+    }
+
+    let sni_index = hello
+        .extensions
+        .iter()
+        .position(|extension| extension.data.contains(&sni.start))?;
+
+    let padding = hello
+        .extensions
+        .iter()
+        .position(|extension| extension.kind == EXTENSION_PADDING);
+
+    // the range of the original payload replaced by the new padding extension
+    let (replace, padding_len) = match padding {
+        // resizing a padding after the server name doesn't move it, and a second padding
+        // extension would get the ClientHello rejected
+        Some(index) if index > sni_index => return None,
+        Some(index) => {
+            let extension = &hello.extensions[index];
+            (
+                extension.start..extension.data.end,
+                extension.data.len() as isize + shift,
+            )
+        }
+        None if shift >= 4 => {
+            let start = hello.extensions[sni_index].start;
+            (start..start, shift - 4)
+        }
+        None => return None,
+    };
+
+    let new_record_len = (record_len as isize + shift) as usize;
+    if padding_len < 0 || new_record_len > MAX_RECORD_LENGTH {
+        return None;
+    }
+
+    let extensions_len_offset = hello.extensions.first()?.start - 2;
+    let extensions_len = read_u16(data, extensions_len_offset)? as isize + shift;
+
+    let mut padded = Vec::with_capacity((data.len() as isize + shift) as usize);
+    padded.extend_from_slice(&data[..replace.start]);
+    padded.extend_from_slice(&EXTENSION_PADDING.to_be_bytes());
+    padded.extend_from_slice(&(padding_len as u16).to_be_bytes());
+    padded.resize(padded.len() + padding_len as usize, 0);
+    padded.extend_from_slice(&data[replace.end..]);
+
+    // record header, handshake header and extensions block lengths
+    padded[3..5].copy_from_slice(&(new_record_len as u16).to_be_bytes());
+    let new_handshake_len = (handshake_len as isize + shift) as u32;
+    padded[6..9].copy_from_slice(&new_handshake_len.to_be_bytes()[1..]);
+    padded[extensions_len_offset..extensions_len_offset + 2]
+        .copy_from_slice(&(extensions_len as u16).to_be_bytes());
+
+    Some(padded)
+}
+
+/// One step of a strategy chain.
+///
+/// Fakes are sent as soon as their step is reached, the real payload is sent after the last
+/// step. Steps that don't apply to the protocol of the flow are skipped.
+#[derive(Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
+#[serde(tag = "step", rename_all = "kebab-case", deny_unknown_fields)]
+pub enum Step {
+    /// Send a fake payload with a low TTL, `strategies.ttl` if none is given.
+    Fake {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        ttl: Option<u8>,
+    },
+    /// Move the server name of the fake ClientHello to the offset with a padding extension.
+    PadSni { offset: usize },
+    /// Change the case of the server name of the fake ClientHello.
+    SniCase { case: SniCase },
+    /// Split the payload into two segments at the position.
+    Split { position: usize },
+}
+
+impl fmt::Display for Step {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Fake { ttl: None } => write!(f, "fake"),
+            Self::Fake { ttl: Some(ttl) } => write!(f, "fake (ttl {ttl})"),
+            Self::PadSni { offset } => write!(f, "pad-sni {offset}"),
+            Self::SniCase {
+                case: SniCase::Random,
+            } => write!(f, "sni-case random"),
+            Self::SniCase {
+                case: SniCase::Alternate,
+            } => write!(f, "sni-case alternate"),
+            Self::Split { position } => write!(f, "split {position}"),
+        }
+    }
+}
+
+/// How flows whose ClientHello uses an encrypted client hello are handled.
+#[derive(Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum EchPolicy {
+    /// Match the hostlists against the public name of the outer ClientHello.
+    OuterSni,
+    /// Never desync, the real server name is encrypted anyway.
+    Pass,
+    /// Always desync, regardless of the hostlists.
+    Fake,
+}
+
+/// How retransmissions of a desynced first payload are handled.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum RetransmitAction {
+    /// Apply the chain of the first transmission again.
+    Repeat,
+    /// Apply `retransmission.escalation` instead.
+    Escalate,
+    /// Send the retransmission unchanged.
+    Pass,
+}
+
+/// Compute the positions at which a payload of `len` bytes is cut into segments.
+///
+/// The payload is cut at `split` if it lies inside the payload. An empty result means the
+/// payload is sent in one piece.
+pub fn split_positions(len: usize, split: Option<usize>) -> Vec<usize> {
+    split
+        .filter(|&split| split > 0 && split < len)
+        .into_iter()
+        .collect()
+}
+
+/// How a first payload is sent once the steps of a chain are applied to it.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Desync {
+    /// TTLs of the fakes sent ahead of the payload, `strategies.ttl` where `None`.
+    pub fakes: Vec<Option<u8>>,
+    /// The fake payload, changed by the steps for the fake ClientHello.
+    pub fake: Vec<u8>,
+    /// The real payload, sent as it is.
+    pub payload: Vec<u8>,
+    /// Positions the payload is cut into segments at, empty if it is sent in one piece.
+    pub positions: Vec<usize>,
+}
+
+impl Desync {
+    /// The segments of the payload with their offsets into it.
+    pub fn segments(&self) -> impl Iterator<Item = (usize, &[u8])> {
+        let ends = self.positions.iter().copied().chain([self.payload.len()]);
+
+        ends.scan(0, |start, end| {
+            let segment = (*start, &self.payload[*start..end]);
+            *start = end;
+            Some(segment)
+        })
+    }
+}
+
+/// Apply the steps of a chain to the first payload of a flow and to the fake sent with it.
+///
+/// The ClientHello steps only change the fake, and are skipped if it isn't a ClientHello.
+pub fn desync(data: &[u8], chain: &[Step], fake: &[u8]) -> Desync {
+    let mut fakes = Vec::new();
+    let mut fake = fake.to_vec();
+    let mut split = None;
+
+    for step in chain {
+        match *step {
+            Step::Fake { ttl } => fakes.push(ttl),
+            Step::PadSni { offset } => {
+                if let Some(padded) = pad_client_hello(&fake, offset) {
+                    fake = padded;
+                }
+            }
+            Step::SniCase { case } => {
+                if let Some(sni) = parse_client_hello(&fake).and_then(|hello| hello.sni) {
+                    change_case(&mut fake[sni], case);
+                }
+            }
+            Step::Split { position } => split = Some(position),
+        }
+    }
+
+    Desync {
+        fakes,
+        fake,
+        payload: data.to_vec(),
+        positions: split_positions(data.len(), split),
+    }
+}
+
+/// How the letters of the server name in the fake ClientHello are re-cased.
+///
+/// Host names are case-insensitive, but many DPI engines compare them byte for byte. The real
+/// ClientHello is left alone: the server would hash the re-cased one into the handshake
+/// transcript and the client the one it sent, so the Finished messages wouldn't verify.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum SniCase {
+    /// Each letter gets a random case, with at least one letter upper case.
+    Random,
+    /// Letters alternate between lower and upper case, e.g. `wWw.W3.oRg`.
+    Alternate,
+}
+
+/// Change the case of the letters of a host name in place.
+pub fn change_case(name: &mut [u8], case: SniCase) {
+    match case {
+        SniCase::Random => {
+            let mut bits = RandomState::new().hash_one(name.as_ptr());
+            let mut changed = false;
+
+            for byte in name.iter_mut().filter(|byte| byte.is_ascii_alphabetic()) {
+                if bits & 1 == 1 {
+                    byte.make_ascii_uppercase();
+                    changed = true;
+                } else {
+                    byte.make_ascii_lowercase();
+                }
+                bits = bits.rotate_right(1);
+            }
+
+            // an all lower case name would be matched by the DPI as usual
+            if !changed && let Some(byte) = name.iter_mut().find(|byte| byte.is_ascii_alphabetic())
+            {
+                byte.make_ascii_uppercase();
+            }
+        }
+        SniCase::Alternate => {
+            for (index, byte) in name
+                .iter_mut()
+                .filter(|byte| byte.is_ascii_alphabetic())
+                .enumerate()
+            {
+                if index % 2 == 1 {
+                    byte.make_ascii_uppercase();
+                } else {
+                    byte.make_ascii_lowercase();
+                }
+            }
+        }
+    }
+}
@@ -1,6 +1,8 @@
 use std::env::current_exe;
 
 use color_eyre::Result;
+use log::error;
+use packetmock::settings::{Settings, open_store};
 use windows::Win32::{
     Foundation::VARIANT_FALSE,
     System::{
@@ -13,12 +15,8 @@ use windows::Win32::{
     },
 };
 use windows_core::Interface;
-use windows_registry::LOCAL_MACHINE;
 
-use crate::{
-    REGISTRY_NAME,
-    service::{ServiceState, query_service},
-};
+use crate::service::{ServiceState, query_service};
 
 pub struct Scheduler;
 
@@ -117,20 +115,21 @@ impl Scheduler {
 }
 
 pub fn run_on_startup() -> bool {
-    let Ok(key) = LOCAL_MACHINE.open(format!("Software\\{REGISTRY_NAME}")) else {
-        return true;
-    };
-
-    match key.get_u32("RunTrayOnStartup") {
-        Ok(run) => run == 1,
-        Err(_) => true,
+    match open_store().and_then(|store| store.load()) {
+        Ok(settings) => settings.run_tray_on_startup,
+        Err(e) => {
+            error!("Failed to load settings: {e:?}");
+            Settings::default().run_tray_on_startup
+        }
     }
 }
 
 pub fn set_run_on_startup(run: bool) -> Result<()> {
-    let key = LOCAL_MACHINE.create(format!("Software\\{REGISTRY_NAME}"))?;
+    let store = open_store()?;
 
-    key.set_u32("RunTrayOnStartup", if run { 1 } else { 0 })?;
+    let mut settings = store.load()?;
+    settings.run_tray_on_startup = run;
+    store.save(&settings)?;
 
     if run {
         Scheduler::create_if_not_exists()?;
@@ -34,8 +34,11 @@ use crate::{
         uninstall_service,
     },
     tasksch::{run_on_startup, set_run_on_startup},
-    tray::menu::{MENU_ID_STARTUP, MENU_ID_TTL},
-    windivert::ttl::{get_ttl, set_ttl},
+    tray::menu::{MAX_MENU_PROFILES, MENU_ID_PROFILE, MENU_ID_STARTUP, MENU_ID_TTL},
+    windivert::{
+        profile::{get_profiles, set_active_profile},
+        ttl::{get_ttl, set_ttl},
+    },
 };
 
 use self::{
@@ -85,6 +88,7 @@ unsafe extern "system" fn wnd_proc(hwnd: HWND, msg: u32, wparam: usize, lparam:
             WM_RBUTTONUP => unsafe {
                 const MENU_ID_TTL_UP: usize = MENU_ID_TTL + 5;
                 const MENU_ID_TTL_DOWN: usize = MENU_ID_TTL - 5;
+                const MENU_ID_PROFILE_LAST: usize = MENU_ID_PROFILE + MAX_MENU_PROFILES - 1;
 
                 if let Err(e) = match show_popup_menu(hwnd) {
                     Ok(id) => match id as usize {
@@ -130,6 +134,17 @@ unsafe extern "system" fn wnd_proc(hwnd: HWND, msg: u32, wparam: usize, lparam:
                                 show_toast(&format!("Set TTL to {new_ttl}"))
                             }
                         }
+                        MENU_ID_PROFILE..=MENU_ID_PROFILE_LAST => {
+                            let (profiles, _) = get_profiles();
+
+                            match profiles.get(id as usize - MENU_ID_PROFILE) {
+                                Some(name) => match set_active_profile(name) {
+                                    Ok(_) => show_toast(&format!("Switched to profile {name}")),
+                                    Err(e) => toast_err("Failed to switch profile", e),
+                                },
+                                None => Ok(()),
+                            }
+                        }
                         MENU_ID_STARTUP => {
                             let run = !run_on_startup();
 
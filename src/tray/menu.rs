@@ -22,7 +22,10 @@ use windows_sys::w;
 use crate::{
     service::{ServiceState, query_service},
     tasksch::run_on_startup,
-    windivert::ttl::get_ttl,
+    windivert::{
+        profile::{get_profiles, get_schedule_status},
+        ttl::get_ttl,
+    },
 };
 
 pub const MENU_ID_EXIT: usize = 1000;
@@ -32,6 +35,10 @@ pub const MENU_ID_START: usize = 1003;
 pub const MENU_ID_INSTALL: usize = 1004;
 pub const MENU_ID_TTL: usize = 2000;
 pub const MENU_ID_STARTUP: usize = 3000;
+/// First of the profile items, one per profile.
+pub const MENU_ID_PROFILE: usize = 4000;
+/// Maximum number of profiles listed in the menu.
+pub const MAX_MENU_PROFILES: usize = 100;
 
 /// Create the popup menu based on the current service state.
 fn create_popup_menu() -> Result<HMENU> {
@@ -81,6 +88,14 @@ fn create_settings_menu() -> Result<HMENU> {
 
         AppendMenuW(menu, MF_POPUP, ttl_menu as _, ttl_wide.as_ptr());
 
+        let (profiles, active) = get_profiles();
+        let profile_menu = create_profile_menu(&profiles, &active)?;
+
+        let profile_text = format!("Profile (current: {})", get_schedule_status());
+        let profile_wide: Vec<u16> = profile_text.encode_utf16().chain(once(0)).collect();
+
+        AppendMenuW(menu, MF_POPUP, profile_menu as _, profile_wide.as_ptr());
+
         let checked = if run_on_startup() {
             MF_CHECKED
         } else {
@@ -113,6 +128,31 @@ fn create_ttl_menu() -> Result<HMENU> {
     }
 }
 
+/// Create the profile submenu, with the active profile checked.
+fn create_profile_menu(profiles: &[String], active: &str) -> Result<HMENU> {
+    unsafe {
+        let menu = CreatePopupMenu();
+
+        for (index, name) in profiles.iter().take(MAX_MENU_PROFILES).enumerate() {
+            let checked = if name == active {
+                MF_CHECKED
+            } else {
+                MF_UNCHECKED
+            };
+            let name_wide: Vec<u16> = name.encode_utf16().chain(once(0)).collect();
+
+            AppendMenuW(
+                menu,
+                MF_STRING | checked,
+                MENU_ID_PROFILE + index,
+                name_wide.as_ptr(),
+            );
+        }
+
+        Ok(menu)
+    }
+}
+
 /// Show the popup menu and return the selected menu item ID.
 pub fn show_popup_menu(hwnd: HWND) -> Result<i32> {
     unsafe {
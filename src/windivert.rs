@@ -1,35 +1,72 @@
+pub mod blockcheck;
+pub mod dns;
+pub mod profile;
+pub mod reload;
 pub mod ttl;
 
-use std::{borrow::Cow, cmp::Ordering, ffi::CString, mem::zeroed, ptr::null_mut, slice};
+use std::{
+    borrow::Cow,
+    cell::RefCell,
+    cmp::Ordering,
+    collections::HashMap,
+    ffi::CString,
+    mem::zeroed,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    path::PathBuf,
+    ptr::null_mut,
+    slice,
+    sync::mpsc::{self, Receiver},
+    thread,
+    time::{Duration, Instant},
+};
 
 use color_eyre::{
     Result,
     eyre::{ContextCompat, bail},
 };
-use const_format::formatcp as const_format;
-use log::info;
+use log::{debug, info, warn};
 use winapi::um::{errhandlingapi::GetLastError, handleapi::INVALID_HANDLE_VALUE};
 use windivert_sys::{
-    WINDIVERT_ADDRESS, WINDIVERT_IPHDR, WINDIVERT_LAYER_WINDIVERT_LAYER_NETWORK, WINDIVERT_TCPHDR,
-    WinDivertHelperCalcChecksums, WinDivertHelperParsePacket, WinDivertOpen, WinDivertRecv,
-    WinDivertSend,
+    WINDIVERT_ADDRESS, WINDIVERT_IPHDR, WINDIVERT_IPV6HDR, WINDIVERT_LAYER_WINDIVERT_LAYER_NETWORK,
+    WINDIVERT_TCPHDR, WinDivertClose, WinDivertHelperCalcChecksums, WinDivertHelperParsePacket,
+    WinDivertOpen, WinDivertRecv, WinDivertSend,
+};
+
+use packetmock::{
+    adaptive::StrategyCache,
+    autohostlist::{AutoHostlist, Outcome, response_outcome},
+    backend::Backend,
+    cidr::CidrFilter,
+    classify::Detected,
+    conntrack::{FlowKey, FlowTable, Segment, TcpFlags},
+    dns::guard::{SpoofGuard, Verdict},
+    filter::{MAX_PACKET_SIZE, capture_filter, desync_filter},
+    hostlist::HostFilter,
+    http::{FAKE_CLIENT_HELLO, FAKE_HTTP_REQUEST, parse_http_requests, tls::parse_client_hello},
+    profile::{ActiveProfile, Flow, Protocol},
+    ratelimit::InjectionLimiter,
+    schedule::{Clock, Schedule, SystemClock},
+    settings::{
+        Blocking, Conntrack, Dns, DnsGuard, Injection, Ports, Retransmission, Settings,
+        SettingsStore, SettingsWatcher, Strategies, open_store,
+    },
+    stats::STATS,
+    strategy::{EchPolicy, RetransmitAction, Step, desync},
 };
 
-use crate::http::{FAKE_CLIENT_HELLO, FAKE_HTTP_REQUEST, is_client_hello};
+use self::dns::DnsWorker;
 
-use self::ttl::get_ttl;
+pub const BUFFER_SIZE: usize = MAX_PACKET_SIZE;
+/// How often the schedule is checked for a profile change and flows for their timeouts.
+const TICK_INTERVAL: Duration = Duration::from_secs(1);
 
-pub const WINDIVERT_FILTER: &str = const_format!(
-    "outbound and (tcp.DstPort == 80 or tcp.DstPort == 443) and tcp.PayloadLength > 0 and tcp.PayloadLength < {BUFFER_SIZE} and !impostor and !loopback and {LAN_FILTER}"
-);
-const LAN_FILTER: &str = "(
-    (ip.DstAddr < 127.0.0.1 or ip.DstAddr > 127.255.255.255) and
-    (ip.DstAddr < 10.0.0.0 or ip.DstAddr > 10.255.255.255) and
-    (ip.DstAddr < 192.168.0.0 or ip.DstAddr > 192.168.255.255) and
-    (ip.DstAddr < 172.16.0.0 or ip.DstAddr > 172.31.255.255) and
-    (ip.DstAddr < 169.254.0.0 or ip.DstAddr > 169.254.255.255)
-)";
-pub const BUFFER_SIZE: usize = 9016;
+/// Build the WinDivert filter capturing outbound payloads to the configured ports and networks,
+/// their responses if the outcome of flows is observed, DNS queries if they are resolved, and
+/// DNS answers as well if they are guarded.
+pub fn windivert_filter(config: &Config) -> String {
+    let filter = desync_filter(&config.ports, &config.addresses, config.observe);
+    capture_filter(&filter, config.dns.enabled, config.dns_guard.enabled)
+}
 
 /// A safe wrapper around a WinDivert handle and associated methods.
 pub struct WinDivert {
@@ -61,7 +98,7 @@ impl WinDivert {
         Ok(Self { handle })
     }
 
-    /// Receive a packet from the WinDivert handle.
+    /// Receive a packet from the WinDivert handle, skipping the impostors.
     ///
     /// The buffer must be large enough to hold the entire packet.
     pub fn recv<'a, 'b: 'a>(
@@ -69,7 +106,28 @@ impl WinDivert {
         buffer: &'b mut [u8],
         addr: &'b mut WINDIVERT_ADDRESS,
     ) -> Result<Packet<'a>> {
+        let (len, received) = self.receive(buffer)?;
+        *addr = received;
+
+        Packet::new(Cow::Borrowed(&buffer[..len]), Cow::Borrowed(addr))
+    }
+
+    /// Send a packet to the WinDivert handle as an impostor.
+    pub fn send(&self, mut packet: Packet<'_>) -> Result<()> {
+        if packet.recalc_checksums {
+            packet.calc_checksums()?;
+        }
+
+        self.inject(&packet.raw, &packet.addr)
+    }
+}
+
+impl Backend for WinDivert {
+    type Meta = WINDIVERT_ADDRESS;
+
+    fn capture(&self, buffer: &mut [u8]) -> Result<(usize, WINDIVERT_ADDRESS)> {
         let mut recv_len = 0;
+        let mut addr: WINDIVERT_ADDRESS = unsafe { zeroed() };
 
         let result = unsafe {
             WinDivertRecv(
@@ -77,7 +135,7 @@ impl WinDivert {
                 buffer.as_mut_ptr() as _,
                 buffer.len() as _,
                 &mut recv_len,
-                addr,
+                &mut addr,
             )
         };
 
@@ -86,24 +144,17 @@ impl WinDivert {
             bail!("Failed to receive packet: {err_code}");
         }
 
-        let raw = &buffer[..recv_len as usize];
-
-        Packet::new(Cow::Borrowed(raw), Cow::Borrowed(addr))
+        Ok((recv_len as usize, addr))
     }
 
-    /// Send a packet to the WinDivert handle.
-    pub fn send(&self, mut packet: Packet<'_>) -> Result<()> {
-        if packet.recalc_checksums {
-            packet.calc_checksums()?;
-        }
-
+    fn transmit(&self, packet: &[u8], addr: &WINDIVERT_ADDRESS) -> Result<()> {
         let result = unsafe {
             WinDivertSend(
                 self.handle,
-                packet.raw.as_ptr() as _,
-                packet.raw.len() as _,
+                packet.as_ptr() as _,
+                packet.len() as _,
                 null_mut(),
-                packet.addr.as_ref(),
+                addr,
             )
         };
 
@@ -114,6 +165,22 @@ impl WinDivert {
 
         Ok(())
     }
+
+    #[inline]
+    fn is_marked(addr: &WINDIVERT_ADDRESS) -> bool {
+        addr.Impostor() != 0
+    }
+
+    #[inline]
+    fn mark(addr: &mut WINDIVERT_ADDRESS) {
+        addr.set_Impostor(1);
+    }
+}
+
+impl Drop for WinDivert {
+    fn drop(&mut self) {
+        unsafe { WinDivertClose(self.handle) };
+    }
 }
 
 /// A representation of a network packet intercepted by WinDivert.
@@ -122,6 +189,7 @@ pub struct Packet<'a> {
     pub addr: Cow<'a, WINDIVERT_ADDRESS>,
 
     ip_header_ptr: *mut WINDIVERT_IPHDR,
+    ipv6_header_ptr: *mut WINDIVERT_IPV6HDR,
     tcp_header_ptr: *mut WINDIVERT_TCPHDR,
     data_ptr: *mut u8,
     data_length: usize,
@@ -133,6 +201,7 @@ impl<'a> Packet<'a> {
     /// Create a new `Packet` from raw packet data and address.
     pub fn new<'b: 'a>(raw: Cow<'b, [u8]>, addr: Cow<'b, WINDIVERT_ADDRESS>) -> Result<Self> {
         let mut ip_header = null_mut();
+        let mut ipv6_header = null_mut();
         let mut tcp_header = null_mut();
         let mut data = null_mut();
         let mut length = 0;
@@ -142,7 +211,7 @@ impl<'a> Packet<'a> {
                 raw.as_ptr() as _,
                 raw.len() as _,
                 &mut ip_header,
-                null_mut(),
+                &mut ipv6_header,
                 null_mut(),
                 null_mut(),
                 null_mut(),
@@ -164,6 +233,7 @@ impl<'a> Packet<'a> {
             raw,
             addr,
             ip_header_ptr: ip_header,
+            ipv6_header_ptr: ipv6_header,
             tcp_header_ptr: tcp_header,
             data_ptr: data as _,
             data_length: length as _,
@@ -175,6 +245,7 @@ impl<'a> Packet<'a> {
     /// This is necessary if the raw data has been modified or reallocated.
     fn reparse(&mut self) -> Result<()> {
         let mut ip_header = null_mut();
+        let mut ipv6_header = null_mut();
         let mut tcp_header = null_mut();
         let mut data = null_mut();
         let mut length = 0;
@@ -184,7 +255,7 @@ impl<'a> Packet<'a> {
                 self.raw.as_ptr() as _,
                 self.raw.len() as _,
                 &mut ip_header,
-                null_mut(),
+                &mut ipv6_header,
                 null_mut(),
                 null_mut(),
                 null_mut(),
@@ -203,6 +274,7 @@ impl<'a> Packet<'a> {
         }
 
         self.ip_header_ptr = ip_header;
+        self.ipv6_header_ptr = ipv6_header;
         self.tcp_header_ptr = tcp_header;
         self.data_ptr = data as _;
         self.data_length = length as _;
@@ -244,15 +316,71 @@ impl<'a> Packet<'a> {
         unsafe { slice::from_raw_parts_mut(self.data_ptr, self.data_length) }
     }
 
-    /// Get a mutable reference to the IP header.
-    ///
-    /// # Safety
-    /// This function is unsafe because it does not check if the IP header pointer is null. The
-    /// caller must ensure that this condition is met before calling this function.
-    #[inline]
-    pub fn ip_header_mut(&mut self) -> &mut WINDIVERT_IPHDR {
+    /// Destination address from the IPv4 or IPv6 header.
+    pub fn dst_addr(&self) -> IpAddr {
+        if self.ip_header_ptr.is_null() {
+            ipv6_addr(unsafe { (*self.ipv6_header_ptr).DstAddr })
+        } else {
+            let addr = unsafe { (*self.ip_header_ptr).DstAddr };
+            IpAddr::V4(Ipv4Addr::from(u32::from_be(addr)))
+        }
+    }
+
+    /// Source address from the IPv4 or IPv6 header.
+    pub fn src_addr(&self) -> IpAddr {
+        if self.ip_header_ptr.is_null() {
+            ipv6_addr(unsafe { (*self.ipv6_header_ptr).SrcAddr })
+        } else {
+            let addr = unsafe { (*self.ip_header_ptr).SrcAddr };
+            IpAddr::V4(Ipv4Addr::from(u32::from_be(addr)))
+        }
+    }
+
+    /// The TCP segment as seen by the flow table.
+    pub fn segment(&self) -> Segment {
+        let tcp = self.tcp_header();
+        let outbound = self.addr.Outbound() != 0;
+
+        let src = SocketAddr::new(self.src_addr(), u16::from_be(tcp.SrcPort));
+        let dst = SocketAddr::new(self.dst_addr(), u16::from_be(tcp.DstPort));
+        let (local, remote) = if outbound { (src, dst) } else { (dst, src) };
+
+        Segment {
+            key: FlowKey { local, remote },
+            outbound,
+            flags: TcpFlags {
+                syn: tcp.Syn() != 0,
+                ack: tcp.Ack() != 0,
+                fin: tcp.Fin() != 0,
+                rst: tcp.Rst() != 0,
+            },
+            seq: u32::from_be(tcp.SeqNum),
+            payload_length: self.data_length,
+        }
+    }
+
+    /// Set the TTL of an IPv4 packet or the hop limit of an IPv6 packet.
+    pub fn set_ttl(&mut self, ttl: u8) {
+        self.recalc_checksums = true;
+
+        if self.ip_header_ptr.is_null() {
+            unsafe { (*self.ipv6_header_ptr).HopLimit = ttl };
+        } else {
+            unsafe { (*self.ip_header_ptr).TTL = ttl };
+        }
+    }
+
+    /// Adjust the length field of the IP header by the given number of bytes.
+    fn adjust_ip_length(&mut self, diff: isize) {
         self.recalc_checksums = true;
-        unsafe { &mut *self.ip_header_ptr }
+
+        let length = if self.ip_header_ptr.is_null() {
+            unsafe { &mut (*self.ipv6_header_ptr).Length }
+        } else {
+            unsafe { &mut (*self.ip_header_ptr).Length }
+        };
+
+        *length = ((u16::from_be(*length) as isize + diff) as u16).to_be();
     }
 
     /// Get a reference to the TCP header.
@@ -265,6 +393,17 @@ impl<'a> Packet<'a> {
         unsafe { &*self.tcp_header_ptr }
     }
 
+    /// Get a mutable reference to the TCP header.
+    ///
+    /// # Safety
+    /// This function is unsafe because it does not check if the TCP header pointer is null. The
+    /// caller must ensure that this condition is met before calling this function.
+    #[inline]
+    pub fn tcp_header_mut(&mut self) -> &mut WINDIVERT_TCPHDR {
+        self.recalc_checksums = true;
+        unsafe { &mut *self.tcp_header_ptr }
+    }
+
     /// Recalculate the checksums for the packet.
     /// This should be called after modifying the packet data or headers.
     fn calc_checksums(&mut self) -> Result<()> {
@@ -297,9 +436,7 @@ impl<'a> Packet<'a> {
             Ordering::Less | Ordering::Greater => {
                 let diff = data.len() as isize - self.data_length as isize;
 
-                let ip_header = self.ip_header_mut();
-                let length = u16::from_be(ip_header.Length);
-                ip_header.Length = (length as i16 + diff as i16).to_be() as u16;
+                self.adjust_ip_length(diff);
 
                 let length = (self.raw.len() as isize + diff) as usize;
 
@@ -326,6 +463,31 @@ impl<'a> Packet<'a> {
         Ok(())
     }
 
+    /// Create copies of the packet carrying `data` in place of the payload, cut into segments
+    /// at the given ascending positions. Sequence numbers are adjusted so the segments continue
+    /// the original stream.
+    pub fn segments(&self, data: &[u8], positions: &[usize]) -> Result<Vec<Self>> {
+        let seq = u32::from_be(self.tcp_header().SeqNum);
+
+        let mut segments = Vec::with_capacity(positions.len() + 1);
+        let mut start = 0;
+
+        for &end in positions.iter().chain([&data.len()]) {
+            if end <= start || end > data.len() {
+                continue;
+            }
+
+            let mut segment = self.try_clone()?;
+            segment.set_data(&data[start..end])?;
+            segment.tcp_header_mut().SeqNum = seq.wrapping_add(start as u32).to_be();
+            segments.push(segment);
+
+            start = end;
+        }
+
+        Ok(segments)
+    }
+
     /// Create a deep copy of the packet, allocating new memory for the raw data and address.
     pub fn try_clone(&self) -> Result<Self> {
         let raw = Cow::Owned(self.raw.clone().into_owned());
@@ -335,47 +497,680 @@ impl<'a> Packet<'a> {
     }
 }
 
+/// An IPv6 address from the words of a WinDivert header, which are in network byte order.
+fn ipv6_addr(words: [u32; 4]) -> IpAddr {
+    let mut octets = [0; 16];
+    for (chunk, word) in octets.chunks_exact_mut(4).zip(words) {
+        chunk.copy_from_slice(&word.to_ne_bytes());
+    }
+    IpAddr::V6(Ipv6Addr::from(octets))
+}
+
 /// Start intercepting packets and modifying them as necessary.
-pub fn intercept() -> Result<()> {
-    let windivert = WinDivert::open(WINDIVERT_FILTER)?;
-
-    let mut buffer = [0; BUFFER_SIZE];
-    let mut address: WINDIVERT_ADDRESS = unsafe { zeroed() };
-
-    let ttl = get_ttl();
-
-    info!("Intercepting packets");
-
-    loop {
-        match windivert.recv(&mut buffer, &mut address) {
-            Ok(packet) => {
-                match u16::from_be(packet.tcp_header().DstPort) {
-                    // HTTP
-                    80 => {
-                        if packet.data().is_some() {
-                            let mut packet_copy = packet.try_clone()?;
-                            packet_copy.set_data(FAKE_HTTP_REQUEST)?;
-                            packet_copy.ip_header_mut().TTL = ttl;
-                            windivert.send(packet_copy)?;
-                        }
+///
+/// The settings are reloaded while packets keep flowing when they change on disk or when a
+/// reload is requested through `reload`.
+pub fn intercept(reload: Receiver<()>) -> Result<()> {
+    let store = open_store()?;
+    let settings = store.load()?;
+    let config = Config::load(&*store, &settings)?;
+
+    let (config_tx, config_rx) = mpsc::channel();
+    let watcher = SettingsWatcher::new(store, &settings);
+    thread::spawn(move || reload::watch_settings(watcher, reload, config_tx));
+
+    let mut interceptor = Interceptor::new(config)?;
+    info!("Using profile {}", interceptor.profile);
+    interceptor.run(&config_rx)
+}
+
+/// Everything the packets are handled with that can be reloaded.
+pub struct Config {
+    ports: Ports,
+    addresses: CidrFilter,
+    strategies: Strategies,
+    hosts: HostFilter,
+    /// The selected profile and the scheduled ones, by name.
+    profiles: HashMap<String, ActiveProfile>,
+    schedule: Schedule,
+    conntrack: Conntrack,
+    blocking: Blocking,
+    /// File of the domains learned to look blocked.
+    auto_hostlist: PathBuf,
+    /// Whether the outcome of flows is observed.
+    observe: bool,
+    /// Chains tried in turn for domains that stay blocked.
+    candidates: Vec<Vec<Step>>,
+    retransmission: Retransmission,
+    /// File of the learned state.
+    state: PathBuf,
+    /// How long the chain chosen for a domain is kept.
+    decision_ttl: Option<Duration>,
+    injection: Injection,
+    dns: Dns,
+    dns_guard: DnsGuard,
+}
+
+impl Config {
+    /// Validate the settings and load the hostlists they refer to.
+    pub fn load(store: &dyn SettingsStore, settings: &Settings) -> Result<Self> {
+        settings.validate()?;
+
+        let lists = &settings.lists;
+        let schedule = Schedule::new(settings);
+
+        let mut profiles = HashMap::new();
+        for name in schedule.profiles() {
+            if !profiles.contains_key(name) {
+                let profile = settings
+                    .profile(name)
+                    .with_context(|| format!("Profile {name:?} doesn't exist"))?;
+                profiles.insert(name.to_owned(), ActiveProfile::load(name, &profile, store)?);
+            }
+        }
+
+        Ok(Self {
+            ports: settings.ports.clone(),
+            addresses: CidrFilter::load(
+                &settings.addresses.include,
+                &settings.addresses.exclude,
+                &store.resolve(&lists.cidr),
+                &store.resolve(&lists.cidr_exclude),
+            )?,
+            strategies: settings.strategies.clone(),
+            hosts: HostFilter::load(
+                &store.resolve(&lists.hostlist),
+                &store.resolve(&lists.exclude),
+            )?,
+            profiles,
+            schedule,
+            conntrack: settings.conntrack.clone(),
+            blocking: settings.blocking.clone(),
+            auto_hostlist: store.resolve(&lists.auto_hostlist),
+            observe: settings.observes_outcomes(),
+            candidates: settings.candidate_chains(),
+            retransmission: settings.retransmission.clone(),
+            state: store.resolve(&lists.state),
+            decision_ttl: settings.adaptive.ttl(),
+            injection: settings.injection.clone(),
+            dns: settings.dns.clone(),
+            dns_guard: settings.dns_guard.clone(),
+        })
+    }
+}
+
+/// The capture handle and the settings used while intercepting packets.
+struct Interceptor {
+    windivert: WinDivert,
+    /// Filter the capture handle was opened with.
+    filter: String,
+    config: Config,
+    /// Name of the profile in use.
+    profile: String,
+    clock: Box<dyn Clock>,
+    flows: FlowTable,
+    /// Domains learned to look blocked, desynced like the included ones.
+    auto: AutoHostlist,
+    /// Chains chosen for the domains that stayed blocked.
+    adaptive: StrategyCache,
+    /// Limits on the fakes, taken from while sending.
+    limiter: RefCell<InjectionLimiter>,
+    /// Answers the DNS queries if they are resolved.
+    dns: Option<DnsWorker>,
+    /// Drops forged DNS answers if they are guarded against.
+    guard: Option<SpoofGuard>,
+    /// When the schedule and the flow timeouts were last checked.
+    ticked: Instant,
+}
+
+fn flow_table(limits: &Conntrack) -> FlowTable {
+    FlowTable::new(
+        limits.max_flows,
+        Duration::from_secs(limits.idle_timeout),
+        Duration::from_secs(limits.close_timeout),
+    )
+}
+
+fn auto_hostlist(config: &Config) -> Result<AutoHostlist> {
+    AutoHostlist::load(
+        config.auto_hostlist.clone(),
+        config.blocking.fail_threshold,
+        Duration::from_secs(config.blocking.fail_window),
+    )
+}
+
+fn dns_worker(settings: &Dns) -> Result<Option<DnsWorker>> {
+    settings
+        .enabled
+        .then(|| DnsWorker::spawn(settings))
+        .transpose()
+}
+
+fn strategy_cache(config: &Config) -> Result<StrategyCache> {
+    StrategyCache::load(
+        config.state.clone(),
+        config.candidates.clone(),
+        config.blocking.fail_threshold,
+        config.decision_ttl,
+    )
+}
+
+/// What was done with the first payload of a flow.
+#[derive(Default)]
+struct Handled {
+    /// Host name found in the payload.
+    host: Option<String>,
+    /// Steps applied to the payload, `None` if it was passed through.
+    chain: Option<Vec<Step>>,
+    /// The payload is a ClientHello with an encrypted client hello.
+    ech: bool,
+}
+
+impl Interceptor {
+    /// Open the capture handle for the configuration and load the learned state.
+    fn new(config: Config) -> Result<Self> {
+        let filter = windivert_filter(&config);
+        let clock = Box::new(SystemClock);
+
+        Ok(Self {
+            windivert: WinDivert::open(&filter)?,
+            filter,
+            profile: config.schedule.profile_at(&clock.now()).to_owned(),
+            flows: flow_table(&config.conntrack),
+            auto: auto_hostlist(&config)?,
+            adaptive: strategy_cache(&config)?,
+            limiter: RefCell::new(InjectionLimiter::new(&config.injection)),
+            dns: dns_worker(&config.dns)?,
+            guard: config
+                .dns_guard
+                .enabled
+                .then(|| SpoofGuard::new(&config.dns_guard)),
+            ticked: Instant::now(),
+            clock,
+            config,
+        })
+    }
+
+    /// Handle captured packets until receiving fails, applying the configurations sent
+    /// through `configs` as they come.
+    fn run(&mut self, configs: &Receiver<Config>) -> Result<()> {
+        let mut buffer = [0; BUFFER_SIZE];
+        let mut address: WINDIVERT_ADDRESS = unsafe { zeroed() };
+
+        info!("Intercepting packets");
+
+        loop {
+            let packet = self.windivert.recv(&mut buffer, &mut address);
+
+            // the packet was captured with the old settings, but is handled with the new ones
+            if let Some(config) = configs.try_iter().last() {
+                self.apply(config)?;
+            }
+            self.tick();
+
+            match packet {
+                Ok(packet) => {
+                    STATS.packets.increment();
+
+                    if let Some(dns) = &self.dns
+                        && dns.submit(&packet.raw, &packet.addr)
+                    {
+                        continue;
                     }
-                    // HTTPS
-                    443 => {
-                        if is_client_hello(&packet) {
-                            let mut packet_copy = packet.try_clone()?;
-                            packet_copy.set_data(FAKE_CLIENT_HELLO)?;
-                            packet_copy.ip_header_mut().TTL = ttl;
-                            windivert.send(packet_copy)?;
+                    if let Some(guard) = &mut self.guard
+                        && packet.tcp_header_ptr.is_null()
+                    {
+                        let outbound = packet.addr.Outbound() != 0;
+
+                        match guard.inspect(&packet.raw, outbound, Instant::now()) {
+                            Verdict::Drop(spoof) => {
+                                debug!("Dropped a forged DNS answer ({spoof})");
+                                continue;
+                            }
+                            Verdict::Pass | Verdict::Accept => {}
                         }
                     }
-                    _ => unreachable!(),
+                    self.handle(packet)?;
+                }
+                Err(e) => {
+                    bail!("Failed to receive packet: {e:?}");
+                }
+            }
+        }
+    }
+
+    /// Switch to a reloaded configuration, reopening the capture handle if the filter changed.
+    fn apply(&mut self, config: Config) -> Result<()> {
+        let filter = windivert_filter(&config);
+
+        if filter != self.filter {
+            // packets still queued in the old handle are dropped and retransmitted by TCP
+            match WinDivert::open(&filter) {
+                Ok(windivert) => {
+                    self.windivert = windivert;
+                    self.filter = filter;
+                }
+                Err(e) => {
+                    warn!("Keeping the running configuration, failed to reopen the capture: {e:?}");
+                    return Ok(());
                 }
+            }
+        }
+
+        if config.auto_hostlist != self.auto.path() {
+            match auto_hostlist(&config) {
+                Ok(auto) => self.auto = auto,
+                Err(e) => warn!("Keeping the previous auto-hostlist: {e:?}"),
+            }
+        }
+        self.auto.set_thresholds(
+            config.blocking.fail_threshold,
+            Duration::from_secs(config.blocking.fail_window),
+        );
+
+        // also picks up decisions reset from the command line
+        match strategy_cache(&config) {
+            Ok(adaptive) => self.adaptive = adaptive,
+            Err(e) => warn!("Keeping the previous strategy cache: {e:?}"),
+        }
+
+        self.limiter
+            .get_mut()
+            .set_limits(&config.injection, Instant::now());
+
+        if config.dns != self.config.dns {
+            match dns_worker(&config.dns) {
+                Ok(dns) => self.dns = dns,
+                Err(e) => warn!("Keeping the previous DNS resolver: {e:?}"),
+            }
+        }
+
+        // what was learned of the servers is kept across reloads
+        match (&mut self.guard, config.dns_guard.enabled) {
+            (Some(guard), true) => guard.set_settings(&config.dns_guard),
+            (None, true) => self.guard = Some(SpoofGuard::new(&config.dns_guard)),
+            (_, false) => self.guard = None,
+        }
+
+        self.config = config;
+        self.profile = self.scheduled_profile();
+
+        let limits = &self.config.conntrack;
+        self.flows.set_limits(
+            limits.max_flows,
+            Duration::from_secs(limits.idle_timeout),
+            Duration::from_secs(limits.close_timeout),
+        );
+        info!("Applied the reloaded settings (profile: {})", self.profile);
+
+        Ok(())
+    }
+
+    fn scheduled_profile(&self) -> String {
+        self.config
+            .schedule
+            .profile_at(&self.clock.now())
+            .to_owned()
+    }
+
+    /// Switch to the profile of the schedule, expire idle flows, and sync and compact the state
+    /// store, at most every `TICK_INTERVAL`.
+    fn tick(&mut self) {
+        if self.ticked.elapsed() < TICK_INTERVAL {
+            return;
+        }
+        self.ticked = Instant::now();
+
+        self.flows.expire(self.ticked);
+        self.limiter.get_mut().expire(self.ticked);
+        if let Some(guard) = &mut self.guard {
+            guard.expire(self.ticked);
+        }
+
+        if let Err(e) = self.adaptive.sync() {
+            warn!("Failed to sync the state store: {e:?}");
+        }
+        if let Err(e) = self.adaptive.maybe_compact() {
+            warn!("Failed to compact the state store: {e:?}");
+        }
+
+        let profile = self.scheduled_profile();
 
-                windivert.send(packet)?;
+        if profile != self.profile {
+            info!("Switching to profile {profile} on schedule");
+            self.profile = profile;
+        }
+    }
+
+    /// Track the flow of the packet and desync its first payload. Handshake and teardown
+    /// segments are only tracked, and later payloads of the flow pass through.
+    fn handle(&mut self, packet: Packet<'_>) -> Result<()> {
+        // DNS packets pass once guarded, or were captured by a handle opened before the
+        // resolver or the guard were disabled
+        if packet.tcp_header_ptr.is_null() {
+            return self.windivert.send(packet);
+        }
+
+        let now = Instant::now();
+        let segment = packet.segment();
+        let mut handled = false;
+        let mut learned = None;
+        let mut retransmitted = None;
+
+        if let Some(flow) = self.flows.track(&segment, now) {
+            handled = flow.first_payload;
+
+            if handled
+                && segment.outbound
+                && segment.payload_length > 0
+                && flow.first_seq == Some(segment.seq)
+                && !flow.chain.is_empty()
+            {
+                retransmitted = Some(flow.chain.clone());
+            }
+
+            // flows that weren't desynced feed the auto-hostlist, desynced ones the adaptive
+            // choice of their chain
+            let observed = if flow.chain.is_empty() {
+                self.config.blocking.auto_hostlist
+            } else {
+                self.adaptive.is_enabled()
+            };
+
+            if observed
+                && handled
+                && flow.outcome.is_none()
+                && let Some(host) = &flow.host
+            {
+                let outcome = if !segment.outbound && segment.payload_length > 0 {
+                    Some(response_outcome(host, packet.data_unchecked()))
+                } else if !segment.outbound && segment.flags.rst {
+                    Some(Outcome::Reset)
+                } else if flow.retransmissions >= self.config.blocking.retransmissions {
+                    Some(Outcome::Retransmitted)
+                } else {
+                    None
+                };
+
+                flow.outcome = outcome;
+                learned = outcome.map(|outcome| (host.clone(), flow.chain.clone(), outcome));
+            }
+        }
+
+        if let Some((host, chain, outcome)) = learned {
+            self.learn(&host, &chain, outcome, now);
+        }
+
+        if let Some(chain) = retransmitted {
+            return self.handle_retransmission(packet, &segment, chain);
+        }
+
+        if !segment.outbound || segment.payload_length == 0 || handled {
+            return self.windivert.send(packet);
+        }
+
+        // networks that didn't fit in the filter are only excluded here
+        if !self.config.addresses.matches(segment.key.remote.ip()) {
+            return self.windivert.send(packet);
+        }
+
+        // ports captured by a handle opened before the settings changed are unknown
+        let detected = self
+            .config
+            .ports
+            .classify(segment.key.remote.port(), packet.data_unchecked());
+
+        let handled = match detected {
+            Detected::Http => self.handle_http(packet)?,
+            Detected::Tls => self.handle_tls(packet)?,
+            // SSH and unknown traffic passes through untouched
+            Detected::Ssh | Detected::Unknown => {
+                self.windivert.send(packet)?;
+                Handled::default()
             }
-            Err(e) => {
-                bail!("Failed to receive packet: {e:?}");
+        };
+
+        if let Some(flow) = self.flows.get_mut(&segment.key) {
+            // counted once per flow, retransmissions of the first payload aren't handled again
+            if handled.ech {
+                STATS.ech_flows.increment();
             }
+            flow.first_payload = true;
+            flow.first_seq = Some(segment.seq);
+            // excluded domains are never learned
+            flow.host = handled
+                .host
+                .filter(|host| !self.config.hosts.exclude.matches(host));
+            flow.chain = handled.chain.unwrap_or_default();
         }
+
+        Ok(())
+    }
+
+    /// Record the outcome of a flow desynced with the chain, empty if it wasn't desynced.
+    fn learn(&mut self, host: &str, chain: &[Step], outcome: Outcome, now: Instant) {
+        let result = if chain.is_empty() {
+            self.auto.record(host, outcome, now).map(drop)
+        } else {
+            self.adaptive.record(host, chain, outcome)
+        };
+
+        if let Err(e) = result {
+            warn!("Failed to save the outcome of a flow to {host}: {e:?}");
+        }
+    }
+
+    /// Send a copy of the packet carrying the fake payload with a low TTL.
+    fn send_fake(&self, packet: &Packet<'_>, fake: &[u8], ttl: Option<u8>) -> Result<()> {
+        let mut packet_copy = packet.try_clone()?;
+        packet_copy.set_data(fake)?;
+        packet_copy.set_ttl(ttl.unwrap_or(self.config.strategies.ttl));
+        self.windivert.send(packet_copy)?;
+
+        STATS.fakes.increment();
+
+        Ok(())
+    }
+
+    /// The strategy chain of the active profile for the flow of the packet, replaced by the
+    /// candidate chosen for its domain if it stayed blocked.
+    fn select(
+        &self,
+        packet: &Packet<'_>,
+        protocol: Protocol,
+        host: Option<&str>,
+    ) -> Option<&[Step]> {
+        let chain = self.config.profiles.get(&self.profile)?.select(&Flow {
+            port: u16::from_be(packet.tcp_header().DstPort),
+            protocol,
+            host,
+            addr: packet.dst_addr(),
+        })?;
+
+        // flows the profile passes through stay untouched
+        if chain.is_empty() {
+            return Some(chain);
+        }
+
+        Some(
+            host.and_then(|host| self.adaptive.chain(host))
+                .unwrap_or(chain),
+        )
+    }
+
+    /// Check if a flow to the host should be desynced, either because of the hostlists or
+    /// because the domain was learned to look blocked.
+    fn matches_host(&self, host: Option<&str>) -> bool {
+        let hosts = &self.config.hosts;
+
+        hosts.matches(host)
+            || host.is_some_and(|host| self.auto.matches(host) && !hosts.exclude.matches(host))
+    }
+
+    /// Desync packets carrying the start of an HTTP request.
+    fn handle_http(&self, packet: Packet<'_>) -> Result<Handled> {
+        let mut requests = parse_http_requests(packet.data().unwrap_or_default()).peekable();
+
+        // continuation segments (e.g. large POST bodies) carry no request line
+        let host = requests
+            .peek()
+            .and_then(|request| request.hostname())
+            .map(str::to_owned);
+        let chain = requests
+            .peek()
+            .filter(|request| self.matches_host(request.hostname()))
+            .and_then(|request| self.select(&packet, Protocol::Http, request.hostname()));
+
+        for request in requests {
+            debug!(
+                "HTTP request at offset {}: {} {} (host: {})",
+                request.start,
+                request.method(),
+                request.target(),
+                request.host().unwrap_or("<none>"),
+            );
+        }
+
+        let chain = match chain {
+            Some(chain) => self
+                .apply_chain(packet, chain, FAKE_HTTP_REQUEST)?
+                .then(|| chain.to_vec()),
+            None => {
+                self.windivert.send(packet)?;
+                None
+            }
+        };
+
+        Ok(Handled {
+            host,
+            chain,
+            ech: false,
+        })
+    }
+
+    /// Desync packets carrying a TLS ClientHello.
+    fn handle_tls(&self, packet: Packet<'_>) -> Result<Handled> {
+        let Some(hello) = parse_client_hello(packet.data_unchecked()) else {
+            self.windivert.send(packet)?;
+            return Ok(Handled::default());
+        };
+
+        let name = hello.server_name();
+        let host = name.map(str::to_owned);
+
+        let ech = hello.ech;
+        let desync = if ech {
+            match self.config.strategies.ech {
+                EchPolicy::OuterSni => self.matches_host(name),
+                EchPolicy::Pass => false,
+                EchPolicy::Fake => true,
+            }
+        } else {
+            self.matches_host(name)
+        };
+
+        let chain = desync
+            .then(|| self.select(&packet, Protocol::Tls, name))
+            .flatten();
+
+        let Some(chain) = chain else {
+            self.windivert.send(packet)?;
+            return Ok(Handled {
+                host,
+                chain: None,
+                ech,
+            });
+        };
+
+        let desynced = self.apply_chain(packet, chain, FAKE_CLIENT_HELLO)?;
+        Ok(Handled {
+            host,
+            chain: desynced.then(|| chain.to_vec()),
+            ech,
+        })
+    }
+
+    /// Handle a retransmission of a first payload that was desynced with the chain, as
+    /// configured by `retransmission.action`.
+    fn handle_retransmission(
+        &self,
+        packet: Packet<'_>,
+        segment: &Segment,
+        chain: Vec<Step>,
+    ) -> Result<()> {
+        let action = self.config.retransmission.action;
+        debug!(
+            "Retransmission of the first payload to {}: {action:?}",
+            segment.key.remote
+        );
+
+        let chain = match action {
+            RetransmitAction::Repeat => {
+                STATS.retransmissions_repeated.increment();
+                chain
+            }
+            RetransmitAction::Escalate => {
+                STATS.retransmissions_escalated.increment();
+                self.config.retransmission.escalation.clone()
+            }
+            RetransmitAction::Pass => {
+                STATS.retransmissions_passed.increment();
+                return self.windivert.send(packet);
+            }
+        };
+
+        let detected = self
+            .config
+            .ports
+            .classify(segment.key.remote.port(), packet.data_unchecked());
+
+        let fake = match detected {
+            Detected::Http => FAKE_HTTP_REQUEST,
+            Detected::Tls => FAKE_CLIENT_HELLO,
+            Detected::Ssh | Detected::Unknown => return self.windivert.send(packet),
+        };
+
+        self.apply_chain(packet, &chain, fake)?;
+        Ok(())
+    }
+
+    /// Apply the steps of a chain and send the packet, in segments if needed.
+    ///
+    /// Returns `false` if the injection
+    /// limit was reached and the packet was sent as it is.
+    fn apply_chain(&self, packet: Packet<'_>, chain: &[Step], fake: &[u8]) -> Result<bool> {
+        let desync = desync(packet.data_unchecked(), chain, fake);
+
+        // the flow goes through without desync rather than not at all
+        let fakes = desync.fakes.len() as u32;
+        if fakes > 0
+            && !self
+                .limiter
+                .borrow_mut()
+                .allow(packet.dst_addr(), fakes, Instant::now())
+        {
+            debug!(
+                "Injection limit reached, passing a flow to {}",
+                packet.dst_addr()
+            );
+            STATS.fakes_limited.increment();
+            self.windivert.send(packet)?;
+            return Ok(false);
+        }
+
+        for &ttl in &desync.fakes {
+            self.send_fake(&packet, &desync.fake, ttl)?;
+        }
+
+        if desync.positions.is_empty() {
+            self.windivert.send(packet)?;
+            return Ok(true);
+        }
+
+        for segment in packet.segments(&desync.payload, &desync.positions)? {
+            self.windivert.send(segment)?;
+        }
+
+        Ok(true)
     }
 }
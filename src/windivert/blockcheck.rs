@@ -0,0 +1,97 @@
+use std::{
+    net::SocketAddr,
+    sync::mpsc,
+    thread::{self, JoinHandle},
+};
+
+use color_eyre::{Report as Error, Result, eyre::eyre};
+use log::{info, warn};
+use packetmock::{
+    blockcheck::{
+        BlockcheckOptions, Report, combinations, default_chains, probe, probe_addresses,
+        probe_settings,
+    },
+    hostlist::HostFilter,
+    settings::open_store,
+    strategy::Step,
+};
+
+use super::{Config, Interceptor};
+use crate::service::{ServiceState, query_service};
+
+/// Probe the domain with every combination of chain and TTL, desyncing the probes with an
+/// interceptor of their own that is reconfigured before each of them.
+pub fn blockcheck(options: &BlockcheckOptions) -> Result<Report> {
+    if let Ok(ServiceState::Running) = query_service() {
+        warn!("The service is running, its desync applies to the probes as well");
+    }
+
+    let store = open_store()?;
+    let settings = store.load()?;
+
+    let targets = options
+        .protocols
+        .iter()
+        .map(|&protocol| Ok((protocol, options.target(protocol)?)))
+        .collect::<Result<Vec<_>>>()?;
+    let addresses = targets
+        .iter()
+        .map(|&(_, target)| target)
+        .collect::<Vec<SocketAddr>>();
+
+    let candidates = match settings.candidate_chains() {
+        chains if chains.is_empty() => default_chains(),
+        chains => chains,
+    };
+
+    // only the probes are desynced, whatever the hostlists and network lists say
+    let load = |chain: &[Step]| -> Result<Config> {
+        let settings = probe_settings(&settings, chain, &addresses);
+        let mut config = Config::load(&*store, &settings)?;
+        config.addresses = probe_addresses(&addresses)?;
+        config.hosts = HostFilter::default();
+        Ok(config)
+    };
+
+    let (config_tx, config_rx) = mpsc::channel();
+    let (ready_tx, ready_rx) = mpsc::channel();
+    let first = load(&[])?;
+
+    // the capture handle is closed when the process exits
+    let interceptor = thread::spawn(move || -> Result<()> {
+        let mut interceptor = Interceptor::new(first)?;
+        let _ = ready_tx.send(());
+        interceptor.run(&config_rx)
+    });
+
+    if ready_rx.recv().is_err() {
+        return Err(stopped(interceptor));
+    }
+
+    let mut report = Report::new(&options.domain);
+
+    for &(protocol, target) in &targets {
+        for chain in combinations(&candidates, &options.ttls) {
+            // applied when the interceptor captures the first packet of the probe
+            if config_tx.send(load(&chain)?).is_err() || interceptor.is_finished() {
+                return Err(stopped(interceptor));
+            }
+
+            let result = report.run(protocol, target, &chain, || {
+                probe(target, &options.domain, protocol, options.timeout)
+            });
+            let steps = chain.iter().map(Step::to_string).collect::<Vec<_>>();
+            info!("{protocol:?} [{}]: {}", steps.join(", "), result.verdict);
+        }
+    }
+
+    Ok(report)
+}
+
+/// The error the interceptor stopped with.
+fn stopped(interceptor: JoinHandle<Result<()>>) -> Error {
+    match interceptor.join() {
+        Ok(Err(e)) => e.wrap_err("The interceptor stopped"),
+        _ => eyre!("The interceptor stopped"),
+    }
+}
@@ -0,0 +1,98 @@
+use std::{
+    sync::mpsc::{self, Receiver, SyncSender, TrySendError},
+    thread,
+    time::Instant,
+};
+
+use color_eyre::{Result, eyre::Context};
+use log::{debug, info, warn};
+use packetmock::{
+    backend::Backend,
+    dns::{DnsForwarder, packet::parse_dns_packet},
+    settings::Dns,
+};
+use windivert_sys::WINDIVERT_ADDRESS;
+
+use super::WinDivert;
+
+/// Queries waiting for the resolver at most, more are dropped for their clients to retry.
+const MAX_QUEUED: usize = 256;
+
+/// Answers the captured DNS queries on a thread of its own, so waiting for the resolver
+/// doesn't hold up the other packets.
+///
+/// The queries are dropped, and the answers are injected inbound from their server through a
+/// handle that captures nothing.
+pub struct DnsWorker {
+    queries: SyncSender<(Vec<u8>, WINDIVERT_ADDRESS)>,
+}
+
+impl DnsWorker {
+    /// Start answering queries with the settings. The thread stops once the worker is dropped.
+    pub fn spawn(settings: &Dns) -> Result<Self> {
+        let forwarder = DnsForwarder::new(settings)?;
+        let (queries, queries_rx) = mpsc::sync_channel(MAX_QUEUED);
+        let (ready_tx, ready_rx) = mpsc::channel();
+
+        // handles can't be moved to another thread
+        thread::spawn(move || match WinDivert::open("false") {
+            Ok(windivert) => {
+                let _ = ready_tx.send(Ok(()));
+                answer_queries(windivert, forwarder, &queries_rx);
+            }
+            Err(e) => {
+                let _ = ready_tx.send(Err(e));
+            }
+        });
+
+        ready_rx
+            .recv()
+            .wrap_err("The DNS thread stopped before starting")??;
+        info!("Resolving DNS queries through {}", settings.resolver);
+
+        Ok(Self { queries })
+    }
+
+    /// Answer the packet if it is a DNS query, returning `false` if it isn't one.
+    pub fn submit(&self, raw: &[u8], addr: &WINDIVERT_ADDRESS) -> bool {
+        if addr.Outbound() == 0 || parse_dns_packet(raw).is_none() {
+            return false;
+        }
+
+        match self.queries.try_send((raw.to_vec(), *addr)) {
+            Ok(()) => {}
+            Err(TrySendError::Full(_)) => debug!("Dropped a DNS query, the resolver is behind"),
+            // the thread only stops when the worker is dropped
+            Err(TrySendError::Disconnected(_)) => {}
+        }
+        true
+    }
+}
+
+fn answer_queries(
+    windivert: WinDivert,
+    mut forwarder: DnsForwarder,
+    queries: &Receiver<(Vec<u8>, WINDIVERT_ADDRESS)>,
+) {
+    for (raw, addr) in queries {
+        let Some(packet) = parse_dns_packet(&raw) else {
+            continue;
+        };
+        let Some(answer) = forwarder.answer(packet.message, packet.udp, Instant::now()) else {
+            // not a standard query, let the server answer it
+            if let Err(e) = windivert.inject(&raw, &addr) {
+                warn!("Failed to pass a DNS message through: {e:?}");
+            }
+            continue;
+        };
+
+        // same interface, the other way
+        let mut reply_addr = addr;
+        reply_addr.set_Outbound(0);
+
+        match windivert.inject(&packet.reply(&answer), &reply_addr) {
+            Ok(()) => debug!("Answered a DNS query from {}", packet.source),
+            Err(e) => warn!("Failed to inject a DNS answer: {e:?}"),
+        }
+    }
+}
@@ -0,0 +1,52 @@
+use color_eyre::{Result, eyre::Context};
+use log::error;
+use packetmock::{
+    schedule::{Schedule, ScheduleStatus, SystemClock},
+    settings::{Settings, open_store},
+};
+
+use crate::service::{ServiceState, query_service, reload_service};
+
+fn load_settings() -> Settings {
+    match open_store().and_then(|store| store.load()) {
+        Ok(settings) => settings,
+        Err(e) => {
+            error!("Failed to load settings: {e:?}");
+            Settings::default()
+        }
+    }
+}
+
+/// Names of the profiles that can be activated, and the name of the selected one.
+pub fn get_profiles() -> (Vec<String>, String) {
+    let settings = load_settings();
+
+    let names = settings
+        .profile_names()
+        .into_iter()
+        .map(str::to_owned)
+        .collect();
+
+    (names, settings.active_profile)
+}
+
+/// The profile in use according to the schedule, and when it changes next.
+pub fn get_schedule_status() -> ScheduleStatus {
+    Schedule::new(&load_settings()).status(&SystemClock)
+}
+
+pub fn set_active_profile(name: &str) -> Result<()> {
+    let store = open_store()?;
+
+    let mut settings = store.load()?;
+    settings.active_profile = name.to_owned();
+    settings.validate()?;
+    store.save(&settings)?;
+
+    // the service also notices the change by itself, this only makes it immediate
+    if let Ok(ServiceState::Running) = query_service() {
+        reload_service().wrap_err("Failed to reload the service settings")?;
+    };
+
+    Ok(())
+}
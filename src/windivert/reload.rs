@@ -0,0 +1,50 @@
+use std::{
+    sync::mpsc::{Receiver, RecvTimeoutError, Sender},
+    time::Duration,
+};
+
+use log::{error, info};
+use packetmock::settings::SettingsWatcher;
+
+use super::Config;
+
+/// How often the settings are checked for changes.
+const POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Send a new configuration to the interceptor whenever the settings change or a reload is
+/// requested. Invalid settings are logged and the running configuration is kept.
+///
+/// Returns once the interceptor or the requester is gone.
+pub fn watch_settings(
+    mut watcher: SettingsWatcher,
+    requests: Receiver<()>,
+    configs: Sender<Config>,
+) {
+    loop {
+        let settings = match requests.recv_timeout(POLL_INTERVAL) {
+            Ok(()) => {
+                info!("Reloading settings on request");
+                watcher.reload()
+            }
+            Err(RecvTimeoutError::Timeout) => match watcher.poll() {
+                Some(settings) => {
+                    info!("Settings changed, reloading");
+                    settings
+                }
+                None => continue,
+            },
+            Err(RecvTimeoutError::Disconnected) => return,
+        };
+
+        let config = settings.and_then(|settings| Config::load(watcher.store(), &settings));
+
+        match config {
+            Ok(config) => {
+                if configs.send(config).is_err() {
+                    return;
+                }
+            }
+            Err(e) => error!("Keeping the running settings, the new ones are invalid: {e:?}"),
+        }
+    }
+}
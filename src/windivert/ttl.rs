@@ -1,31 +1,30 @@
 use color_eyre::{Result, eyre::Context};
-use windows_registry::LOCAL_MACHINE;
+use log::error;
+use packetmock::settings::{Strategies, open_store};
 
-use crate::{
-    REGISTRY_NAME,
-    service::{ServiceState, query_service, start_service, stop_service},
-};
-
-const DEFAULT_TTL: u8 = 4;
+use crate::service::{ServiceState, query_service, reload_service};
 
 pub fn get_ttl() -> u8 {
-    let Ok(key) = LOCAL_MACHINE.open(format!("Software\\{REGISTRY_NAME}")) else {
-        return DEFAULT_TTL;
-    };
-
-    match key.get_u32("TTL") {
-        Ok(ttl) => ttl as u8,
-        Err(_) => DEFAULT_TTL,
+    match open_store().and_then(|store| store.load()) {
+        Ok(settings) => settings.strategies.ttl,
+        Err(e) => {
+            error!("Failed to load settings: {e:?}");
+            Strategies::default().ttl
+        }
     }
 }
 
 pub fn set_ttl(ttl: u8) -> Result<()> {
-    let key = LOCAL_MACHINE.create(format!("Software\\{REGISTRY_NAME}"))?;
-    key.set_u32("TTL", ttl as u32)?;
+    let store = open_store()?;
+
+    let mut settings = store.load()?;
+    settings.strategies.ttl = ttl;
+    settings.validate()?;
+    store.save(&settings)?;
 
+    // the service also notices the change by itself, this only makes it immediate
     if let Ok(ServiceState::Running) = query_service() {
-        stop_service().wrap_err("Failed to restart the service")?;
-        start_service().wrap_err("Failed to restart the service")?;
+        reload_service().wrap_err("Failed to reload the service settings")?;
     };
 
     Ok(())
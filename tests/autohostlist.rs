@@ -0,0 +1,68 @@
+use std::{
+    env::temp_dir,
+    fs,
+    path::PathBuf,
+    time::{Duration, Instant},
+};
+
+use packetmock::autohostlist::{AutoHostlist, Outcome};
+
+const WINDOW: Duration = Duration::from_secs(60);
+
+/// A path in the temporary directory that no other test uses, without a file.
+fn list_path(name: &str) -> PathBuf {
+    let path = temp_dir().join(format!("packetmock-{}-{name}.txt", std::process::id()));
+    let _ = fs::remove_file(&path);
+    path
+}
+
+#[test]
+fn learned_domains_survive_a_reload() {
+    let path = list_path("auto-reload");
+    let now = Instant::now();
+
+    let mut list = AutoHostlist::load(path.clone(), 2, WINDOW).unwrap();
+    assert!(!list.record("Example.com", Outcome::Reset, now).unwrap());
+    assert!(list.record("example.com", Outcome::Reset, now).unwrap());
+    assert!(!list.record("blocked.org", Outcome::Reset, now).unwrap());
+    assert!(list.record("blocked.org", Outcome::Blockpage, now).unwrap());
+
+    let list = AutoHostlist::load(path.clone(), 2, WINDOW).unwrap();
+    assert_eq!(
+        list.domains().collect::<Vec<_>>(),
+        ["blocked.org", "example.com"]
+    );
+    assert!(list.matches("www.example.com"));
+    assert!(!path.with_extension("tmp").exists());
+
+    fs::remove_file(path).unwrap();
+}
+
+#[test]
+fn interrupted_saves_keep_the_previous_list() {
+    let path = list_path("auto-interrupted");
+    let now = Instant::now();
+
+    let mut list = AutoHostlist::load(path.clone(), 1, WINDOW).unwrap();
+    assert!(list.record("example.com", Outcome::Reset, now).unwrap());
+    let saved = fs::read_to_string(&path).unwrap();
+
+    // a crash while writing the next version
+    let temporary = path.with_extension("tmp");
+    fs::write(&temporary, "blocked.o").unwrap();
+
+    let mut list = AutoHostlist::load(path.clone(), 1, WINDOW).unwrap();
+    assert_eq!(list.domains().collect::<Vec<_>>(), ["example.com"]);
+    assert_eq!(fs::read_to_string(&path).unwrap(), saved);
+
+    // the next save replaces the leftover
+    assert!(list.record("blocked.org", Outcome::Reset, now).unwrap());
+    assert!(!temporary.exists());
+    let list = AutoHostlist::load(path.clone(), 1, WINDOW).unwrap();
+    assert_eq!(
+        list.domains().collect::<Vec<_>>(),
+        ["blocked.org", "example.com"]
+    );
+
+    fs::remove_file(path).unwrap();
+}
@@ -0,0 +1,117 @@
+use color_eyre::Result;
+use packetmock::backend::{Backend, Fwmark, INJECTED_MARK, MemoryBackend, MemoryStack};
+
+/// Stops a runaway loop, far more packets than any test sends.
+const MAX_PACKETS: usize = 1000;
+
+/// A backend that forgets to mark what it sends.
+struct Unmarked(MemoryBackend);
+
+impl Backend for Unmarked {
+    type Meta = Fwmark;
+
+    fn capture(&self, buffer: &mut [u8]) -> Result<(usize, Fwmark)> {
+        self.0.capture(buffer)
+    }
+
+    fn transmit(&self, packet: &[u8], mark: &Fwmark) -> Result<()> {
+        self.0.transmit(packet, mark)
+    }
+
+    fn is_marked(mark: &Fwmark) -> bool {
+        mark.is_injected()
+    }
+
+    fn mark(_: &mut Fwmark) {}
+}
+
+/// Let the instances handle packets until none of them has any left, returning how many were
+/// handled, at most `MAX_PACKETS`.
+fn run(instances: &[&dyn Fn() -> bool]) -> usize {
+    let mut handled = 0;
+
+    while handled < MAX_PACKETS {
+        let busy = instances.iter().filter(|handle| handle()).count();
+        if busy == 0 {
+            break;
+        }
+        handled += busy;
+    }
+
+    handled.min(MAX_PACKETS)
+}
+
+/// Handle a packet like an interceptor desyncing everything: send a fake, then the packet
+/// itself. Returns `false` if no packet was captured.
+fn interceptor<B: Backend>(backend: &B) -> impl Fn() -> bool {
+    move || {
+        let mut buffer = [0; 64];
+        let Ok((len, meta)) = backend.receive(&mut buffer) else {
+            return false;
+        };
+
+        backend.inject(b"fake", &meta).unwrap();
+        backend.inject(&buffer[..len], &meta).unwrap();
+        true
+    }
+}
+
+#[test]
+fn injected_packets_carry_the_mark() {
+    let stack = MemoryStack::default();
+    let backend = stack.open();
+
+    stack.send(b"request");
+    assert_eq!(run(&[&interceptor(&backend)]), 1);
+
+    let sent = stack.sent();
+    assert_eq!(sent.len(), 3);
+    assert_eq!(sent[0], (b"request".to_vec(), Fwmark(0)));
+    assert_eq!(sent[1], (b"fake".to_vec(), Fwmark(INJECTED_MARK)));
+    assert_eq!(sent[2], (b"request".to_vec(), Fwmark(INJECTED_MARK)));
+}
+
+#[test]
+fn other_marks_are_kept() {
+    let mut mark = Fwmark(0x1);
+    mark.set_injected();
+
+    assert_eq!(mark, Fwmark(INJECTED_MARK | 0x1));
+    assert!(mark.is_injected());
+    assert!(!Fwmark(0x1).is_injected());
+}
+
+#[test]
+fn one_instance_doesnt_loop() {
+    let stack = MemoryStack::default();
+    let backend = stack.open();
+
+    for _ in 0..10 {
+        stack.send(b"request");
+    }
+
+    assert_eq!(run(&[&interceptor(&backend)]), 10);
+}
+
+#[test]
+fn two_instances_dont_loop() {
+    let stack = MemoryStack::default();
+    let first = stack.open();
+    let second = stack.open();
+
+    stack.send(b"request");
+
+    // each instance handles the request, and neither the injections of the other
+    let handled = run(&[&interceptor(&first), &interceptor(&second)]);
+    assert_eq!(handled, 2);
+}
+
+#[test]
+fn unmarked_injections_loop() {
+    let stack = MemoryStack::default();
+    let backend = Unmarked(stack.open());
+
+    stack.send(b"request");
+
+    assert_eq!(run(&[&interceptor(&backend)]), MAX_PACKETS);
+}
@@ -0,0 +1,168 @@
+use std::net::IpAddr;
+
+use packetmock::cidr::{Cidr, CidrFilter, CidrSet, DEFAULT_EXCLUDE};
+
+fn addr(addr: &str) -> IpAddr {
+    addr.parse().unwrap()
+}
+
+fn cidr_set(networks: &[&str]) -> CidrSet {
+    let networks = networks
+        .iter()
+        .map(|cidr| cidr.parse::<Cidr>().unwrap())
+        .collect::<Vec<_>>();
+
+    CidrSet::new(&networks)
+}
+
+fn networks(set: &CidrSet) -> Vec<String> {
+    set.networks().iter().map(Cidr::to_string).collect()
+}
+
+#[test]
+fn parsing_clears_the_host_bits() {
+    let cidr = "10.1.2.3/8".parse::<Cidr>().unwrap();
+    assert_eq!(cidr.to_string(), "10.0.0.0/8");
+    assert_eq!(cidr.last(), addr("10.255.255.255"));
+
+    let cidr = "fe80::1234/10".parse::<Cidr>().unwrap();
+    assert_eq!(cidr.to_string(), "fe80::/10");
+    assert_eq!(cidr.last(), addr("febf:ffff:ffff:ffff:ffff:ffff:ffff:ffff"));
+
+    // bare addresses are networks of their own
+    assert_eq!("192.0.2.1".parse::<Cidr>().unwrap().prefix(), 32);
+    assert_eq!("2001:db8::1".parse::<Cidr>().unwrap().prefix(), 128);
+
+    for invalid in [
+        "10.0.0.0/33",
+        "::/129",
+        "10.0.0/8",
+        "10.0.0.0/",
+        "example.com",
+    ] {
+        assert!(invalid.parse::<Cidr>().is_err(), "{invalid}");
+    }
+}
+
+#[test]
+fn sets_contain_their_networks() {
+    let set = cidr_set(&["10.0.0.0/8", "192.0.2.1", "2001:db8::/32"]);
+
+    assert!(set.contains(addr("10.0.0.0")));
+    assert!(set.contains(addr("10.255.255.255")));
+    assert!(!set.contains(addr("11.0.0.0")));
+    assert!(!set.contains(addr("9.255.255.255")));
+    assert!(set.contains(addr("192.0.2.1")));
+    assert!(!set.contains(addr("192.0.2.2")));
+
+    assert!(set.contains(addr("2001:db8::1")));
+    assert!(set.contains(addr("2001:db8:ffff::")));
+    assert!(!set.contains(addr("2001:db9::")));
+
+    // the families don't mix, even when the bits match
+    assert!(!set.contains(addr("::ffff:10.0.0.1")));
+    assert!(!set.contains(addr("a00::")));
+
+    assert!(CidrSet::default().is_empty());
+    assert!(!CidrSet::default().contains(addr("10.0.0.1")));
+}
+
+#[test]
+fn covered_networks_are_merged() {
+    // smaller networks inside larger ones, inserted before and after them
+    let set = cidr_set(&[
+        "10.1.0.0/16",
+        "10.0.0.0/8",
+        "10.2.3.4",
+        "fe80::1",
+        "fe80::/10",
+        "fe80::/64",
+    ]);
+    assert_eq!(networks(&set), ["10.0.0.0/8", "fe80::/10"]);
+
+    // neighbours stay apart
+    let set = cidr_set(&["10.0.0.0/9", "10.128.0.0/9", "10.0.0.0/9"]);
+    assert_eq!(networks(&set), ["10.0.0.0/9", "10.128.0.0/9"]);
+
+    let mut set = cidr_set(&["0.0.0.0/0"]);
+    set.insert(&"192.0.2.0/24".parse().unwrap());
+    assert_eq!(networks(&set), ["0.0.0.0/0"]);
+    assert!(set.contains(addr("255.255.255.255")));
+    assert!(!set.contains(addr("::1")));
+}
+
+#[test]
+fn network_lists() {
+    let (set, invalid) = CidrSet::parse(
+        "# local\n10.0.0.0/8 # private\n\n2001:db8::/32\n10.0.0.0/40\nnot a network\n",
+    );
+
+    assert_eq!(networks(&set), ["10.0.0.0/8", "2001:db8::/32"]);
+    assert_eq!(
+        invalid
+            .iter()
+            .map(|entry| (entry.line, entry.entry.as_str()))
+            .collect::<Vec<_>>(),
+        [(5, "10.0.0.0/40"), (6, "not a network")]
+    );
+}
+
+#[test]
+fn default_exclude_covers_local_networks() {
+    let set = cidr_set(DEFAULT_EXCLUDE);
+    assert_eq!(networks(&set).len(), DEFAULT_EXCLUDE.len());
+
+    for local in [
+        "0.0.0.0",
+        "10.1.2.3",
+        "100.64.0.1",
+        "127.0.0.1",
+        "169.254.1.1",
+        "172.31.255.255",
+        "192.168.1.1",
+        "198.19.0.1",
+        "224.0.0.251",
+        "255.255.255.255",
+        "::",
+        "::1",
+        "fd12:3456::1",
+        "fe80::1",
+        "ff02::fb",
+    ] {
+        assert!(set.contains(addr(local)), "{local}");
+    }
+
+    for public in [
+        "1.1.1.1",
+        "100.128.0.1",
+        "172.32.0.1",
+        "192.0.2.1",
+        "223.255.255.255",
+        "::2",
+        "2001:db8::1",
+        "fec0::1",
+    ] {
+        assert!(!set.contains(addr(public)), "{public}");
+    }
+}
+
+#[test]
+fn exclude_overrides_include() {
+    let filter = CidrFilter {
+        include: cidr_set(&["192.0.2.0/24"]),
+        exclude: cidr_set(&["192.0.2.128/25"]),
+    };
+
+    assert!(filter.matches(addr("192.0.2.1")));
+    assert!(!filter.matches(addr("192.0.2.200")));
+    assert!(!filter.matches(addr("198.51.100.1")));
+
+    // without networks to include everything not excluded is desynced
+    let filter = CidrFilter {
+        include: CidrSet::default(),
+        exclude: cidr_set(DEFAULT_EXCLUDE),
+    };
+
+    assert!(filter.matches(addr("198.51.100.1")));
+    assert!(!filter.matches(addr("192.168.1.1")));
+}
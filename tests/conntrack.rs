@@ -0,0 +1,181 @@
+use std::{
+    net::SocketAddr,
+    time::{Duration, Instant},
+};
+
+use packetmock::conntrack::{FlowKey, FlowState, FlowTable, Segment, TcpFlags};
+
+const IDLE: Duration = Duration::from_secs(60);
+const CLOSE: Duration = Duration::from_secs(10);
+
+fn key(port: u16) -> FlowKey {
+    FlowKey {
+        local: SocketAddr::from(([10, 0, 0, 2], port)),
+        remote: SocketAddr::from(([192, 0, 2, 1], 443)),
+    }
+}
+
+fn segment(port: u16, outbound: bool, flags: TcpFlags, seq: u32, payload_length: usize) -> Segment {
+    Segment {
+        key: key(port),
+        outbound,
+        flags,
+        seq,
+        payload_length,
+    }
+}
+
+const SYN: TcpFlags = TcpFlags {
+    syn: true,
+    ack: false,
+    fin: false,
+    rst: false,
+};
+const SYN_ACK: TcpFlags = TcpFlags {
+    syn: true,
+    ack: true,
+    fin: false,
+    rst: false,
+};
+const ACK: TcpFlags = TcpFlags {
+    syn: false,
+    ack: true,
+    fin: false,
+    rst: false,
+};
+const FIN: TcpFlags = TcpFlags {
+    syn: false,
+    ack: true,
+    fin: true,
+    rst: false,
+};
+const RST: TcpFlags = TcpFlags {
+    syn: false,
+    ack: false,
+    fin: false,
+    rst: true,
+};
+
+/// Open a flow with a handshake at the time.
+fn open(table: &mut FlowTable, port: u16, at: Instant) {
+    table.track(&segment(port, true, SYN, 100, 0), at).unwrap();
+    table
+        .track(&segment(port, false, SYN_ACK, 500, 0), at)
+        .unwrap();
+}
+
+#[test]
+fn handshakes_are_tracked() {
+    let mut table = FlowTable::new(16, IDLE, CLOSE);
+    let now = Instant::now();
+
+    let flow = table.track(&segment(1, true, SYN, 100, 0), now).unwrap();
+    assert_eq!(flow.state, FlowState::SynSent);
+    assert_eq!(flow.client_isn, Some(100));
+
+    let flow = table
+        .track(&segment(1, false, SYN_ACK, 500, 0), now)
+        .unwrap();
+    assert_eq!(flow.state, FlowState::Established);
+    assert_eq!(flow.server_isn, Some(500));
+
+    let flow = table.track(&segment(1, true, ACK, 101, 200), now).unwrap();
+    flow.first_payload = true;
+    flow.first_seq = Some(101);
+    let flow = table.track(&segment(1, true, ACK, 101, 200), now).unwrap();
+    assert_eq!(flow.retransmissions, 1);
+
+    let flow = table.track(&segment(1, false, FIN, 501, 0), now).unwrap();
+    assert_eq!(flow.state, FlowState::Closing);
+    let flow = table.track(&segment(1, true, RST, 301, 0), now).unwrap();
+    assert_eq!(flow.state, FlowState::Reset);
+}
+
+#[test]
+fn only_outbound_segments_open_flows() {
+    let mut table = FlowTable::new(16, IDLE, CLOSE);
+    let now = Instant::now();
+
+    assert!(table.track(&segment(1, false, ACK, 1, 100), now).is_none());
+    assert!(table.track(&segment(1, true, ACK, 1, 0), now).is_none());
+    assert!(table.is_empty());
+
+    // opened before tracking started
+    let flow = table.track(&segment(1, true, ACK, 1, 100), now).unwrap();
+    assert_eq!(flow.state, FlowState::Established);
+    assert_eq!(flow.client_isn, None);
+}
+
+#[test]
+fn reused_ports_start_a_new_flow() {
+    let mut table = FlowTable::new(16, IDLE, CLOSE);
+    let now = Instant::now();
+    open(&mut table, 1, now);
+    table.get_mut(&key(1)).unwrap().first_payload = true;
+
+    let flow = table.track(&segment(1, true, SYN, 900, 0), now).unwrap();
+    assert_eq!(flow.state, FlowState::SynSent);
+    assert_eq!(flow.client_isn, Some(900));
+    assert!(!flow.first_payload);
+    assert_eq!(table.len(), 1);
+}
+
+#[test]
+fn flows_expire() {
+    let mut table = FlowTable::new(16, IDLE, CLOSE);
+    let now = Instant::now();
+    for port in 1..=4 {
+        open(&mut table, port, now);
+    }
+    table.track(&segment(2, false, FIN, 501, 0), now).unwrap();
+    table.track(&segment(3, false, RST, 501, 0), now).unwrap();
+
+    // reset flows go at the next expiry
+    table.expire(now);
+    assert_eq!(table.len(), 3);
+    assert!(table.get_mut(&key(3)).is_none());
+
+    // closing flows once closing for long enough
+    table.expire(now + CLOSE);
+    assert_eq!(table.len(), 2);
+    assert!(table.get_mut(&key(2)).is_none());
+
+    // the others once idle, which a segment ends
+    table
+        .track(&segment(4, true, ACK, 101, 0), now + CLOSE)
+        .unwrap();
+    table.expire(now + IDLE);
+    assert_eq!(table.len(), 1);
+    assert!(table.get_mut(&key(4)).is_some());
+
+    table.expire(now + CLOSE + IDLE);
+    assert!(table.is_empty());
+}
+
+#[test]
+fn full_tables_evict_the_least_recently_seen_flow() {
+    let mut table = FlowTable::new(3, IDLE, CLOSE);
+    let now = Instant::now();
+    for port in 1..=3 {
+        open(&mut table, port, now + Duration::from_secs(u64::from(port)));
+    }
+
+    // flow 1 was seen last now
+    let later = now + Duration::from_secs(10);
+    table.track(&segment(1, true, ACK, 101, 0), later).unwrap();
+
+    open(&mut table, 4, later);
+    assert_eq!(table.len(), 3);
+    assert!(table.get_mut(&key(2)).is_none());
+
+    open(&mut table, 5, later);
+    assert_eq!(table.len(), 3);
+    assert!(table.get_mut(&key(3)).is_none());
+    for port in [1, 4, 5] {
+        assert!(table.get_mut(&key(port)).is_some(), "{port}");
+    }
+
+    // smaller limits evict down to them
+    table.set_limits(2, IDLE, CLOSE);
+    assert_eq!(table.len(), 2);
+}
@@ -0,0 +1,440 @@
+use std::{
+    io::{Read, Write},
+    net::{IpAddr, TcpListener, TcpStream},
+    sync::{
+        Arc,
+        atomic::{AtomicUsize, Ordering},
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use packetmock::{
+    dns::{
+        DnsForwarder,
+        packet::{parse_dns_packet, transport_checksum},
+        resolver::{Transport, Upstream},
+    },
+    settings::{Dns, Settings},
+};
+
+/// Address every A record of the stand-in resolver points to.
+const ADDRESS: [u8; 4] = [93, 184, 216, 34];
+/// TTL of the records of the stand-in resolver.
+const TTL: u32 = 300;
+
+/// A query for the name, with EDNS announcing `udp_size` if set.
+fn query(id: u16, name: &str, udp_size: Option<u16>) -> Vec<u8> {
+    let mut query = id.to_be_bytes().to_vec();
+    // recursion desired, one question, an additional OPT record with EDNS
+    query.extend_from_slice(&[0x01, 0x00, 0, 1, 0, 0, 0, 0]);
+    query.extend_from_slice(&u16::from(udp_size.is_some()).to_be_bytes());
+
+    for label in name.split('.') {
+        query.push(label.len() as u8);
+        query.extend_from_slice(label.as_bytes());
+    }
+    query.extend_from_slice(&[0, 0, 1, 0, 1]);
+
+    if let Some(size) = udp_size {
+        query.extend_from_slice(&[0, 0, 41]);
+        query.extend_from_slice(&size.to_be_bytes());
+        query.extend_from_slice(&[0, 0, 0, 0, 0, 0]);
+    }
+
+    query
+}
+
+/// The response of the stand-in resolver: an A record for the name, 40 of them for names
+/// starting with `big`.
+fn response(query: &[u8]) -> Vec<u8> {
+    let question_end = 12 + query[12..].iter().position(|&b| b == 0).unwrap() + 5;
+    let records: u16 = if query[13..16].eq_ignore_ascii_case(b"big") {
+        40
+    } else {
+        1
+    };
+
+    let mut response = query[..2].to_vec();
+    response.extend_from_slice(&[0x81, 0x80, 0, 1]);
+    response.extend_from_slice(&records.to_be_bytes());
+    response.extend_from_slice(&[0, 0, 0, 0]);
+    response.extend_from_slice(&query[12..question_end]);
+
+    for _ in 0..records {
+        response.extend_from_slice(&[0xc0, 0x0c, 0, 1, 0, 1]);
+        response.extend_from_slice(&TTL.to_be_bytes());
+        response.extend_from_slice(&[0, 4]);
+        response.extend_from_slice(&ADDRESS);
+    }
+
+    response
+}
+
+/// A plain HTTP stand-in for a DoH resolver, counting the queries it answered.
+fn doh_server() -> (String, Arc<AtomicUsize>) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("http://{}/dns-query", listener.local_addr().unwrap());
+    let requests = Arc::new(AtomicUsize::new(0));
+    let counter = requests.clone();
+
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let counter = counter.clone();
+            thread::spawn(move || serve(stream.unwrap(), &counter));
+        }
+    });
+
+    (url, requests)
+}
+
+fn serve(mut stream: TcpStream, requests: &AtomicUsize) {
+    let mut data = Vec::new();
+    let mut buffer = [0; 4096];
+
+    loop {
+        let Some(head_end) = data.windows(4).position(|w| w == b"\r\n\r\n") else {
+            match stream.read(&mut buffer) {
+                Ok(0) | Err(_) => return,
+                Ok(read) => data.extend_from_slice(&buffer[..read]),
+            }
+            continue;
+        };
+
+        let head = String::from_utf8_lossy(&data[..head_end]).to_ascii_lowercase();
+        assert!(head.starts_with("post /dns-query http/1.1"));
+        assert!(head.contains("content-type: application/dns-message"));
+
+        let length = head
+            .lines()
+            .find_map(|line| line.strip_prefix("content-length: "))
+            .unwrap()
+            .parse::<usize>()
+            .unwrap();
+        let body = head_end + 4;
+
+        while data.len() < body + length {
+            let read = stream.read(&mut buffer).unwrap();
+            data.extend_from_slice(&buffer[..read]);
+        }
+
+        requests.fetch_add(1, Ordering::SeqCst);
+        let answer = response(&data[body..body + length]);
+        let head = format!(
+            "HTTP/1.1 200 OK\r\nContent-Type: application/dns-message\r\nContent-Length: {}\r\n\r\n",
+            answer.len()
+        );
+        stream.write_all(head.as_bytes()).unwrap();
+        stream.write_all(&answer).unwrap();
+
+        data.drain(..body + length);
+    }
+}
+
+fn forwarder(url: &str) -> DnsForwarder {
+    DnsForwarder::new(&Dns {
+        enabled: true,
+        resolver: url.to_owned(),
+        timeout: 2,
+        ..Dns::default()
+    })
+    .unwrap()
+}
+
+fn id(message: &[u8]) -> u16 {
+    u16::from_be_bytes([message[0], message[1]])
+}
+
+fn answers(message: &[u8]) -> u16 {
+    u16::from_be_bytes([message[6], message[7]])
+}
+
+/// TTL of the first answer record, after a question for `example.com`.
+fn first_ttl(message: &[u8]) -> u32 {
+    let offset = 12 + 13 + 4 + 6;
+    u32::from_be_bytes(message[offset..offset + 4].try_into().unwrap())
+}
+
+#[test]
+fn resolves_through_the_resolver() {
+    let (url, requests) = doh_server();
+    let mut forwarder = forwarder(&url);
+
+    let answer = forwarder
+        .answer(&query(0x1234, "example.com", None), true, Instant::now())
+        .unwrap();
+
+    assert_eq!(id(&answer), 0x1234);
+    assert_eq!(answers(&answer), 1);
+    assert_eq!(&answer[answer.len() - 4..], &ADDRESS);
+    assert_eq!(requests.load(Ordering::SeqCst), 1);
+}
+
+#[test]
+fn cached_answers_take_the_query_id_and_case() {
+    let (url, requests) = doh_server();
+    let mut forwarder = forwarder(&url);
+    let now = Instant::now();
+
+    forwarder
+        .answer(&query(1, "example.com", None), true, now)
+        .unwrap();
+    let cached = forwarder
+        .answer(&query(2, "ExAmPlE.com", None), true, now)
+        .unwrap();
+
+    assert_eq!(requests.load(Ordering::SeqCst), 1);
+    assert_eq!(id(&cached), 2);
+    assert_eq!(&cached[13..20], b"ExAmPlE");
+}
+
+#[test]
+fn cached_answers_count_their_ttl_down() {
+    let (url, requests) = doh_server();
+    let mut forwarder = forwarder(&url);
+    let now = Instant::now();
+
+    forwarder
+        .answer(&query(1, "example.com", None), true, now)
+        .unwrap();
+
+    let later = now + Duration::from_secs(100);
+    let cached = forwarder
+        .answer(&query(2, "example.com", None), true, later)
+        .unwrap();
+    assert_eq!(first_ttl(&cached), TTL - 100);
+    assert_eq!(requests.load(Ordering::SeqCst), 1);
+
+    let expired = now + Duration::from_secs(u64::from(TTL));
+    let fresh = forwarder
+        .answer(&query(3, "example.com", None), true, expired)
+        .unwrap();
+    assert_eq!(first_ttl(&fresh), TTL);
+    assert_eq!(requests.load(Ordering::SeqCst), 2);
+}
+
+#[test]
+fn large_answers_are_truncated_over_udp() {
+    let (url, _) = doh_server();
+    let mut forwarder = forwarder(&url);
+    let now = Instant::now();
+
+    let truncated = forwarder
+        .answer(&query(1, "big.example", None), true, now)
+        .unwrap();
+    assert_ne!(truncated[2] & 0x02, 0);
+    assert_eq!(answers(&truncated), 0);
+
+    // the client asks again over TCP, or announces a larger size
+    let full = forwarder
+        .answer(&query(2, "big.example", None), false, now)
+        .unwrap();
+    assert_eq!(full[2] & 0x02, 0);
+    assert_eq!(answers(&full), 40);
+
+    let edns = forwarder
+        .answer(&query(3, "big.example", Some(1232)), true, now)
+        .unwrap();
+    assert_eq!(answers(&edns), 40);
+}
+
+#[test]
+fn unreachable_resolvers_fail_the_query() {
+    // a port nothing listens on anymore
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("http://{}/dns-query", listener.local_addr().unwrap());
+    drop(listener);
+
+    let answer = forwarder(&url)
+        .answer(&query(7, "example.com", None), true, Instant::now())
+        .unwrap();
+
+    assert_eq!(id(&answer), 7);
+    assert_eq!(answer[3] & 0x0f, 2);
+    assert_eq!(answers(&answer), 0);
+}
+
+#[test]
+fn queries_fail_right_away_while_the_resolver_is_down() {
+    // accepts connections but never answers
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("http://{}/dns-query", listener.local_addr().unwrap());
+    let mut forwarder = DnsForwarder::new(&Dns {
+        enabled: true,
+        resolver: url,
+        timeout: 1,
+        ..Dns::default()
+    })
+    .unwrap();
+
+    let now = Instant::now();
+    let answer = forwarder
+        .answer(&query(1, "example.com", None), true, now)
+        .unwrap();
+    assert_eq!(answer[3] & 0x0f, 2);
+
+    let started = Instant::now();
+    let answer = forwarder
+        .answer(&query(2, "example.org", None), true, now)
+        .unwrap();
+    assert_eq!(id(&answer), 2);
+    assert_eq!(answer[3] & 0x0f, 2);
+    assert!(started.elapsed() < Duration::from_millis(500));
+}
+
+#[test]
+fn responses_are_not_queries() {
+    let (url, requests) = doh_server();
+    let mut forwarder = forwarder(&url);
+
+    let response = response(&query(1, "example.com", None));
+    assert!(forwarder.answer(&response, true, Instant::now()).is_none());
+    assert_eq!(requests.load(Ordering::SeqCst), 0);
+}
+
+/// An IPv4 packet from 10.0.0.2:50000 to 192.0.2.53:53 carrying the query.
+fn ipv4_packet(query: &[u8], tcp: bool) -> Vec<u8> {
+    let mut transport = vec![0xc3, 0x50, 0, 53];
+    if tcp {
+        transport.extend_from_slice(&1000u32.to_be_bytes());
+        transport.extend_from_slice(&2000u32.to_be_bytes());
+        transport.extend_from_slice(&[0x50, 0x18, 0xff, 0xff, 0, 0, 0, 0]);
+        transport.extend_from_slice(&(query.len() as u16).to_be_bytes());
+    } else {
+        transport.extend_from_slice(&((8 + query.len()) as u16).to_be_bytes());
+        transport.extend_from_slice(&[0, 0]);
+    }
+    transport.extend_from_slice(query);
+
+    let mut packet = vec![0x45, 0];
+    packet.extend_from_slice(&((20 + transport.len()) as u16).to_be_bytes());
+    packet.extend_from_slice(&[0, 0, 0x40, 0, 64, if tcp { 6 } else { 17 }, 0, 0]);
+    packet.extend_from_slice(&[10, 0, 0, 2, 192, 0, 2, 53]);
+    packet.extend_from_slice(&transport);
+
+    packet
+}
+
+/// Whether the ones' complement sum of the data, checksum included, checks out.
+fn header_checksum_valid(data: &[u8]) -> bool {
+    let mut sum = data
+        .chunks(2)
+        .map(|word| u32::from(u16::from_be_bytes([word[0], *word.get(1).unwrap_or(&0)])))
+        .sum::<u32>();
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    sum == 0xffff
+}
+
+#[test]
+fn udp_answers_come_from_the_server() {
+    let query = query(1, "example.com", None);
+    let raw = ipv4_packet(&query, false);
+
+    let packet = parse_dns_packet(&raw).unwrap();
+    assert!(packet.udp);
+    assert_eq!(packet.message, query);
+
+    let answer = response(&query);
+    let reply = packet.reply(&answer);
+
+    assert_eq!(&reply[12..20], &[192, 0, 2, 53, 10, 0, 0, 2]);
+    assert_eq!(&reply[20..24], &[0, 53, 0xc3, 0x50]);
+    assert_eq!(&reply[28..], answer.as_slice());
+    assert!(header_checksum_valid(&reply[..20]));
+
+    let source = IpAddr::from([192, 0, 2, 53]);
+    let destination = IpAddr::from([10, 0, 0, 2]);
+    assert_eq!(transport_checksum(source, destination, 17, &reply[20..]), 0);
+}
+
+#[test]
+fn tcp_answers_continue_the_stream() {
+    let query = query(1, "example.com", None);
+    let raw = ipv4_packet(&query, true);
+
+    let packet = parse_dns_packet(&raw).unwrap();
+    assert!(!packet.udp);
+    assert_eq!(packet.message, query);
+
+    let answer = response(&query);
+    let reply = packet.reply(&answer);
+    let tcp = &reply[20..];
+
+    assert_eq!(&tcp[..4], &[0, 53, 0xc3, 0x50]);
+    // the answer starts at the acknowledged sequence number and acknowledges the query
+    assert_eq!(&tcp[4..8], &2000u32.to_be_bytes());
+    let acked = 1000 + 2 + query.len() as u32;
+    assert_eq!(&tcp[8..12], &acked.to_be_bytes());
+    assert_eq!(&tcp[20..22], &(answer.len() as u16).to_be_bytes());
+    assert_eq!(&tcp[22..], answer.as_slice());
+
+    let source = IpAddr::from([192, 0, 2, 53]);
+    let destination = IpAddr::from([10, 0, 0, 2]);
+    assert_eq!(transport_checksum(source, destination, 6, tcp), 0);
+}
+
+#[test]
+fn tcp_segments_with_several_queries_pass() {
+    let first = query(1, "example.com", None);
+    let second = query(2, "example.org", None);
+
+    let mut payload = first.clone();
+    payload.extend_from_slice(&(second.len() as u16).to_be_bytes());
+    payload.extend_from_slice(&second);
+    let mut raw = ipv4_packet(&payload, true);
+    raw[40..42].copy_from_slice(&(first.len() as u16).to_be_bytes());
+
+    assert!(parse_dns_packet(&raw).is_none());
+
+    // nor are queries continuing in the next segment answered
+    let raw = ipv4_packet(&first, true);
+    assert!(parse_dns_packet(&raw[..raw.len() - 1]).is_none());
+}
+
+#[test]
+fn other_ports_are_not_dns() {
+    let mut raw = ipv4_packet(&query(1, "example.com", None), false);
+    raw[23] = 54;
+
+    assert!(parse_dns_packet(&raw).is_none());
+}
+
+#[test]
+fn resolver_urls() {
+    let doh = Upstream::parse("https://dns.example/resolve").unwrap();
+    assert_eq!(
+        doh.transport,
+        Transport::Https {
+            path: "/resolve".to_owned()
+        }
+    );
+    assert_eq!((doh.host.as_str(), doh.port), ("dns.example", 443));
+    assert_eq!(doh.address(None), None);
+
+    let dot = Upstream::parse("tls://[2606:4700:4700::1111]").unwrap();
+    assert_eq!(dot.transport, Transport::Tls);
+    assert_eq!(dot.port, 853);
+    assert!(dot.address(None).is_some());
+
+    let port = Upstream::parse("https://1.1.1.1:8443").unwrap();
+    assert_eq!(port.address(None), Some("1.1.1.1:8443".parse().unwrap()));
+
+    assert!(Upstream::parse("udp://1.1.1.1").is_err());
+    assert!(Upstream::parse("1.1.1.1").is_err());
+}
+
+#[test]
+fn resolver_names_need_an_address() {
+    let mut settings = Settings::default();
+    settings.dns.enabled = true;
+    settings.dns.resolver = "https://dns.example/dns-query".to_owned();
+
+    let problems = settings.problems();
+    assert_eq!(problems.len(), 1);
+    assert_eq!(problems[0].key, "dns.address");
+
+    settings.dns.address = Some("192.0.2.1".parse().unwrap());
+    assert!(settings.problems().is_empty());
+}
@@ -0,0 +1,275 @@
+use std::time::{Duration, Instant};
+
+use packetmock::{
+    dns::guard::{Spoof, SpoofGuard, Verdict},
+    settings::DnsGuard,
+};
+
+const CLIENT: [u8; 4] = [10, 0, 0, 2];
+const SERVER: [u8; 4] = [192, 0, 2, 53];
+/// Address of the genuine answers.
+const GENUINE: [u8; 4] = [93, 184, 216, 34];
+
+/// A DNS message for `example.com`: a query, or a response with an A record for the address.
+fn message(id: u16, answer: Option<[u8; 4]>) -> Vec<u8> {
+    let mut message = id.to_be_bytes().to_vec();
+    let flags: [u8; 2] = if answer.is_some() {
+        [0x81, 0x80]
+    } else {
+        [0x01, 0x00]
+    };
+    message.extend_from_slice(&flags);
+    message.extend_from_slice(&[0, 1, 0, u8::from(answer.is_some()), 0, 0, 0, 0]);
+    message.extend_from_slice(b"\x07example\x03com\x00\x00\x01\x00\x01");
+
+    if let Some(address) = answer {
+        message.extend_from_slice(&[0xc0, 0x0c, 0, 1, 0, 1, 0, 0, 0x0e, 0x10, 0, 4]);
+        message.extend_from_slice(&address);
+    }
+
+    message
+}
+
+/// An IPv4 UDP packet between the client port 50000 and the server port 53.
+fn packet(outbound: bool, ttl: u8, ip_id: u16, message: &[u8]) -> Vec<u8> {
+    let (source, destination, ports) = if outbound {
+        (CLIENT, SERVER, [0xc3, 0x50, 0, 53])
+    } else {
+        (SERVER, CLIENT, [0, 53, 0xc3, 0x50])
+    };
+
+    let mut packet = vec![0x45, 0];
+    packet.extend_from_slice(&((28 + message.len()) as u16).to_be_bytes());
+    packet.extend_from_slice(&ip_id.to_be_bytes());
+    packet.extend_from_slice(&[0, 0, ttl, 17, 0, 0]);
+    packet.extend_from_slice(&source);
+    packet.extend_from_slice(&destination);
+    packet.extend_from_slice(&ports);
+    packet.extend_from_slice(&((8 + message.len()) as u16).to_be_bytes());
+    packet.extend_from_slice(&[0, 0]);
+    packet.extend_from_slice(message);
+
+    packet
+}
+
+fn guard() -> SpoofGuard {
+    SpoofGuard::new(&DnsGuard {
+        enabled: true,
+        ..DnsGuard::default()
+    })
+}
+
+fn query(guard: &mut SpoofGuard, id: u16, now: Instant) {
+    let verdict = guard.inspect(&packet(true, 128, 1, &message(id, None)), true, now);
+    assert_eq!(verdict, Verdict::Pass);
+}
+
+fn answer(
+    guard: &mut SpoofGuard,
+    id: u16,
+    address: [u8; 4],
+    ttl: u8,
+    ip_id: u16,
+    at: Instant,
+) -> Verdict {
+    let packet = packet(false, ttl, ip_id, &message(id, Some(address)));
+    guard.inspect(&packet, false, at)
+}
+
+/// A guard that learned the server from a genuine answer with TTL 50, a non-zero IP
+/// identification and a 40 ms round trip.
+fn learned(now: Instant) -> SpoofGuard {
+    let mut guard = guard();
+    query(&mut guard, 1, now);
+
+    let at = now + Duration::from_millis(40);
+    assert_eq!(answer(&mut guard, 1, GENUINE, 50, 7, at), Verdict::Accept);
+
+    // learned once the query times out
+    guard.expire(now + Duration::from_secs(5));
+    assert_eq!(guard.pending(), 0);
+
+    guard
+}
+
+#[test]
+fn bogon_answers_are_dropped() {
+    let mut guard = guard();
+    let now = Instant::now();
+    query(&mut guard, 1, now);
+
+    let forged = answer(&mut guard, 1, [127, 0, 0, 1], 60, 0, now);
+    assert_eq!(forged, Verdict::Drop(Spoof::Bogon));
+
+    // the query still waits for the genuine answer
+    let genuine = answer(&mut guard, 1, GENUINE, 50, 7, now);
+    assert_eq!(genuine, Verdict::Accept);
+}
+
+#[test]
+fn bogons_are_configurable() {
+    let mut guard = SpoofGuard::new(&DnsGuard {
+        enabled: true,
+        bogons: vec!["198.51.100.0/24".parse().unwrap()],
+        ..DnsGuard::default()
+    });
+    let now = Instant::now();
+    query(&mut guard, 1, now);
+
+    let blockpage = answer(&mut guard, 1, [198, 51, 100, 7], 60, 0, now);
+    assert_eq!(blockpage, Verdict::Drop(Spoof::Bogon));
+}
+
+#[test]
+fn answers_with_an_unusual_ttl_are_dropped() {
+    let now = Instant::now();
+    let mut guard = learned(now);
+    query(&mut guard, 2, now);
+
+    let at = now + Duration::from_millis(40);
+    let forged = answer(&mut guard, 2, [203, 0, 113, 1], 60, 7, at);
+    assert_eq!(forged, Verdict::Drop(Spoof::IpTtl));
+
+    // routes change by a hop or two
+    let genuine = answer(&mut guard, 2, GENUINE, 52, 7, at);
+    assert_eq!(genuine, Verdict::Accept);
+}
+
+#[test]
+fn answers_with_an_unusual_ip_id_are_dropped() {
+    let now = Instant::now();
+    let mut guard = learned(now);
+    query(&mut guard, 2, now);
+
+    let at = now + Duration::from_millis(40);
+    let forged = answer(&mut guard, 2, [203, 0, 113, 1], 50, 0, at);
+    assert_eq!(forged, Verdict::Drop(Spoof::IpId));
+
+    let genuine = answer(&mut guard, 2, GENUINE, 50, 8, at);
+    assert_eq!(genuine, Verdict::Accept);
+}
+
+#[test]
+fn answers_faster_than_the_server_are_dropped() {
+    let now = Instant::now();
+    let mut guard = learned(now);
+    query(&mut guard, 2, now);
+
+    let early = now + Duration::from_millis(5);
+    let forged = answer(&mut guard, 2, [203, 0, 113, 1], 50, 7, early);
+    assert_eq!(forged, Verdict::Drop(Spoof::Early));
+
+    let genuine = answer(
+        &mut guard,
+        2,
+        GENUINE,
+        50,
+        7,
+        now + Duration::from_millis(38),
+    );
+    assert_eq!(genuine, Verdict::Accept);
+}
+
+#[test]
+fn servers_are_learned_from_the_last_answer() {
+    let mut guard = guard();
+    let now = Instant::now();
+    query(&mut guard, 1, now);
+
+    // a fresh guard can't tell the forged answer racing ahead from the genuine one
+    let forged = answer(
+        &mut guard,
+        1,
+        [203, 0, 113, 1],
+        60,
+        0,
+        now + Duration::from_millis(5),
+    );
+    assert_eq!(forged, Verdict::Accept);
+    let genuine = answer(
+        &mut guard,
+        1,
+        GENUINE,
+        50,
+        7,
+        now + Duration::from_millis(40),
+    );
+    assert_eq!(genuine, Verdict::Accept);
+    assert_eq!(guard.pending(), 1);
+
+    guard.expire(now + Duration::from_secs(5));
+    assert_eq!(guard.pending(), 0);
+
+    let later = now + Duration::from_secs(6);
+    query(&mut guard, 2, later);
+    let forged = answer(
+        &mut guard,
+        2,
+        [203, 0, 113, 1],
+        60,
+        0,
+        later + Duration::from_millis(5),
+    );
+    assert_eq!(forged, Verdict::Drop(Spoof::IpTtl));
+    let genuine = answer(
+        &mut guard,
+        2,
+        GENUINE,
+        50,
+        7,
+        later + Duration::from_millis(40),
+    );
+    assert_eq!(genuine, Verdict::Accept);
+    assert_eq!(guard.pending(), 0);
+}
+
+#[test]
+fn answers_to_unknown_queries_pass() {
+    let now = Instant::now();
+    let mut guard = learned(now);
+
+    // answered already, or never asked
+    assert_eq!(answer(&mut guard, 1, GENUINE, 60, 0, now), Verdict::Pass);
+    assert_eq!(answer(&mut guard, 9, GENUINE, 60, 0, now), Verdict::Pass);
+}
+
+#[test]
+fn servers_are_learned_again_when_nothing_looks_usual() {
+    let now = Instant::now();
+    let mut guard = learned(now);
+    query(&mut guard, 2, now);
+
+    // the route to the server changed, its genuine answers now look forged
+    let at = now + Duration::from_millis(40);
+    assert_eq!(
+        answer(&mut guard, 2, GENUINE, 40, 7, at),
+        Verdict::Drop(Spoof::IpTtl)
+    );
+
+    guard.expire(now + Duration::from_secs(5));
+    assert_eq!(guard.pending(), 0);
+
+    let later = now + Duration::from_secs(6);
+    query(&mut guard, 3, later);
+    let genuine = answer(
+        &mut guard,
+        3,
+        GENUINE,
+        40,
+        7,
+        later + Duration::from_millis(40),
+    );
+    assert_eq!(genuine, Verdict::Accept);
+}
+
+#[test]
+fn other_packets_pass() {
+    let mut guard = guard();
+    let now = Instant::now();
+
+    let mut other_port = packet(false, 50, 7, &message(1, Some(GENUINE)));
+    other_port[21] = 54;
+    assert_eq!(guard.inspect(&other_port, false, now), Verdict::Pass);
+    assert_eq!(guard.inspect(&[0x45, 0, 0], false, now), Verdict::Pass);
+    assert_eq!(guard.pending(), 0);
+}
@@ -0,0 +1,113 @@
+use packetmock::{
+    cidr::{Cidr, CidrSet},
+    filter::{
+        Direction, FilterBuilder, NftablesOptions, Protocol, capture_filter, dns_filters,
+        dns_guard_filter,
+    },
+    settings::Settings,
+};
+
+fn networks(networks: &[&str]) -> CidrSet {
+    let networks = networks
+        .iter()
+        .map(|cidr| cidr.parse::<Cidr>().unwrap())
+        .collect::<Vec<_>>();
+
+    CidrSet::new(&networks)
+}
+
+fn default_builder() -> FilterBuilder {
+    let settings = Settings::default();
+
+    FilterBuilder::new(Direction::Outbound, Protocol::Tcp)
+        .ports(settings.ports.classifiers().map(|(port, _)| port))
+        .payload_length(1..9016)
+        .exclude(&CidrSet::new(&settings.addresses.exclude))
+}
+
+#[test]
+fn windivert_default() {
+    insta::assert_snapshot!(default_builder().build().to_windivert());
+}
+
+#[test]
+fn nftables_default() {
+    insta::assert_snapshot!(
+        default_builder()
+            .build()
+            .to_nftables(&NftablesOptions::default())
+    );
+}
+
+#[test]
+fn windivert_include_both_directions() {
+    let filter = FilterBuilder::new(Direction::Both, Protocol::Udp)
+        .ports([53])
+        .include(&networks(&["203.0.113.0/24", "2001:db8::/32"]))
+        .loopback(true)
+        .skip_injected(false)
+        .build();
+
+    insta::assert_snapshot!(filter.to_windivert());
+}
+
+#[test]
+fn nftables_include_both_directions() {
+    let filter = FilterBuilder::new(Direction::Both, Protocol::Udp)
+        .ports([53])
+        .include(&networks(&["203.0.113.0/24", "2001:db8::/32"]))
+        .build();
+
+    let options = NftablesOptions {
+        table: "test".to_owned(),
+        queue: 200,
+        mark: 0x10,
+    };
+
+    insta::assert_snapshot!(filter.to_nftables(&options));
+}
+
+#[test]
+fn windivert_leaves_out_large_sets() {
+    let exclude = (0..=40)
+        .map(|i| format!("10.{i}.0.0/16"))
+        .collect::<Vec<_>>();
+    let exclude = exclude.iter().map(String::as_str).collect::<Vec<_>>();
+
+    let filter = FilterBuilder::new(Direction::Inbound, Protocol::Tcp)
+        .exclude(&networks(&exclude))
+        .build();
+
+    insta::assert_snapshot!(filter.to_windivert());
+}
+
+#[test]
+fn windivert_tracked_connections() {
+    let filter = default_builder().track_connections(true).build();
+    insta::assert_snapshot!(filter.to_windivert());
+}
+
+#[test]
+fn nftables_tracked_connections() {
+    let filter = default_builder().track_connections(true).build();
+    insta::assert_snapshot!(filter.to_nftables(&NftablesOptions::default()));
+}
+
+#[test]
+fn windivert_dns_queries() {
+    let filters = dns_filters().map(|filter| filter.to_windivert());
+    insta::assert_snapshot!(filters.join("\n"));
+}
+
+#[test]
+fn windivert_dns_guard() {
+    insta::assert_snapshot!(dns_guard_filter().to_windivert());
+}
+
+#[test]
+fn windivert_capture_with_dns() {
+    let desync = default_builder().build();
+    assert_eq!(capture_filter(&desync, false, false), desync.to_windivert());
+
+    insta::assert_snapshot!(capture_filter(&desync, true, true));
+}
@@ -0,0 +1,93 @@
+use packetmock::hostlist::{HostFilter, Hostlist};
+
+fn hostlist(text: &str) -> Hostlist {
+    let (hostlist, invalid) = Hostlist::parse(text);
+    assert!(invalid.is_empty());
+    hostlist
+}
+
+#[test]
+fn plain_entries_match_subdomains() {
+    let list = hostlist("example.com\n");
+
+    assert!(list.matches("example.com"));
+    assert!(list.matches("www.example.com"));
+    assert!(list.matches("a.b.example.com"));
+    assert!(!list.matches("notexample.com"));
+    assert!(!list.matches("example.org"));
+    assert!(!list.matches("com"));
+}
+
+#[test]
+fn exact_entries_match_the_domain_only() {
+    let list = hostlist("=example.com\n");
+
+    assert!(list.matches("example.com"));
+    assert!(list.matches("Example.COM."));
+    assert!(!list.matches("www.example.com"));
+}
+
+#[test]
+fn subdomain_entries_leave_out_the_domain() {
+    let list = hostlist("*.example.com\n");
+
+    assert!(!list.matches("example.com"));
+    assert!(list.matches("www.example.com"));
+    assert!(list.matches("a.b.example.com"));
+
+    // together with an exact entry, like a plain one
+    let list = hostlist("*.example.com\n=example.com\n");
+    assert!(list.matches("example.com"));
+    assert!(list.matches("www.example.com"));
+}
+
+#[test]
+fn wildcards_match_a_single_label() {
+    let list = hostlist("cdn.*.example.com\n");
+
+    assert!(list.matches("cdn.eu.example.com"));
+    assert!(list.matches("img.cdn.eu.example.com"));
+    assert!(!list.matches("cdn.example.com"));
+    assert!(!list.matches("cdn.a.b.example.com"));
+    assert!(!list.matches("eu.example.com"));
+}
+
+#[test]
+fn comments_and_invalid_entries() {
+    let (list, invalid) = Hostlist::parse("# domains\nexample.com # trailing\n\nbad domain\n=\n");
+
+    assert_eq!(list.len(), 1);
+    assert!(list.matches("example.com"));
+    assert_eq!(
+        invalid
+            .iter()
+            .map(|entry| (entry.line, entry.entry.as_str()))
+            .collect::<Vec<_>>(),
+        [(4, "bad domain"), (5, "=")]
+    );
+}
+
+#[test]
+fn exclude_overrides_include() {
+    let filter = HostFilter {
+        include: Some(hostlist("example.com\n")),
+        exclude: hostlist("=login.example.com\n"),
+    };
+
+    assert!(filter.matches(Some("example.com")));
+    assert!(filter.matches(Some("www.example.com")));
+    assert!(!filter.matches(Some("login.example.com")));
+    assert!(filter.matches(Some("a.login.example.com")));
+    assert!(!filter.matches(Some("example.org")));
+    assert!(!filter.matches(None));
+
+    // without an include list everything not excluded is desynced
+    let filter = HostFilter {
+        include: None,
+        exclude: hostlist("example.com\n"),
+    };
+
+    assert!(!filter.matches(Some("www.example.com")));
+    assert!(filter.matches(Some("example.org")));
+    assert!(filter.matches(None));
+}
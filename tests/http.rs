@@ -0,0 +1,75 @@
+use packetmock::http::parse_http_requests;
+
+fn hosts(data: &[u8]) -> Vec<Option<&str>> {
+    parse_http_requests(data)
+        .map(|request| request.hostname())
+        .collect()
+}
+
+#[test]
+fn single_request() {
+    let data = b"GET /index.html HTTP/1.1\r\nHost: example.com:8080\r\nAccept: */*\r\n\r\n";
+    let requests = parse_http_requests(data).collect::<Vec<_>>();
+
+    assert_eq!(requests.len(), 1);
+    assert_eq!(requests[0].method(), "GET");
+    assert_eq!(requests[0].target(), "/index.html");
+    assert_eq!(requests[0].host(), Some("example.com:8080"));
+    assert_eq!(requests[0].hostname(), Some("example.com"));
+    assert_eq!(requests[0].end, Some(data.len()));
+}
+
+#[test]
+fn pipelined_requests() {
+    let data = b"GET / HTTP/1.1\r\nHost: a.example\r\n\r\n\
+                 POST /form HTTP/1.1\r\nHost: b.example\r\nContent-Length: 5\r\n\r\nhello\
+                 HEAD / HTTP/1.1\r\nHost: c.example\r\n\r\n";
+
+    assert_eq!(
+        hosts(data),
+        [Some("a.example"), Some("b.example"), Some("c.example")]
+    );
+}
+
+#[test]
+fn chunked_bodies_end_the_iteration() {
+    // the chunks can't be skipped without decoding them
+    let data = b"POST / HTTP/1.1\r\nHost: a.example\r\nTransfer-Encoding: chunked\r\n\r\n\
+                 5\r\nhello\r\n0\r\n\r\n\
+                 GET / HTTP/1.1\r\nHost: b.example\r\n\r\n";
+
+    assert_eq!(hosts(data), [Some("a.example")]);
+}
+
+#[test]
+fn oversized_content_length_ends_the_iteration() {
+    let data = b"POST / HTTP/1.1\r\nHost: a.example\r\n\
+                 Content-Length: 18446744073709551615\r\n\r\n\
+                 GET / HTTP/1.1\r\nHost: b.example\r\n\r\n";
+
+    assert_eq!(hosts(data), [Some("a.example")]);
+}
+
+#[test]
+fn bodies_past_the_segment_end_the_iteration() {
+    let data = b"POST / HTTP/1.1\r\nHost: a.example\r\nContent-Length: 100\r\n\r\nhello";
+
+    assert_eq!(hosts(data), [Some("a.example")]);
+}
+
+#[test]
+fn split_request_heads() {
+    let data = b"GET /long/path HTTP/1.1\r\nHost: a.example\r\nUser-Ag";
+    let requests = parse_http_requests(data).collect::<Vec<_>>();
+
+    assert_eq!(requests.len(), 1);
+    assert_eq!(requests[0].hostname(), Some("a.example"));
+    assert_eq!(requests[0].end, None);
+}
+
+#[test]
+fn other_payloads_are_not_requests() {
+    assert_eq!(hosts(b"\x16\x03\x01\x00\x05hello").len(), 0);
+    assert_eq!(hosts(b"GETTING / HTTP/1.1\r\n\r\n").len(), 0);
+    assert_eq!(hosts(b"GET / SMTP/1.0\r\n\r\n").len(), 0);
+}
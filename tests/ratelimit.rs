@@ -0,0 +1,130 @@
+use std::{
+    net::{IpAddr, Ipv4Addr},
+    time::{Duration, Instant},
+};
+
+use packetmock::{
+    ratelimit::{InjectionLimiter, TokenBucket},
+    settings::Injection,
+};
+
+const A: IpAddr = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1));
+const B: IpAddr = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 2));
+const C: IpAddr = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 3));
+
+fn millis(millis: u64) -> Duration {
+    Duration::from_millis(millis)
+}
+
+fn limits(rate: u32, burst: u32, destination_rate: u32, destination_burst: u32) -> Injection {
+    Injection {
+        rate,
+        burst,
+        destination_rate,
+        destination_burst,
+    }
+}
+
+#[test]
+fn buckets_refill_up_to_the_burst() {
+    let now = Instant::now();
+    let mut bucket = TokenBucket::new(2, 4, now);
+
+    assert!(bucket.is_full(now));
+    assert!(bucket.try_take(4, now));
+    assert!(!bucket.try_take(1, now));
+
+    assert!(bucket.has(1, now + millis(500)));
+    assert!(!bucket.has(2, now + millis(500)));
+    assert!(!bucket.is_full(now + millis(1500)));
+    assert!(bucket.is_full(now + millis(2000)));
+
+    // time spent full doesn't add up
+    assert!(!bucket.try_take(5, now + millis(10_000)));
+    assert!(bucket.try_take(4, now + millis(10_000)));
+    assert!(!bucket.has(1, now + millis(10_000)));
+
+    // taking too many leaves the tokens as they are
+    assert!(!bucket.try_take(2, now + millis(10_500)));
+    assert!(bucket.try_take(1, now + millis(10_500)));
+}
+
+#[test]
+fn buckets_keep_their_tokens_across_new_limits() {
+    let now = Instant::now();
+    let mut bucket = TokenBucket::new(1, 10, now);
+    assert!(bucket.try_take(7, now));
+
+    // the tokens left are capped by a smaller burst
+    bucket.set_limits(1, 2, now);
+    assert!(bucket.is_full(now));
+    assert!(!bucket.has(3, now));
+
+    // and not refilled by a larger one
+    bucket.set_limits(4, 10, now);
+    assert!(!bucket.has(3, now));
+    assert!(bucket.has(6, now + millis(1000)));
+}
+
+#[test]
+fn refused_fakes_take_no_tokens() {
+    let mut limiter = InjectionLimiter::new(&limits(10, 2, 1, 2));
+    let now = Instant::now();
+
+    // the global bucket runs out
+    assert!(limiter.allow(B, 2, now));
+    assert!(!limiter.allow(A, 1, now));
+
+    // the destination bucket was left full while the global one refilled
+    let later = now + millis(200);
+    assert!(limiter.allow(A, 2, later));
+    assert!(!limiter.allow(A, 1, later + millis(500)));
+
+    // refused by the destination, the global bucket keeps its tokens for others
+    let later = later + millis(1000);
+    assert!(!limiter.allow(A, 2, later));
+    assert!(limiter.allow(C, 2, later));
+}
+
+#[test]
+fn reloads_apply_to_the_buckets() {
+    let mut limiter = InjectionLimiter::new(&limits(1, 4, 1, 4));
+    let now = Instant::now();
+    assert!(limiter.allow(A, 3, now));
+
+    // smaller bursts cap the tokens left, larger ones don't refill them
+    limiter.set_limits(&limits(1, 4, 1, 2), now);
+    assert!(!limiter.allow(A, 2, now));
+    limiter.set_limits(&limits(1, 4, 1, 4), now);
+    assert!(!limiter.allow(A, 2, now));
+    assert!(limiter.allow(A, 1, now));
+
+    // without limits nothing is refused
+    limiter.set_limits(&limits(0, 0, 0, 0), now);
+    assert!(limiter.allow(A, 100, now));
+    assert!(limiter.allow(B, 100, now));
+
+    // limits put back start from full buckets
+    limiter.set_limits(&limits(1, 4, 1, 2), now);
+    assert!(limiter.allow(A, 2, now));
+    assert!(!limiter.allow(A, 1, now));
+    assert!(limiter.allow(B, 2, now));
+    assert!(!limiter.allow(B, 1, now));
+}
+
+#[test]
+fn refilled_destinations_are_forgotten() {
+    let mut limiter = InjectionLimiter::new(&limits(0, 0, 1, 2));
+    let now = Instant::now();
+    assert!(limiter.allow(A, 2, now));
+    assert!(limiter.allow(B, 2, now + millis(1000)));
+
+    // a larger burst tells new buckets from the ones kept
+    let later = now + millis(2000);
+    limiter.expire(later);
+    limiter.set_limits(&limits(0, 0, 1, 4), later);
+
+    assert!(limiter.allow(A, 4, later));
+    assert!(!limiter.allow(B, 2, later));
+    assert!(limiter.allow(B, 1, later));
+}
@@ -0,0 +1,148 @@
+use chrono::{NaiveDate, NaiveDateTime};
+use packetmock::{
+    schedule::{Clock, Schedule},
+    settings::Settings,
+};
+
+struct FixedClock(NaiveDateTime);
+
+impl Clock for FixedClock {
+    fn now(&self) -> NaiveDateTime {
+        self.0
+    }
+}
+
+/// 2025-06-02 is a Monday.
+fn at(day: u32, hour: u32, minute: u32) -> NaiveDateTime {
+    NaiveDate::from_ymd_opt(2025, 6, day)
+        .unwrap()
+        .and_hms_opt(hour, minute, 0)
+        .unwrap()
+}
+
+fn schedule(toml: &str) -> Schedule {
+    let settings: Settings = toml::from_str(toml).unwrap();
+    settings.validate().unwrap();
+
+    Schedule::new(&settings)
+}
+
+const WORK_AND_NIGHT: &str = r#"
+active_profile = "default"
+
+[profiles.work]
+rules = []
+
+[profiles.night]
+rules = []
+
+[[schedule]]
+days = ["mon", "tue", "wed", "thu", "fri"]
+start = "09:00"
+end = "18:00"
+profile = "work"
+
+[[schedule]]
+start = "23:00"
+end = "07:00"
+profile = "night"
+"#;
+
+#[test]
+fn weekday_window() {
+    let schedule = schedule(WORK_AND_NIGHT);
+
+    assert_eq!(schedule.profile_at(&at(2, 8, 59)), "default");
+    assert_eq!(schedule.profile_at(&at(2, 9, 0)), "work");
+    assert_eq!(schedule.profile_at(&at(6, 17, 59)), "work");
+    assert_eq!(schedule.profile_at(&at(2, 18, 0)), "default");
+    // saturday
+    assert_eq!(schedule.profile_at(&at(7, 12, 0)), "default");
+}
+
+#[test]
+fn window_crossing_midnight() {
+    let schedule = schedule(WORK_AND_NIGHT);
+
+    assert_eq!(schedule.profile_at(&at(2, 22, 59)), "default");
+    assert_eq!(schedule.profile_at(&at(2, 23, 0)), "night");
+    assert_eq!(schedule.profile_at(&at(3, 6, 59)), "night");
+    assert_eq!(schedule.profile_at(&at(3, 7, 0)), "default");
+}
+
+#[test]
+fn first_window_wins() {
+    let schedule = schedule(
+        r#"
+        [profiles.a]
+        rules = []
+
+        [profiles.b]
+        rules = []
+
+        [[schedule]]
+        start = "10:00"
+        end = "12:00"
+        profile = "a"
+
+        [[schedule]]
+        start = "00:00"
+        end = "00:00"
+        profile = "b"
+        "#,
+    );
+
+    assert_eq!(schedule.profile_at(&at(2, 11, 0)), "a");
+    assert_eq!(schedule.profile_at(&at(2, 12, 0)), "b");
+}
+
+#[test]
+fn next_change() {
+    let schedule = schedule(WORK_AND_NIGHT);
+
+    assert_eq!(
+        schedule.next_change(&at(2, 12, 0)),
+        Some((at(2, 18, 0), "default"))
+    );
+    // friday evening to saturday night, skipping the weekend work hours
+    assert_eq!(
+        schedule.next_change(&at(7, 7, 0)),
+        Some((at(7, 23, 0), "night"))
+    );
+
+    let status = schedule.status(&FixedClock(at(6, 20, 0)));
+    assert_eq!(
+        status.to_string(),
+        "default, next change: night at Fri 23:00"
+    );
+}
+
+#[test]
+fn no_schedule() {
+    let schedule = schedule("");
+
+    assert_eq!(schedule.profile_at(&at(2, 12, 0)), "default");
+    assert_eq!(schedule.next_change(&at(2, 12, 0)), None);
+    assert_eq!(
+        schedule.status(&FixedClock(at(2, 12, 0))).to_string(),
+        "default, no scheduled change"
+    );
+}
+
+#[test]
+fn invalid_schedules() {
+    let unknown_profile = r#"
+        [[schedule]]
+        start = "09:00"
+        end = "18:00"
+        profile = "missing"
+    "#;
+    let settings: Settings = toml::from_str(unknown_profile).unwrap();
+    assert!(settings.validate().is_err());
+
+    for time in ["24:01", "9", "12:60"] {
+        let toml =
+            format!("[[schedule]]\nstart = \"{time}\"\nend = \"18:00\"\nprofile = \"default\"\n");
+        assert!(toml::from_str::<Settings>(&toml).is_err(), "{time}");
+    }
+}
@@ -0,0 +1,84 @@
+use std::{env::temp_dir, fs, path::PathBuf};
+
+use packetmock::settings::{FileStore, SettingsStore};
+
+/// A configuration file in the temporary directory that no other test uses.
+fn config_path(name: &str, text: &str) -> PathBuf {
+    let path = temp_dir().join(format!("packetmock-{}-{name}.toml", std::process::id()));
+    fs::write(&path, text).unwrap();
+    path
+}
+
+const CONFIG: &str = r#"# chosen by hand
+active_profile = "default"
+
+[strategies]
+# low enough for the fakes to expire
+ttl = 3
+split_position = 2 # inside the record header
+
+[ports]
+tls = [443, 8443]
+"#;
+
+#[test]
+fn saving_keeps_comments_and_order() {
+    let path = config_path("save-value", CONFIG);
+    let store = FileStore::new(path.clone()).unwrap();
+
+    let mut settings = store.load().unwrap();
+    settings.strategies.ttl = 5;
+    store.save(&settings).unwrap();
+
+    let text = fs::read_to_string(&path).unwrap();
+    assert_eq!(text, CONFIG.replace("ttl = 3", "ttl = 5"));
+    assert_eq!(store.load().unwrap().strategies.ttl, 5);
+    assert!(!path.with_extension("tmp").exists());
+
+    fs::remove_file(path).unwrap();
+}
+
+#[test]
+fn saving_adds_and_removes_keys() {
+    let path = config_path("save-keys", CONFIG);
+    let store = FileStore::new(path.clone()).unwrap();
+
+    let mut settings = store.load().unwrap();
+    settings.strategies.split_position = None;
+    settings.strategies.pad_sni_offset = Some(200);
+    settings.dns_guard.enabled = true;
+    store.save(&settings).unwrap();
+
+    let text = fs::read_to_string(&path).unwrap();
+    assert!(text.starts_with("# chosen by hand\n"));
+    assert!(text.contains("# low enough for the fakes to expire\nttl = 3\n"));
+    assert!(!text.contains("split_position"));
+    assert!(text.contains("[dns_guard]\nenabled = true\n"));
+    // defaults stay out of the file
+    assert!(!text.contains("ttl_tolerance"));
+
+    let loaded = store.load().unwrap();
+    assert_eq!(loaded.strategies.split_position, None);
+    assert_eq!(loaded.strategies.pad_sni_offset, Some(200));
+    assert!(loaded.dns_guard.enabled);
+
+    fs::remove_file(path).unwrap();
+}
+
+#[test]
+fn saving_creates_the_file() {
+    let path = config_path("save-new", "");
+    fs::remove_file(&path).unwrap();
+    let store = FileStore::new(path.clone()).unwrap();
+
+    let mut settings = store.load().unwrap();
+    settings.strategies.ttl = 6;
+    store.save(&settings).unwrap();
+
+    assert_eq!(
+        fs::read_to_string(&path).unwrap(),
+        "[strategies]\nttl = 6\n"
+    );
+
+    fs::remove_file(path).unwrap();
+}
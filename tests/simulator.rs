@@ -0,0 +1,185 @@
+use packetmock::{
+    blockcheck::{Verdict, default_chains, probe_payload},
+    http::tls::parse_client_hello,
+    profile::Protocol,
+    simulator::{Action, ClientBackend, Dpi, Inspection, Simulation},
+    strategy::{SniCase, Step},
+};
+
+const DOMAIN: &str = "blocked.example.org";
+
+/// A DPI 3 hops away from the client, with the server 10 hops away.
+fn simulation(inspection: Inspection, case_sensitive: bool, action: Action) -> Simulation {
+    Simulation {
+        client: ClientBackend { fake_ttl: 5 },
+        dpi: Dpi {
+            blocked: vec!["example.org".to_owned()],
+            inspection,
+            case_sensitive,
+            distance: 3,
+            action,
+        },
+        server_distance: 10,
+    }
+}
+
+fn profiles() -> Vec<(&'static str, Simulation)> {
+    vec![
+        (
+            "per-packet",
+            simulation(Inspection::PerPacket, false, Action::Reset),
+        ),
+        (
+            "per-packet case-sensitive",
+            simulation(Inspection::PerPacket, true, Action::Reset),
+        ),
+        (
+            "reassembly",
+            simulation(Inspection::Reassembly, false, Action::Blockpage),
+        ),
+        (
+            "reassembly case-sensitive",
+            simulation(Inspection::Reassembly, true, Action::Reset),
+        ),
+    ]
+}
+
+fn describe(chain: &[Step]) -> String {
+    if chain.is_empty() {
+        return "none".to_owned();
+    }
+    let steps = chain.iter().map(Step::to_string).collect::<Vec<_>>();
+    steps.join(", ")
+}
+
+#[test]
+fn strategies_against_profiles() {
+    let mut chains = vec![Vec::new()];
+    chains.extend(default_chains());
+    chains.push(vec![Step::SniCase {
+        case: SniCase::Alternate,
+    }]);
+
+    let mut table = String::new();
+    for (name, simulation) in profiles() {
+        for protocol in [Protocol::Http, Protocol::Tls] {
+            for chain in &chains {
+                let verdict = simulation.run(DOMAIN, protocol, chain);
+                table += &format!("{name} / {protocol:?} / {}: {verdict}\n", describe(chain));
+            }
+        }
+    }
+
+    insta::assert_snapshot!(table);
+}
+
+#[test]
+fn undesynced_flows_are_blocked() {
+    for (name, simulation) in profiles() {
+        for protocol in [Protocol::Http, Protocol::Tls] {
+            let verdict = simulation.run(DOMAIN, protocol, &[]);
+            assert_ne!(verdict, Verdict::Works, "{name} {protocol:?}");
+        }
+    }
+
+    let simulation = simulation(Inspection::PerPacket, false, Action::Reset);
+    assert_eq!(
+        simulation.run("example.com", Protocol::Tls, &[]),
+        Verdict::Works
+    );
+}
+
+#[test]
+fn split_defeats_only_per_packet_inspection() {
+    let chain = [Step::Split { position: 1 }];
+
+    for protocol in [Protocol::Http, Protocol::Tls] {
+        let per_packet = simulation(Inspection::PerPacket, false, Action::Reset);
+        assert_eq!(per_packet.run(DOMAIN, protocol, &chain), Verdict::Works);
+
+        let reassembly = simulation(Inspection::Reassembly, false, Action::Reset);
+        assert_eq!(reassembly.run(DOMAIN, protocol, &chain), Verdict::Reset);
+    }
+}
+
+#[test]
+fn sni_case_changes_only_the_fake() {
+    let client = ClientBackend { fake_ttl: 5 };
+    let chain = [
+        Step::Fake { ttl: None },
+        Step::SniCase {
+            case: SniCase::Alternate,
+        },
+    ];
+
+    let packets = client.send(DOMAIN, Protocol::Tls, &chain);
+    assert_eq!(packets.len(), 2);
+    let fake = parse_client_hello(&packets[0].data).unwrap();
+    assert_eq!(fake.server_name(), Some("wWw.W3.oRg"));
+    assert_eq!(
+        packets[1].data,
+        probe_payload(DOMAIN, Protocol::Tls).unwrap()
+    );
+
+    // without a fake the flow is blocked as if it wasn't desynced
+    for inspection in [Inspection::PerPacket, Inspection::Reassembly] {
+        let sensitive = simulation(inspection, true, Action::Reset);
+        assert_eq!(
+            sensitive.run(DOMAIN, Protocol::Tls, &chain[1..]),
+            Verdict::Reset
+        );
+    }
+}
+
+#[test]
+fn padding_changes_only_the_fake() {
+    let client = ClientBackend { fake_ttl: 5 };
+    let chain = [Step::Fake { ttl: None }, Step::PadSni { offset: 200 }];
+
+    let packets = client.send(DOMAIN, Protocol::Tls, &chain);
+    assert_eq!(packets.len(), 2);
+    let fake = parse_client_hello(&packets[0].data).unwrap();
+    assert_eq!(fake.sni.map(|sni| sni.start), Some(200));
+    assert_eq!(
+        packets[1].data,
+        probe_payload(DOMAIN, Protocol::Tls).unwrap()
+    );
+
+    // the real ClientHello still completes the handshake
+    let simulation = simulation(Inspection::PerPacket, false, Action::Reset);
+    let padded = [Step::PadSni { offset: 200 }, Step::Split { position: 1 }];
+    assert_eq!(
+        simulation.run(DOMAIN, Protocol::Tls, &padded),
+        Verdict::Works
+    );
+}
+
+#[test]
+fn fakes_only_work_between_the_dpi_and_the_server() {
+    let simulation = simulation(Inspection::Reassembly, false, Action::Blockpage);
+    let fake = |ttl| [Step::Fake { ttl: Some(ttl) }];
+
+    for protocol in [Protocol::Http, Protocol::Tls] {
+        // expired before reaching the DPI
+        assert_eq!(
+            simulation.run(DOMAIN, protocol, &fake(2)),
+            Verdict::Blockpage
+        );
+        for ttl in 3..10 {
+            assert_eq!(simulation.run(DOMAIN, protocol, &fake(ttl)), Verdict::Works);
+        }
+        // the server gets the fake instead of the real payload
+        assert_eq!(simulation.run(DOMAIN, protocol, &fake(10)), Verdict::Closed);
+    }
+}
+
+#[test]
+fn blockpages_and_resets_are_told_apart() {
+    let reset = simulation(Inspection::PerPacket, false, Action::Reset);
+    let blockpage = simulation(Inspection::PerPacket, false, Action::Blockpage);
+
+    for protocol in [Protocol::Http, Protocol::Tls] {
+        assert_eq!(reset.run(DOMAIN, protocol, &[]), Verdict::Reset);
+        assert_eq!(blockpage.run(DOMAIN, protocol, &[]), Verdict::Blockpage);
+    }
+}
@@ -0,0 +1,133 @@
+use std::{env::temp_dir, fs, path::PathBuf};
+
+use packetmock::state::StateStore;
+
+/// A path in the temporary directory that no other test uses, without a file.
+fn log_path(name: &str) -> PathBuf {
+    let path = temp_dir().join(format!("packetmock-{}-{name}.jsonl", std::process::id()));
+    let _ = fs::remove_file(&path);
+    path
+}
+
+fn value(store: &StateStore, key: &str) -> Option<u32> {
+    store
+        .get("test", key)
+        .map(|entry| serde_json::from_value(entry.value.clone()).unwrap())
+}
+
+#[test]
+fn records_survive_a_reopen() {
+    let path = log_path("reopen");
+
+    let mut store = StateStore::open(path.clone()).unwrap();
+    store.set("test", "a", &1, None).unwrap();
+    store.set("test", "b", &2, None).unwrap();
+    store.set("test", "a", &3, None).unwrap();
+    assert!(store.remove("test", "b").unwrap());
+    drop(store);
+
+    let store = StateStore::open(path.clone()).unwrap();
+    assert_eq!(value(&store, "a"), Some(3));
+    assert_eq!(value(&store, "b"), None);
+
+    fs::remove_file(path).unwrap();
+}
+
+#[test]
+fn records_cut_short_are_skipped() {
+    let path = log_path("truncated");
+
+    let mut store = StateStore::open(path.clone()).unwrap();
+    store.set("test", "a", &1, None).unwrap();
+    store.set("test", "b", &2, None).unwrap();
+    drop(store);
+
+    // a crash while appending the last record
+    let text = fs::read_to_string(&path).unwrap();
+    fs::write(&path, &text[..text.len() - 10]).unwrap();
+
+    let mut store = StateStore::open(path.clone()).unwrap();
+    assert_eq!(value(&store, "a"), Some(1));
+    assert_eq!(value(&store, "b"), None);
+
+    // the next record starts on a line of its own
+    store.set("test", "c", &3, None).unwrap();
+    drop(store);
+
+    let store = StateStore::open(path.clone()).unwrap();
+    assert_eq!(value(&store, "a"), Some(1));
+    assert_eq!(value(&store, "c"), Some(3));
+
+    fs::remove_file(path).unwrap();
+}
+
+#[test]
+fn partial_headers_are_rewritten_by_the_first_record() {
+    let path = log_path("partial-header");
+    fs::write(&path, r#"{"schema":"packet"#).unwrap();
+
+    // reading alone leaves the log as it is
+    let store = StateStore::open(path.clone()).unwrap();
+    assert_eq!(value(&store, "a"), None);
+    drop(store);
+    assert_eq!(fs::read_to_string(&path).unwrap(), r#"{"schema":"packet"#);
+
+    let mut store = StateStore::open(path.clone()).unwrap();
+    store.set("test", "a", &1, None).unwrap();
+    drop(store);
+
+    let store = StateStore::open(path.clone()).unwrap();
+    assert_eq!(value(&store, "a"), Some(1));
+
+    fs::remove_file(path).unwrap();
+}
+
+#[test]
+fn corrupt_headers_are_refused() {
+    for (name, text) in [
+        ("corrupt-line", "not a header\n"),
+        ("corrupt-partial", "not a header"),
+        ("other-schema", "{\"schema\":\"other\",\"version\":1}\n"),
+        (
+            "newer-version",
+            "{\"schema\":\"packetmock-state\",\"version\":99}\n",
+        ),
+    ] {
+        let path = log_path(name);
+        fs::write(&path, text).unwrap();
+
+        assert!(StateStore::open(path.clone()).is_err(), "{name}");
+        assert_eq!(fs::read_to_string(&path).unwrap(), text, "{name}");
+
+        fs::remove_file(path).unwrap();
+    }
+}
+
+#[test]
+fn compaction_keeps_the_live_entries() {
+    let path = log_path("compact");
+
+    let mut store = StateStore::open(path.clone()).unwrap();
+    for round in 0..10 {
+        for key in ["a", "b", "c"] {
+            store.set("test", key, &round, None).unwrap();
+        }
+    }
+    store.remove("test", "b").unwrap();
+    store.compact().unwrap();
+
+    assert_eq!(fs::read_to_string(&path).unwrap().lines().count(), 3);
+
+    // appending goes on in the new log
+    store.set("test", "d", &4, None).unwrap();
+    drop(store);
+
+    let store = StateStore::open(path.clone()).unwrap();
+    assert_eq!(value(&store, "a"), Some(9));
+    assert_eq!(value(&store, "b"), None);
+    assert_eq!(value(&store, "c"), Some(9));
+    assert_eq!(value(&store, "d"), Some(4));
+    assert!(!path.with_extension("tmp").exists());
+
+    fs::remove_file(path).unwrap();
+}
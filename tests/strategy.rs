@@ -0,0 +1,103 @@
+use packetmock::{
+    http::{FAKE_CLIENT_HELLO, tls::parse_client_hello},
+    strategy::pad_client_hello,
+};
+
+const EXTENSION_PADDING: u16 = 0x0015;
+
+fn read_u16(data: &[u8], offset: usize) -> u16 {
+    u16::from_be_bytes([data[offset], data[offset + 1]])
+}
+
+/// Check that the record, handshake and extensions lengths of a ClientHello add up, and return
+/// the start of its server name and how many padding extensions it has.
+fn check_lengths(data: &[u8]) -> (usize, usize) {
+    let hello = parse_client_hello(data).unwrap();
+
+    let record_len = read_u16(data, 3) as usize;
+    assert_eq!(record_len + 5, data.len());
+    let handshake_len = u32::from_be_bytes([0, data[6], data[7], data[8]]) as usize;
+    assert_eq!(handshake_len + 4, record_len);
+
+    let first = hello.extensions.first().unwrap();
+    let extensions_len = read_u16(data, first.start - 2) as usize;
+    assert_eq!(first.start + extensions_len, data.len());
+    assert_eq!(hello.extensions.last().unwrap().data.end, data.len());
+
+    let paddings = hello
+        .extensions
+        .iter()
+        .filter(|extension| extension.kind == EXTENSION_PADDING)
+        .count();
+
+    assert_eq!(hello.server_name(), Some("www.w3.org"));
+    (hello.sni.unwrap().start, paddings)
+}
+
+/// The ClientHello with a padding extension of the length appended after the others.
+fn with_trailing_padding(data: &[u8], len: usize) -> Vec<u8> {
+    let hello = parse_client_hello(data).unwrap();
+    let extensions_len_offset = hello.extensions.first().unwrap().start - 2;
+    let grow = |data: &mut [u8], offset: usize, size: usize| {
+        let mut bytes = [0; 4];
+        bytes[4 - size..].copy_from_slice(&data[offset..offset + size]);
+        let bytes = (u32::from_be_bytes(bytes) + len as u32 + 4).to_be_bytes();
+        data[offset..offset + size].copy_from_slice(&bytes[4 - size..]);
+    };
+
+    let mut padded = data.to_vec();
+    padded.extend_from_slice(&EXTENSION_PADDING.to_be_bytes());
+    padded.extend_from_slice(&(len as u16).to_be_bytes());
+    padded.resize(padded.len() + len, 0);
+
+    grow(&mut padded, 3, 2);
+    grow(&mut padded, 6, 3);
+    grow(&mut padded, extensions_len_offset, 2);
+    padded
+}
+
+#[test]
+fn padding_moves_the_server_name() {
+    let (sni, paddings) = check_lengths(FAKE_CLIENT_HELLO);
+    assert_eq!(paddings, 0);
+
+    let padded = pad_client_hello(FAKE_CLIENT_HELLO, sni + 100).unwrap();
+    assert_eq!(padded.len(), FAKE_CLIENT_HELLO.len() + 100);
+    assert_eq!(check_lengths(&padded), (sni + 100, 1));
+
+    // the padding in front is resized rather than added again
+    let grown = pad_client_hello(&padded, sni + 300).unwrap();
+    assert_eq!(check_lengths(&grown), (sni + 300, 1));
+
+    let shrunk = pad_client_hello(&grown, sni + 4).unwrap();
+    assert_eq!(check_lengths(&shrunk), (sni + 4, 1));
+}
+
+#[test]
+fn padding_needs_room_for_the_extension() {
+    let (sni, _) = check_lengths(FAKE_CLIENT_HELLO);
+
+    assert_eq!(pad_client_hello(FAKE_CLIENT_HELLO, sni), None);
+    assert_eq!(pad_client_hello(FAKE_CLIENT_HELLO, sni + 3), None);
+    assert_eq!(pad_client_hello(FAKE_CLIENT_HELLO, sni - 1), None);
+    assert_eq!(pad_client_hello(FAKE_CLIENT_HELLO, sni + 20_000), None);
+}
+
+#[test]
+fn padding_after_the_server_name_is_left_alone() {
+    let data = with_trailing_padding(FAKE_CLIENT_HELLO, 16);
+    let (sni, paddings) = check_lengths(&data);
+    assert_eq!(paddings, 1);
+
+    assert_eq!(pad_client_hello(&data, sni + 100), None);
+}
+
+#[test]
+fn handshakes_spanning_records_are_not_padded() {
+    let (sni, _) = check_lengths(FAKE_CLIENT_HELLO);
+
+    let mut data = FAKE_CLIENT_HELLO.to_vec();
+    data[8] = data[8].wrapping_add(16);
+
+    assert_eq!(pad_client_hello(&data, sni + 100), None);
+}